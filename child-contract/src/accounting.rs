@@ -0,0 +1,138 @@
+use crate::*;
+
+// Reward bookkeeping of farms and stakes as of a given time. It does not touch
+// contract state, so calls and views share it: a view computed at some time
+// reports exactly what a call at that time commits.
+
+impl FarmParams {
+    /// Distributes the emission up to `now`: up to the last whole session, or up to
+    /// `now` in continuous mode, following the emission schedule and end time.
+    /// Farms that are not running or have not started stand still.
+    /// Returns whether this update ended the farm.
+    pub(crate) fn update_to(&mut self, now: u64) -> bool {
+        if !self.status.is_running() || now < self.start_time {
+            return false;
+        }
+        self.status = FarmStatus::Active;
+
+        if self.total_effective_staked == 0 {
+            // no stakers => the emission up to now is set aside
+            let until = self.end_time.map_or(now, |end| std::cmp::min(end, now));
+            let emitted = self.take_emission(until);
+            self.distribute_emission(emitted);
+        } else {
+            let distribute_until = self.distributable_until(now);
+            if distribute_until > self.last_distribution {
+                let emitted = self.take_emission(distribute_until);
+                self.distribute_emission(emitted);
+
+                // Without an end time, the farm ends once all reward pools are empty.
+                if self.end_time.is_none() && self.remaining_reward.iter().all(|&r| r == 0) {
+                    self.status = FarmStatus::Ended;
+                }
+            }
+        }
+        self.advance_schedule();
+
+        if self.end_time.map_or(false, |end| self.last_distribution >= end) {
+            self.status = FarmStatus::Ended;
+        }
+        self.status == FarmStatus::Ended
+    }
+
+    /// Like `update_to`, but drops the extra weight of the `expiring` boosts at their
+    /// unlock time. They are given as `(unlock_at, weight)` in order of time, all due
+    /// by `now`. Returns whether the farm ended, and its `reward_per_share` as each
+    /// boost expired.
+    pub(crate) fn update_expiring(&mut self, now: u64, expiring: &[(u64, u128)]) -> (bool, Vec<Vec<U256>>) {
+        let mut ended = false;
+        let mut expired_at = Vec::with_capacity(expiring.len());
+        for (unlock_at, weight) in expiring {
+            ended |= self.update_to(*unlock_at);
+            self.total_effective_staked = self.total_effective_staked.saturating_sub(*weight);
+            expired_at.push(self.reward_per_share.clone());
+        }
+        ended |= self.update_to(now);
+        (ended, expired_at)
+    }
+
+    /// Pays `emitted` out of the reward pools to the stakers, or sets it aside when
+    /// nobody is staked. Emission the pools cannot cover is recorded as unfunded.
+    pub(crate) fn distribute_emission(&mut self, emitted: Vec<u128>) {
+        for (i, emission) in emitted.into_iter().enumerate() {
+            // Only distribute up to the available reward tokens.
+            let funded = std::cmp::min(emission, self.remaining_reward[i]);
+            self.unfunded_emission[i] = self.unfunded_emission[i].saturating_add(emission - funded);
+            self.remaining_reward[i] -= funded;
+            if self.total_effective_staked == 0 {
+                self.undistributed_reward[i] = self.undistributed_reward[i].saturating_add(funded);
+                continue;
+            }
+            let mut reward_to_distribute = funded;
+            if self.idle_reward_policy == IdleRewardPolicy::CarryForward {
+                reward_to_distribute =
+                    reward_to_distribute.saturating_add(std::mem::take(&mut self.undistributed_reward[i]));
+            }
+            self.credit_stakers(i, reward_to_distribute);
+        }
+    }
+
+    /// Adds `amount` of reward token `i` to `reward_per_share`, shared by the stakes'
+    /// effective amounts. Rounding dust the stakers cannot be credited with goes
+    /// back into the reward pool.
+    pub(crate) fn credit_stakers(&mut self, i: usize, amount: u128) {
+        if amount == 0 {
+            return;
+        }
+        let inc = math::reward_per_share_increment(amount, self.total_effective_staked);
+        self.reward_per_share[i] = math::add_reward_per_share(self.reward_per_share[i], inc);
+        let credited = math::reward_for(self.total_effective_staked, inc);
+        self.total_distributed[i] = self.total_distributed[i].saturating_add(credited);
+        self.remaining_reward[i] = self.remaining_reward[i].saturating_add(amount - credited);
+    }
+}
+
+impl StakeInfo {
+    /// An empty stake in `farm`, settled up to its current `reward_per_share`.
+    pub(crate) fn new(farm: &FarmParams) -> Self {
+        StakeInfo {
+            amount: 0,
+            effective_amount: 0,
+            positions: vec![],
+            reward_debt: farm.reward_per_share.clone(),
+            accrued_rewards: vec![0; farm.reward_tokens.len()],
+            auto_compound: false,
+        }
+    }
+
+    /// Rewards of token `i` earned since the last settlement.
+    pub(crate) fn pending_reward(&self, farm: &FarmParams, i: usize) -> u128 {
+        let diff = farm.reward_per_share[i].saturating_sub(self.reward_debt[i]);
+        math::reward_for(self.effective_amount, diff)
+    }
+
+    /// Accrued plus pending rewards of each reward token, as `settle` would leave
+    /// them in `accrued_rewards`.
+    pub(crate) fn unclaimed_rewards(&self, farm: &FarmParams) -> Vec<u128> {
+        self.accrued_rewards
+            .iter()
+            .enumerate()
+            .map(|(i, accrued)| accrued.saturating_add(self.pending_reward(farm, i)))
+            .collect()
+    }
+
+    /// Moves any rewards earned since the last settlement into `accrued_rewards`
+    /// and resets the reward debt to the farm's current `reward_per_share`.
+    pub(crate) fn settle(&mut self, farm: &FarmParams) {
+        self.settle_to(&farm.reward_per_share);
+    }
+
+    /// Like `settle`, up to an earlier `reward_per_share` of the farm.
+    pub(crate) fn settle_to(&mut self, reward_per_share: &[U256]) {
+        for (i, (accrued, debt)) in self.accrued_rewards.iter_mut().zip(&self.reward_debt).enumerate() {
+            let diff = reward_per_share[i].saturating_sub(*debt);
+            *accrued = accrued.saturating_add(math::reward_for(self.effective_amount, diff));
+        }
+        self.reward_debt = reward_per_share.to_vec();
+    }
+}
@@ -0,0 +1,173 @@
+use crate::*;
+
+impl ChildFarmingContract {
+    pub(crate) fn assert_admin(&self) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.admin,
+            "Only admin can call this method"
+        );
+    }
+
+    /// Only the admin, the master contract and allow-listed accounts may create farms.
+    pub(crate) fn assert_farm_creator(&self) {
+        let caller = env::predecessor_account_id();
+        assert!(
+            caller == self.admin || Self::is_master(&caller) || self.farm_creators.contains(&caller),
+            "Only the admin or an allowed farm creator can create farms"
+        );
+    }
+
+    /// The master contract is the account this contract was deployed under as a sub-account.
+    fn is_master(account_id: &AccountId) -> bool {
+        env::current_account_id()
+            .get_parent_account_id()
+            .map_or(false, |parent| parent == account_id.as_str())
+    }
+
+    /// Code upgrades are accepted from the admin or from the master contract.
+    pub(crate) fn assert_upgrade_authority(&self) {
+        let caller = env::predecessor_account_id();
+        assert!(
+            Self::is_master(&caller) || caller == self.admin,
+            "Only the master contract or admin can upgrade this contract"
+        );
+    }
+
+    /// The master may pause and unpause children, so a fleet can be stopped in one call.
+    pub(crate) fn assert_pause_authority(&self) {
+        let caller = env::predecessor_account_id();
+        assert!(
+            Self::is_master(&caller) || caller == self.admin,
+            "Only the master contract or admin can change the pause state"
+        );
+    }
+
+    /// A farm may be managed by its creator or by the admin.
+    pub(crate) fn assert_farm_manager(&self, farm: &FarmParams) {
+        let caller = env::predecessor_account_id();
+        assert!(
+            caller == farm.farm_creator || caller == self.admin,
+            "Only the farm creator or admin can manage this farm"
+        );
+    }
+}
+
+#[near_bindgen]
+impl ChildFarmingContract {
+    /// **(Admin-only)** First step of an admin transfer. `new_admin` takes over
+    /// once it calls `accept_admin`; proposing again replaces the pending admin.
+    #[payable]
+    pub fn propose_admin(&mut self, new_admin: AccountId) {
+        near_sdk::assert_one_yocto();
+        self.assert_admin();
+        self.pending_admin = Some(new_admin.clone());
+        FarmEvent::AdminProposed { new_admin }.emit();
+    }
+
+    /// Second step of an admin transfer, called by the proposed admin.
+    #[payable]
+    pub fn accept_admin(&mut self) {
+        near_sdk::assert_one_yocto();
+        let caller = env::predecessor_account_id();
+        assert_eq!(
+            self.pending_admin.as_ref(),
+            Some(&caller),
+            "Only the pending admin can accept the admin role"
+        );
+        self.pending_admin = None;
+        let old_admin = std::mem::replace(&mut self.admin, caller.clone());
+        FarmEvent::AdminChanged {
+            old_admin,
+            new_admin: caller,
+        }
+        .emit();
+    }
+
+    /// **(Admin-only)** Allows `account_id` to create farms.
+    #[payable]
+    pub fn add_farm_creator(&mut self, account_id: AccountId) {
+        near_sdk::assert_one_yocto();
+        self.assert_admin();
+        self.farm_creators.insert(&account_id);
+        FarmEvent::FarmCreatorAdded { account_id }.emit();
+    }
+
+    /// **(Admin-only)** Revokes farm creation rights. Existing farms keep their creator.
+    #[payable]
+    pub fn remove_farm_creator(&mut self, account_id: AccountId) {
+        near_sdk::assert_one_yocto();
+        self.assert_admin();
+        self.farm_creators.remove(&account_id);
+        FarmEvent::FarmCreatorRemoved { account_id }.emit();
+    }
+
+    /// **(Creator or admin)** Changes the per-session emission of a farm.
+    /// Sessions that already elapsed are distributed at the old rate first.
+    #[payable]
+    pub fn set_reward_per_session(&mut self, farm_id: u64, reward_per_session: Vec<U128>) {
+        near_sdk::assert_one_yocto();
+        let farm = self.internal_get_farm(farm_id).expect("Farm not found");
+        self.assert_farm_manager(&farm);
+        assert_eq!(
+            farm.reward_tokens.len(),
+            reward_per_session.len(),
+            "Must provide reward_per_session for each reward token"
+        );
+
+        self.update_farm(farm_id);
+        let mut farm = self.internal_get_farm(farm_id).expect("Farm not found");
+        farm.reward_per_session = reward_per_session.iter().map(|v| v.0).collect();
+        self.internal_save_farm(farm_id, &farm);
+        FarmEvent::RewardPerSessionUpdated {
+            farm_id,
+            reward_per_session,
+        }
+        .emit();
+    }
+
+    /// **(Creator or admin)** Sets the share of the principal, in basis points, that
+    /// `emergency_withdraw` keeps during the lockup, and where it goes.
+    #[payable]
+    pub fn set_early_exit_penalty(
+        &mut self,
+        farm_id: u64,
+        penalty_bps: u16,
+        destination: PenaltyDestination,
+    ) {
+        near_sdk::assert_one_yocto();
+        let mut farm = self.internal_get_farm(farm_id).expect("Farm not found");
+        self.assert_farm_manager(&farm);
+        assert!(penalty_bps <= MAX_PENALTY_BPS, "Penalty cannot exceed 10000 bps");
+        if destination == PenaltyDestination::Redistribute {
+            assert!(
+                farm.reward_tokens.contains(&farm.staking_token),
+                "Redistribution requires the staking token to be a reward token"
+            );
+        }
+        farm.early_exit_penalty_bps = penalty_bps;
+        farm.penalty_destination = destination.clone();
+        self.internal_save_farm(farm_id, &farm);
+        FarmEvent::EarlyExitPenaltyUpdated {
+            farm_id,
+            penalty_bps,
+            destination,
+        }
+        .emit();
+    }
+
+    /// **(Creator or admin)** Changes the lockup applied to new stakes and top-ups.
+    #[payable]
+    pub fn set_lockup_period(&mut self, farm_id: u64, lockup_period_sec: u64) {
+        near_sdk::assert_one_yocto();
+        let mut farm = self.internal_get_farm(farm_id).expect("Farm not found");
+        self.assert_farm_manager(&farm);
+        farm.lockup_period = lockup_period_sec * 1_000_000_000;
+        self.internal_save_farm(farm_id, &farm);
+        FarmEvent::LockupPeriodUpdated {
+            farm_id,
+            lockup_period_sec,
+        }
+        .emit();
+    }
+}
@@ -0,0 +1,129 @@
+use crate::*;
+use near_sdk::collections::Vector;
+
+/// An amount staked from `timestamp` (nanoseconds) until the next checkpoint.
+#[derive(BorshDeserialize, BorshSerialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub timestamp: u64,
+    pub amount: u128,
+}
+
+/// Storage prefix of the checkpoints of a stake.
+fn stake_checkpoints_prefix(account_id: &AccountId, farm_id: u64) -> Vec<u8> {
+    let mut prefix = b"sc".to_vec();
+    prefix.extend(env::sha256(account_id.as_bytes()));
+    prefix.extend(farm_id.to_le_bytes());
+    prefix
+}
+
+/// Storage prefix of the checkpoints of a farm's `total_staked`.
+fn total_staked_checkpoints_prefix(farm_id: u64) -> Vec<u8> {
+    let mut prefix = b"tc".to_vec();
+    prefix.extend(farm_id.to_le_bytes());
+    prefix
+}
+
+/// Records `amount` as of `now`, unless it is already the latest amount. A
+/// checkpoint from the same block is overwritten. Returns whether a checkpoint
+/// was added, so that the vector must be saved.
+fn push_checkpoint(checkpoints: &mut Vector<Checkpoint>, now: u64, amount: u128) -> bool {
+    let checkpoint = Checkpoint { timestamp: now, amount };
+    if let Some(last) = checkpoints.len().checked_sub(1) {
+        let latest = checkpoints.get(last).unwrap();
+        if latest.amount == amount {
+            return false;
+        }
+        if latest.timestamp == now {
+            checkpoints.replace(last, &checkpoint);
+            return false;
+        }
+    } else if amount == 0 {
+        return false;
+    }
+    checkpoints.push(&checkpoint);
+    true
+}
+
+/// The amount of the latest checkpoint at or before `timestamp`, or 0 if there is
+/// none. Checkpoints are in increasing order of time, so this is a binary search.
+fn amount_at(checkpoints: &Vector<Checkpoint>, timestamp: u64) -> u128 {
+    let (mut low, mut high) = (0, checkpoints.len());
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if checkpoints.get(mid).unwrap().timestamp <= timestamp {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    low.checked_sub(1)
+        .and_then(|i| checkpoints.get(i))
+        .map_or(0, |checkpoint| checkpoint.amount)
+}
+
+impl ChildFarmingContract {
+    /// Records that `account_id` has `amount` staked in `farm_id` from now on.
+    pub(crate) fn internal_checkpoint_stake(&mut self, account_id: &AccountId, farm_id: u64, amount: u128) {
+        let initial_storage = env::storage_usage();
+        let key = (account_id.clone(), farm_id);
+        let mut checkpoints = self
+            .stake_checkpoints
+            .get(&key)
+            .unwrap_or_else(|| Vector::new(stake_checkpoints_prefix(account_id, farm_id)));
+        if push_checkpoint(&mut checkpoints, env::block_timestamp(), amount) {
+            self.stake_checkpoints.insert(&key, &checkpoints);
+        }
+        self.internal_track_history_storage(initial_storage);
+    }
+
+    /// Records that `farm_id` has `total_staked` from now on.
+    pub(crate) fn internal_checkpoint_total_staked(&mut self, farm_id: u64, total_staked: u128) {
+        let initial_storage = env::storage_usage();
+        let mut checkpoints = self
+            .total_staked_checkpoints
+            .get(&farm_id)
+            .unwrap_or_else(|| Vector::new(total_staked_checkpoints_prefix(farm_id)));
+        if push_checkpoint(&mut checkpoints, env::block_timestamp(), total_staked) {
+            self.total_staked_checkpoints.insert(&farm_id, &checkpoints);
+        }
+        self.internal_track_history_storage(initial_storage);
+    }
+
+    /// Adds the change in storage since `initial_storage` to the history. The account
+    /// acting in this call pays for added bytes when its storage is next tracked.
+    /// Checkpoints are never deleted; bytes freed by other bookkeeping are not refunded.
+    pub(crate) fn internal_track_history_storage(&mut self, initial_storage: u64) {
+        let current_storage = env::storage_usage();
+        if current_storage >= initial_storage {
+            let added_bytes = current_storage - initial_storage;
+            self.history_storage_usage += added_bytes;
+            self.unpaid_history_bytes += added_bytes;
+        } else {
+            self.history_storage_usage = self
+                .history_storage_usage
+                .saturating_sub(initial_storage - current_storage);
+        }
+    }
+}
+
+#[near_bindgen]
+impl ChildFarmingContract {
+    /// The amount `account_id` had staked in `farm_id` at `timestamp_sec`, e.g. as
+    /// voting power in a snapshot. Amounts of the current block may still change.
+    pub fn stake_at(&self, account_id: AccountId, farm_id: u64, timestamp_sec: u64) -> U128 {
+        let amount = self
+            .stake_checkpoints
+            .get(&(account_id, farm_id))
+            .map_or(0, |checkpoints| amount_at(&checkpoints, timestamp_sec.saturating_mul(1_000_000_000)));
+        U128(amount)
+    }
+
+    /// The `total_staked` of `farm_id` at `timestamp_sec`.
+    pub fn total_staked_at(&self, farm_id: u64, timestamp_sec: u64) -> U128 {
+        let amount = self
+            .total_staked_checkpoints
+            .get(&farm_id)
+            .map_or(0, |checkpoints| amount_at(&checkpoints, timestamp_sec.saturating_mul(1_000_000_000)));
+        U128(amount)
+    }
+}
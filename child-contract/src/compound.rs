@@ -0,0 +1,137 @@
+use crate::*;
+
+/// Most bytes `compound_for` may add for one account: the checkpoints of its stake
+/// and of the farm's `total_staked`, and the stake's new place in the ranking.
+pub const MAX_COMPOUND_STORAGE_BYTES: u64 = 512;
+
+impl ChildFarmingContract {
+    /// Restakes the accrued rewards of the staking token of `stake_info`, which must
+    /// have been settled. The farm must have the staking token as a reward token and
+    /// be up to date. Returns the amount compounded.
+    pub(crate) fn internal_compound(
+        farm_id: u64,
+        farm: &mut FarmParams,
+        account_id: &AccountId,
+        stake_info: &mut StakeInfo,
+    ) -> u128 {
+        let pos = farm
+            .reward_tokens
+            .iter()
+            .position(|t| t == &farm.staking_token)
+            .expect("The staking token is not a reward token of this farm");
+        let now = env::block_timestamp();
+        let amount = std::mem::take(&mut stake_info.accrued_rewards[pos]);
+        if amount == 0 {
+            return 0;
+        }
+        // The rewards leave the books as if claimed and come back as principal.
+        farm.total_claimed[pos] = farm.total_claimed[pos].saturating_add(amount);
+        // They could have been claimed at any time, so they are not locked again.
+        stake_info.add_position(farm, amount, now, now, BASE_MULTIPLIER_BPS);
+        FarmEvent::Compounded {
+            farm_id,
+            account_id: account_id.clone(),
+            amount: U128(amount),
+        }
+        .emit();
+        amount
+    }
+
+    /// Brings `farm_id` up to date and checks that it accepts compounded stakes.
+    fn internal_get_compounding_farm(&mut self, farm_id: u64) -> FarmParams {
+        self.assert_not_paused(farm_id, PausableAction::Claim);
+        self.assert_not_paused(farm_id, PausableAction::Stake);
+        self.update_farm(farm_id);
+        let farm = self.internal_get_farm(farm_id).expect("Farm not found");
+        let status = farm.status_at(env::block_timestamp());
+        assert!(
+            status.is_running(),
+            "Farm is {}, compounding not allowed",
+            format!("{:?}", status).to_lowercase()
+        );
+        farm
+    }
+}
+
+#[near_bindgen]
+impl ChildFarmingContract {
+    /// Restakes the caller's rewards of the staking token without transferring
+    /// them, in farms that pay rewards in their staking token. The compounded
+    /// tokens are not locked. Returns the amount compounded.
+    #[payable]
+    pub fn compound(&mut self, farm_id: u64) -> U128 {
+        near_sdk::assert_one_yocto();
+        let user = env::predecessor_account_id();
+        let mut farm = self.internal_get_compounding_farm(farm_id);
+        let stake_key = (user.clone(), farm_id);
+        let mut stake_info = self.internal_get_stake(&stake_key).expect("No stake found");
+
+        let initial_storage = self.billable_storage_usage();
+        self.internal_settle(farm_id, &farm, &mut stake_info);
+        let amount = Self::internal_compound(farm_id, &mut farm, &user, &mut stake_info);
+        self.internal_save_stake(&stake_key, &stake_info);
+        self.internal_save_farm(farm_id, &farm);
+        self.internal_charge_storage(&user, initial_storage);
+        U128(amount)
+    }
+
+    /// Compounds the stakes of `account_ids` in `farm_id` that have auto-compounding
+    /// enabled. Anyone may call it, e.g. a keeper batching many accounts. The rewards
+    /// only join a position that has already unlocked, so the stake does not grow a
+    /// new one. Accounts without such a position, or whose available storage balance
+    /// cannot cover `MAX_COMPOUND_STORAGE_BYTES`, are skipped along with the others.
+    /// Returns the total amount compounded.
+    pub fn compound_for(&mut self, farm_id: u64, account_ids: Vec<AccountId>) -> U128 {
+        let mut farm = self.internal_get_compounding_farm(farm_id);
+        let now = env::block_timestamp();
+        let mut total = 0_u128;
+        for account_id in account_ids {
+            let stake_key = (account_id, farm_id);
+            let Some(mut stake_info) = self.internal_get_stake(&stake_key) else {
+                continue;
+            };
+            let unlocked = stake_info.positions.first().map_or(false, |p| p.unlock_at <= now);
+            if !stake_info.auto_compound
+                || !unlocked
+                || !self.can_pay_storage(&stake_key.0, MAX_COMPOUND_STORAGE_BYTES)
+            {
+                continue;
+            }
+            let initial_storage = self.billable_storage_usage();
+            self.internal_settle(farm_id, &farm, &mut stake_info);
+            let amount = Self::internal_compound(farm_id, &mut farm, &stake_key.0, &mut stake_info);
+            self.internal_save_stake(&stake_key, &stake_info);
+            // Saved for each account, so that the checkpoint of the farm's total is
+            // paid for by the first account compounded in this block.
+            self.internal_save_farm(farm_id, &farm);
+            self.internal_track_storage(&stake_key.0, initial_storage);
+            total = total.saturating_add(amount);
+        }
+        self.internal_save_farm(farm_id, &farm);
+        U128(total)
+    }
+
+    /// Lets `compound_for` compound the caller's stake in `farm_id`, and makes
+    /// `claim_rewards` restake its rewards of the staking token instead of paying
+    /// them out.
+    #[payable]
+    pub fn set_auto_compound(&mut self, farm_id: u64, enabled: bool) {
+        near_sdk::assert_one_yocto();
+        let user = env::predecessor_account_id();
+        let farm = self.internal_get_farm(farm_id).expect("Farm not found");
+        assert!(
+            !enabled || farm.reward_tokens.contains(&farm.staking_token),
+            "The staking token is not a reward token of this farm"
+        );
+        let stake_key = (user.clone(), farm_id);
+        let mut stake_info = self.internal_get_stake(&stake_key).expect("No stake found");
+        stake_info.auto_compound = enabled;
+        self.internal_save_stake(&stake_key, &stake_info);
+        FarmEvent::AutoCompoundUpdated {
+            farm_id,
+            account_id: user,
+            enabled,
+        }
+        .emit();
+    }
+}
@@ -0,0 +1,181 @@
+use crate::*;
+
+pub const EVENT_STANDARD: &str = "farm";
+pub const EVENT_STANDARD_VERSION: &str = "1.0.0";
+
+/// NEP-297 events emitted by the farm contract. Each one is logged as
+/// `EVENT_JSON:{"standard":"farm","version":"1.0.0","event":"<name>","data":{..}}`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+pub enum FarmEvent {
+    FarmCreated {
+        farm_id: u64,
+        farm_creator: AccountId,
+        staking_token: AccountId,
+        reward_tokens: Vec<AccountId>,
+        reward_per_session: Vec<U128>,
+        session_interval_sec: u64,
+        start_at_sec: u64,
+        lockup_period_sec: u64,
+        end_at_sec: Option<u64>,
+        emission_schedule: Vec<EmissionStep>,
+        distribution_mode: DistributionMode,
+        idle_reward_policy: IdleRewardPolicy,
+        lock_tiers: Vec<LockTier>,
+    },
+    RewardAdded {
+        farm_id: u64,
+        sender_id: AccountId,
+        reward_token: AccountId,
+        amount: U128,
+    },
+    Staked {
+        farm_id: u64,
+        account_id: AccountId,
+        amount: U128,
+    },
+    Withdrawn {
+        farm_id: u64,
+        account_id: AccountId,
+        amount: U128,
+    },
+    /// The staking token transfer of a withdrawal failed and the stake was restored.
+    WithdrawReverted {
+        farm_id: u64,
+        account_id: AccountId,
+        amount: U128,
+    },
+    RewardsClaimed {
+        farm_id: u64,
+        account_id: AccountId,
+        reward_token: AccountId,
+        amount: U128,
+    },
+    /// A reward transfer failed and the amount was credited back to the stake.
+    ClaimReverted {
+        farm_id: u64,
+        account_id: AccountId,
+        reward_token: AccountId,
+        amount: U128,
+    },
+    FarmEnded {
+        farm_id: u64,
+    },
+    RewardPerSessionUpdated {
+        farm_id: u64,
+        reward_per_session: Vec<U128>,
+    },
+    LockupPeriodUpdated {
+        farm_id: u64,
+        lockup_period_sec: u64,
+    },
+    StorageDeposit {
+        account_id: AccountId,
+        amount: U128,
+    },
+    StorageWithdraw {
+        account_id: AccountId,
+        amount: U128,
+    },
+    StorageUnregister {
+        account_id: AccountId,
+    },
+    AdminProposed {
+        new_admin: AccountId,
+    },
+    AdminChanged {
+        old_admin: AccountId,
+        new_admin: AccountId,
+    },
+    FarmCreatorAdded {
+        account_id: AccountId,
+    },
+    FarmCreatorRemoved {
+        account_id: AccountId,
+    },
+    /// `farm_id` is omitted when the actions were paused for every farm.
+    Paused {
+        farm_id: Option<u64>,
+        actions: Vec<PausableAction>,
+    },
+    Unpaused {
+        farm_id: Option<u64>,
+        actions: Vec<PausableAction>,
+    },
+    /// Principal returned by `emergency_withdraw`. `penalty` was kept from it and
+    /// `forfeited_rewards` went back into the reward pool.
+    EmergencyWithdrawn {
+        farm_id: u64,
+        account_id: AccountId,
+        amount: U128,
+        penalty: U128,
+        forfeited_rewards: Vec<U128>,
+    },
+    FarmCancelled {
+        farm_id: u64,
+    },
+    FarmPaused {
+        farm_id: u64,
+    },
+    FarmResumed {
+        farm_id: u64,
+    },
+    /// Undistributed rewards sent back to a funder by `reclaim_remaining_rewards`.
+    RewardsReclaimed {
+        farm_id: u64,
+        account_id: AccountId,
+        reward_token: AccountId,
+        amount: U128,
+    },
+    /// A reclaim transfer failed and the amount was put back into the reward pool.
+    ReclaimReverted {
+        farm_id: u64,
+        account_id: AccountId,
+        reward_token: AccountId,
+        amount: U128,
+    },
+    EarlyExitPenaltyUpdated {
+        farm_id: u64,
+        penalty_bps: u16,
+        destination: PenaltyDestination,
+    },
+    /// Rewards of the staking token restaked into the stake instead of being paid out.
+    Compounded {
+        farm_id: u64,
+        account_id: AccountId,
+        amount: U128,
+    },
+    AutoCompoundUpdated {
+        farm_id: u64,
+        account_id: AccountId,
+        enabled: bool,
+    },
+}
+
+/// The full NEP-297 envelope around a `FarmEvent`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct EventLog {
+    pub standard: String,
+    pub version: String,
+    #[serde(flatten)]
+    pub event: FarmEvent,
+}
+
+impl FarmEvent {
+    pub fn emit(self) {
+        let log = EventLog {
+            standard: EVENT_STANDARD.to_string(),
+            version: EVENT_STANDARD_VERSION.to_string(),
+            event: self,
+        };
+        env::log_str(
+            format!(
+                "EVENT_JSON:{}",
+                near_sdk::serde_json::to_string(&log).unwrap()
+            )
+            .as_str(),
+        );
+    }
+}
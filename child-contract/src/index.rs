@@ -0,0 +1,98 @@
+use crate::*;
+
+/// Storage prefix of the set of farms an account has a stake in.
+fn user_farms_prefix(account_id: &AccountId) -> Vec<u8> {
+    let mut prefix = b"uf".to_vec();
+    prefix.extend(env::sha256(account_id.as_bytes()));
+    prefix
+}
+
+/// Storage prefix of the set of accounts with a stake in a farm.
+fn farm_stakers_prefix(farm_id: u64) -> Vec<u8> {
+    let mut prefix = b"fs".to_vec();
+    prefix.extend(farm_id.to_le_bytes());
+    prefix
+}
+
+/// Storage prefix of the stakes of a farm ordered by amount.
+fn farm_ranking_prefix(farm_id: u64) -> Vec<u8> {
+    let mut prefix = b"fr".to_vec();
+    prefix.extend(farm_id.to_le_bytes());
+    prefix
+}
+
+impl ChildFarmingContract {
+    /// Records a new stake in the user→farms and farm→stakers indexes.
+    pub(crate) fn internal_index_stake(&mut self, account_id: &AccountId, farm_id: u64) {
+        let mut farms = self
+            .user_farms
+            .get(account_id)
+            .unwrap_or_else(|| UnorderedSet::new(user_farms_prefix(account_id)));
+        farms.insert(&farm_id);
+        self.user_farms.insert(account_id, &farms);
+
+        let mut stakers = self
+            .farm_stakers
+            .get(&farm_id)
+            .unwrap_or_else(|| UnorderedSet::new(farm_stakers_prefix(farm_id)));
+        stakers.insert(account_id);
+        self.farm_stakers.insert(&farm_id, &stakers);
+    }
+
+    /// Moves `account_id` in the ranking of `farm_id` from `previous_amount` to
+    /// `amount` staked. Empty stakes are not ranked.
+    pub(crate) fn internal_rank_stake(
+        &mut self,
+        account_id: &AccountId,
+        farm_id: u64,
+        previous_amount: u128,
+        amount: u128,
+    ) {
+        if previous_amount == amount {
+            return;
+        }
+        let mut ranking = self
+            .farm_rankings
+            .get(&farm_id)
+            .unwrap_or_else(|| TreeMap::new(farm_ranking_prefix(farm_id)));
+        if previous_amount > 0 {
+            ranking.remove(&(previous_amount, account_id.clone()));
+        }
+        if amount > 0 {
+            ranking.insert(&(amount, account_id.clone()), &());
+        }
+        if ranking.is_empty() {
+            self.farm_rankings.remove(&farm_id);
+        } else {
+            self.farm_rankings.insert(&farm_id, &ranking);
+        }
+    }
+
+    /// Removes a stake and its index entries. Emptied index sets are dropped.
+    pub(crate) fn internal_remove_stake(&mut self, stake_key: &(AccountId, u64)) {
+        let (account_id, farm_id) = stake_key;
+        let Some(stake) = self.stakes.remove(stake_key) else {
+            return;
+        };
+        let stake = StakeInfo::from(stake);
+        self.internal_rank_stake(account_id, *farm_id, stake.amount, 0);
+        self.internal_index_boosts(*farm_id, &stake.boosts(), &[]);
+        self.internal_checkpoint_stake(account_id, *farm_id, 0);
+        if let Some(mut farms) = self.user_farms.get(account_id) {
+            farms.remove(farm_id);
+            if farms.is_empty() {
+                self.user_farms.remove(account_id);
+            } else {
+                self.user_farms.insert(account_id, &farms);
+            }
+        }
+        if let Some(mut stakers) = self.farm_stakers.get(farm_id) {
+            stakers.remove(account_id);
+            if stakers.is_empty() {
+                self.farm_stakers.remove(farm_id);
+            } else {
+                self.farm_stakers.insert(farm_id, &stakers);
+            }
+        }
+    }
+}
@@ -1,22 +1,53 @@
+pub mod accounting;
+pub mod admin;
+pub mod checkpoints;
+pub mod compound;
+pub mod events;
+pub mod index;
+pub mod lifecycle;
+pub mod lockup;
+pub mod math;
+pub mod migration;
+pub mod pause;
+pub mod rates;
+pub mod schedule;
+pub mod solvency;
+pub mod storage;
 pub mod view;
 
 use near_contract_standards::fungible_token::Balance;
 use near_sdk::{
     env, near_bindgen, AccountId, Gas, NearToken, PanicOnDefault, Promise, PromiseOrValue,
+    PromiseResult,
 };
-use near_sdk::collections::UnorderedMap;
+use near_sdk::collections::{LookupMap, TreeMap, UnorderedMap, UnorderedSet, Vector};
 use near_sdk::json_types::U128;
 use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use serde_json;
+
+use crate::events::FarmEvent;
+use crate::checkpoints::Checkpoint;
+use crate::lockup::{ExpiredBoost, LockPosition, LockTier, BASE_MULTIPLIER_BPS, MAX_LOCK_POSITIONS};
+use crate::math::U256;
+use crate::migration::{FarmParamsV1, StakeInfoV1};
+use crate::pause::PausableAction;
+use crate::schedule::EmissionStep;
 
 // Constants for gas and deposits.
 const GAS_FOR_FT_TRANSFER: Gas = Gas::from_tgas(50);
+const GAS_FOR_RESOLVE_TRANSFER: Gas = Gas::from_tgas(20);
 const MSG_ADD_REWARD: &str = "ADD_REWARD";
 const MSG_STAKE: &str = "STAKE";
 
-// A multiplier to track rewards with high precision.
-const ACC_REWARD_MULTIPLIER: u128 = 1_000_000_000_000;
+/// Layout version of the contract state written by this code.
+/// Version 1 is the original unversioned layout.
+pub const CURRENT_STATE_VERSION: u32 = 2;
+
+/// Denominator of `early_exit_penalty_bps`.
+pub const MAX_PENALTY_BPS: u16 = 10_000;
+
+/// Reward deposits remembered per reward token for `reclaim_remaining_rewards`.
+const MAX_REWARD_FUNDINGS: usize = 32;
 
 #[derive(BorshDeserialize, BorshSerialize)]
 pub enum StorageKey {
@@ -31,6 +62,57 @@ pub enum StorageKey {
 pub enum FarmStatus {
     Active,
     Ended,
+    /// Created with a start time in the future; becomes `Active` once it starts.
+    Scheduled,
+    /// Cancelled before it started. Stakes can be withdrawn without lockup.
+    Cancelled,
+    /// Emission is stopped by the creator or admin until the farm is resumed.
+    Paused,
+}
+
+impl FarmStatus {
+    /// Scheduled and active farms are the ones still running.
+    pub fn is_running(&self) -> bool {
+        matches!(self, FarmStatus::Active | FarmStatus::Scheduled)
+    }
+}
+
+/// How elapsed time turns into distributed rewards.
+#[derive(BorshDeserialize, BorshSerialize, PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum DistributionMode {
+    /// Rewards are distributed once per whole session.
+    #[default]
+    Session,
+    /// Rewards accrue on every update, pro rata to the time elapsed.
+    Continuous,
+}
+
+/// What happens to rewards emitted while nobody is staked.
+#[derive(BorshDeserialize, BorshSerialize, PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum IdleRewardPolicy {
+    /// Set aside and refunded by `reclaim_remaining_rewards` once the farm is over.
+    #[default]
+    ReturnToFunder,
+    /// Paid out to the stakers with the next distribution.
+    CarryForward,
+}
+
+/// Where the early-exit penalty of `emergency_withdraw` goes.
+#[derive(BorshDeserialize, BorshSerialize, PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum PenaltyDestination {
+    /// Stays in the contract for good, counted in `burned_penalty`.
+    Burn,
+    /// Paid out to the remaining stakers as rewards. Only possible when the staking
+    /// token is also a reward token of the farm.
+    Redistribute,
+    /// Sent to a treasury account.
+    Treasury { account_id: AccountId },
 }
 
 #[derive(Serialize, Deserialize)]
@@ -42,6 +124,69 @@ pub struct FarmInput {
     pub reward_per_session: Vec<U128>,
     pub session_interval_sec: u64,
     pub start_at_sec: u64,
+    /// Unix time, in seconds, at which emission stops for good.
+    #[serde(default)]
+    pub end_at_sec: Option<u64>,
+    /// Later changes of `reward_per_session`, e.g. halvings, in increasing order.
+    #[serde(default)]
+    pub emission_schedule: Vec<EmissionStep>,
+    #[serde(default)]
+    pub distribution_mode: DistributionMode,
+    #[serde(default)]
+    pub idle_reward_policy: IdleRewardPolicy,
+    /// Lock durations stakers may choose for a reward boost, in increasing order.
+    #[serde(default)]
+    pub lock_tiers: Vec<LockTier>,
+}
+
+/// `msg` accepted by `ft_on_transfer`, either as JSON
+/// (`{"action":"stake","farm_id":0}`) or as a legacy `ACTION:farm_id` string.
+/// Unknown fields are rejected, so that a misspelled option refunds the transfer.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(tag = "action", rename_all = "snake_case", deny_unknown_fields)]
+pub enum TransferMessage {
+    /// Stakes the attached tokens for `beneficiary`, or for the sender if omitted.
+    /// Only the sender's own stake may be locked beyond the farm's lockup.
+    Stake {
+        farm_id: u64,
+        /// Keeps the stake locked at least until this unix time, in seconds.
+        #[serde(default)]
+        lock_until_sec: Option<u64>,
+        /// Index of the farm's lock tier to lock the stake in for a reward boost.
+        #[serde(default)]
+        lock_tier: Option<u8>,
+        #[serde(default)]
+        beneficiary: Option<AccountId>,
+    },
+    /// Adds the attached tokens to the farm's reward pool.
+    AddReward { farm_id: u64 },
+}
+
+impl std::str::FromStr for TransferMessage {
+    type Err = String;
+
+    fn from_str(msg: &str) -> Result<Self, Self::Err> {
+        if msg.trim_start().starts_with('{') {
+            return near_sdk::serde_json::from_str(msg).map_err(|e| e.to_string());
+        }
+        let (action, farm_id) = msg
+            .split_once(':')
+            .ok_or_else(|| "Expected a JSON message or ACTION:farm_id".to_string())?;
+        let farm_id: u64 = farm_id
+            .parse()
+            .map_err(|_| format!("Invalid farm_id {}", farm_id))?;
+        match action {
+            MSG_STAKE => Ok(TransferMessage::Stake {
+                farm_id,
+                lock_until_sec: None,
+                lock_tier: None,
+                beneficiary: None,
+            }),
+            MSG_ADD_REWARD => Ok(TransferMessage::AddReward { farm_id }),
+            _ => Err(format!("Unknown action {}", action)),
+        }
+    }
 }
 
 #[derive(BorshDeserialize, BorshSerialize, Clone)]
@@ -53,31 +198,151 @@ pub struct FarmParams {
     pub start_time: u64,
     pub last_distribution: u64,
     pub total_staked: u128,
-    /// Scaled by ACC_REWARD_MULTIPLIER.
-    pub reward_per_share: Vec<u128>,
+    /// Scaled by `math::ACC_REWARD_MULTIPLIER`.
+    pub reward_per_share: Vec<U256>,
     pub lockup_period: u64,
     /// Tracks the remaining reward tokens available for distribution.
     pub remaining_reward: Vec<u128>,
-    /// New field to track the farm status.
+    /// Lifecycle status of the farm.
     pub status: FarmStatus,
+    /// Account that created the farm; it may manage the farm alongside the admin.
+    pub farm_creator: AccountId,
+    /// Share of the principal, in basis points, kept by `emergency_withdraw` during the lockup.
+    pub early_exit_penalty_bps: u16,
+    pub penalty_destination: PenaltyDestination,
+    /// Staking tokens burned by early-exit penalties.
+    pub burned_penalty: u128,
+    /// Reward deposits per reward token as `(funder, amount)`, oldest first.
+    pub reward_funders: Vec<Vec<(AccountId, u128)>>,
+    /// Emission stops at this time; the farm then ends.
+    pub end_time: Option<u64>,
+    /// Upcoming `(from, reward_per_session)` steps. Steps are folded into
+    /// `reward_per_session` once distribution reaches them.
+    pub emission_schedule: Vec<(u64, Vec<u128>)>,
+    pub distribution_mode: DistributionMode,
+    /// Emission per reward token that did not add up to a whole token yet, in
+    /// units of `1 / session_interval`. It is carried into the next distribution.
+    pub emission_carry: Vec<u128>,
+    pub idle_reward_policy: IdleRewardPolicy,
+    /// Rewards emitted while nobody was staked, taken out of `remaining_reward`.
+    pub undistributed_reward: Vec<u128>,
+    /// Emission the reward pools could not cover when it was due.
+    pub unfunded_emission: Vec<u128>,
+    /// Rewards credited to stakers, net of those forfeited by `emergency_withdraw`.
+    pub total_distributed: Vec<u128>,
+    /// Rewards paid out by `claim_rewards`.
+    pub total_claimed: Vec<u128>,
+    /// `(duration, multiplier_bps)` lock tiers stakers may choose, by increasing duration.
+    pub lock_tiers: Vec<(u64, u32)>,
+    /// Sum of the stakes' `effective_amount`, which `reward_per_share` is divided by.
+    pub total_effective_staked: u128,
+}
+
+impl FarmParams {
+    /// The status as of `now`, counting a scheduled farm as active once it has started
+    /// and a running farm as ended once its end time has passed.
+    pub fn status_at(&self, now: u64) -> FarmStatus {
+        if self.status.is_running() && self.end_time.map_or(false, |end| now >= end) {
+            FarmStatus::Ended
+        } else if self.status == FarmStatus::Scheduled && now >= self.start_time {
+            FarmStatus::Active
+        } else {
+            self.status.clone()
+        }
+    }
 }
 
+/// `FarmParams` as stored on chain, tagged with the layout it was written in.
+/// Older layouts are upgraded when read; writes always use the latest one.
+// Only ever held briefly while reading or writing a farm, so the size gap is harmless.
+#[allow(clippy::large_enum_variant)]
 #[derive(BorshDeserialize, BorshSerialize)]
+pub enum VersionedFarmParams {
+    V1(FarmParamsV1),
+    V2(FarmParams),
+}
+
+/// Per-account storage bookkeeping for NEP-145.
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+pub struct AccountStorage {
+    /// Total NEAR deposited for storage, in yoctoNEAR.
+    pub deposit: Balance,
+    /// Bytes of farm and stake state currently paid for by this account.
+    pub used_bytes: u64,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Clone)]
 pub struct StakeInfo {
+    /// Total of `positions`.
     pub amount: u128,
-    pub lockup_end: u64,
-    pub reward_debt: Vec<u128>,
+    /// Reward weight of `positions`, counting their lock tier boosts.
+    pub effective_amount: u128,
+    /// Staked tokens by lockup, in the order they unlock.
+    pub positions: Vec<LockPosition>,
+    /// `reward_per_share` of each reward token as of the last settlement.
+    pub reward_debt: Vec<U256>,
     pub accrued_rewards: Vec<u128>,
+    /// Whether rewards of the staking token are restaked instead of paid out.
+    pub auto_compound: bool,
+}
+
+/// `StakeInfo` as stored on chain, tagged with the layout it was written in.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub enum VersionedStakeInfo {
+    V1(StakeInfoV1),
+    V2(StakeInfo),
+}
+
+impl From<VersionedStakeInfo> for StakeInfo {
+    fn from(stake: VersionedStakeInfo) -> Self {
+        match stake {
+            VersionedStakeInfo::V1(stake) => stake.into_current(),
+            VersionedStakeInfo::V2(stake) => stake,
+        }
+    }
 }
 
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
 pub struct ChildFarmingContract {
-    farms: UnorderedMap<u64, FarmParams>,
-    stakes: UnorderedMap<(AccountId, u64), StakeInfo>,
+    farms: UnorderedMap<u64, VersionedFarmParams>,
+    stakes: UnorderedMap<(AccountId, u64), VersionedStakeInfo>,
     farm_count: u64,
-    storage_deposits: UnorderedMap<AccountId, Balance>,
+    storage_deposits: UnorderedMap<AccountId, AccountStorage>,
     admin: AccountId,
+    /// Bytes taken by a single `storage_deposits` entry, measured at init.
+    account_storage_usage: u64,
+    /// Admin proposed via `propose_admin`, waiting for `accept_admin`.
+    pending_admin: Option<AccountId>,
+    /// Accounts besides the admin that are allowed to create farms.
+    farm_creators: UnorderedSet<AccountId>,
+    /// Bit mask of `PausableAction`s paused for every farm.
+    paused_actions: u8,
+    /// Bit masks of `PausableAction`s paused for individual farms.
+    paused_farms: UnorderedMap<u64, u8>,
+    /// Layout version of this state, see `CURRENT_STATE_VERSION`.
+    state_version: u32,
+    /// Farms each account has a stake in.
+    user_farms: LookupMap<AccountId, UnorderedSet<u64>>,
+    /// Accounts with a stake in each farm.
+    farm_stakers: LookupMap<u64, UnorderedSet<AccountId>>,
+    /// Stakes of each farm ordered by `(amount, account_id)`.
+    farm_rankings: LookupMap<u64, TreeMap<(u128, AccountId), ()>>,
+    /// History of the amount of each stake, for `stake_at`.
+    stake_checkpoints: LookupMap<(AccountId, u64), Vector<Checkpoint>>,
+    /// History of the `total_staked` of each farm, for `total_staked_at`.
+    total_staked_checkpoints: LookupMap<u64, Vector<Checkpoint>>,
+    /// Boosts of each farm yet to expire, as their extra reward weight by unlock time.
+    boost_expiries: LookupMap<u64, TreeMap<u64, u128>>,
+    /// Boosts that have expired in their farm, by farm and unlock time.
+    expired_boosts: LookupMap<(u64, u64), ExpiredBoost>,
+    /// Bytes taken by the checkpoints and the boost bookkeeping. They change with
+    /// other accounts' activity, so they are left out of every account's `used_bytes`
+    /// and paid for once by the account whose call writes them.
+    history_storage_usage: u64,
+    /// Checkpoint bytes written in the current call that nobody has paid for yet.
+    #[borsh(skip)]
+    unpaid_history_bytes: u64,
 }
 
 #[near_bindgen]
@@ -85,87 +350,77 @@ impl ChildFarmingContract {
     #[init]
     pub fn new(admin: AccountId) -> Self {
         assert!(!env::state_exists(), "Already initialized");
-        Self {
+        let mut this = Self {
             farms: UnorderedMap::new(b"farms".to_vec()),
             stakes: UnorderedMap::new(b"stakes".to_vec()),
             farm_count: 0,
             storage_deposits: UnorderedMap::new(b"storage_deposits".to_vec()),
             admin,
-        }
-    }
-
-    fn estimate_farm_storage(num_rewards: usize) -> u64 {
-        let overhead = 40;
-        let base_bytes = 8 + 8 + 8 + 16 + 8; 
-        let reward_per_share_bytes = 16 * (num_rewards as u64);
-        let reward_per_session_bytes = 16 * (num_rewards as u64);
-
-        let staking_token_bytes = 32;
-        let reward_tokens_bytes = 32 * (num_rewards as u64);
-
-        // Additional storage for the remaining_reward vector.
-        let remaining_reward_bytes = 16 * (num_rewards as u64);
-        let status_bytes = 8;
-
-        overhead
-            + base_bytes
-            + reward_per_share_bytes
-            + reward_per_session_bytes
-            + staking_token_bytes
-            + reward_tokens_bytes
-            + remaining_reward_bytes
-            + status_bytes
+            account_storage_usage: 0,
+            pending_admin: None,
+            farm_creators: UnorderedSet::new(b"farm_creators".to_vec()),
+            paused_actions: 0,
+            paused_farms: UnorderedMap::new(b"paused_farms".to_vec()),
+            state_version: CURRENT_STATE_VERSION,
+            user_farms: LookupMap::new(b"user_farms".to_vec()),
+            farm_stakers: LookupMap::new(b"farm_stakers".to_vec()),
+            farm_rankings: LookupMap::new(b"farm_rankings".to_vec()),
+            stake_checkpoints: LookupMap::new(b"stake_checkpoints".to_vec()),
+            total_staked_checkpoints: LookupMap::new(b"total_staked_checkpoints".to_vec()),
+            boost_expiries: LookupMap::new(b"boost_expiries".to_vec()),
+            expired_boosts: LookupMap::new(b"expired_boosts".to_vec()),
+            history_storage_usage: 0,
+            unpaid_history_bytes: 0,
+        };
+        this.measure_account_storage_usage();
+        this
     }
 
-    fn estimate_stake_storage(num_rewards: usize) -> u64 {
-        let overhead_key = 40; 
-        let amount_bytes = 16;
-        let lockup_end_bytes = 8;
-        let reward_debt_bytes = 16 * (num_rewards as u64);
-        let accrued_rewards_bytes = 16 * (num_rewards as u64);
-
-        overhead_key
-            + amount_bytes
-            + lockup_end_bytes
-            + reward_debt_bytes
-            + accrued_rewards_bytes
+    /// Reads a farm, upgrading it from an older layout if needed.
+    fn internal_get_farm(&self, farm_id: u64) -> Option<FarmParams> {
+        self.farms.get(&farm_id).map(|farm| match farm {
+            VersionedFarmParams::V1(farm) => farm.into_current(self.admin.clone()),
+            VersionedFarmParams::V2(farm) => farm,
+        })
     }
 
-    fn assert_storage_sufficient(&self, user: AccountId, bytes_needed: u64) {
-        let deposit = self.storage_deposits.get(&user).unwrap_or(0);
-        let cost = (bytes_needed as u128) * env::storage_byte_cost().as_yoctonear();
-
-        assert!(
-            deposit >= cost,
-            "Insufficient storage. Need {} more yoctoNEAR.",
-            cost.saturating_sub(deposit)
-        );
+    fn internal_save_farm(&mut self, farm_id: u64, farm: &FarmParams) {
+        self.farms
+            .insert(&farm_id, &VersionedFarmParams::V2(farm.clone()));
+        self.internal_checkpoint_total_staked(farm_id, farm.total_staked);
     }
 
-    #[payable]
-    pub fn storage_deposit(&mut self) {
-        let account_id = env::predecessor_account_id();
-        let attached_deposit = env::attached_deposit();
-        let current = self.storage_deposits.get(&account_id).unwrap_or(0);
-        self.storage_deposits.insert(&account_id, &(current + attached_deposit.as_yoctonear()));
+    fn internal_get_stake(&self, stake_key: &(AccountId, u64)) -> Option<StakeInfo> {
+        self.stakes.get(stake_key).map(StakeInfo::from)
     }
 
-    #[payable]
-    pub fn storage_withdraw(&mut self, amount: Option<U128>) {
-        near_sdk::assert_one_yocto();
-        let account_id = env::predecessor_account_id();
-        let mut current = self.storage_deposits.get(&account_id).unwrap_or(0);
-        let to_withdraw = amount.map(|v| v.0).unwrap_or(current);
-        assert!(to_withdraw <= current, "Not enough storage to withdraw");
-
-        current -= to_withdraw;
-        self.storage_deposits.insert(&account_id, &current);
-        Promise::new(account_id).transfer(NearToken::from_yoctonear(to_withdraw));
+    /// Writes a stake and updates the indexes to match.
+    fn internal_save_stake(&mut self, stake_key: &(AccountId, u64), stake_info: &StakeInfo) {
+        let previous = self
+            .stakes
+            .insert(stake_key, &VersionedStakeInfo::V2(stake_info.clone()));
+        let (previous_amount, previous_boosts) = match previous {
+            Some(previous) => {
+                let previous = StakeInfo::from(previous);
+                (previous.amount, previous.boosts())
+            }
+            None => {
+                self.internal_index_stake(&stake_key.0, stake_key.1);
+                (0, vec![])
+            }
+        };
+        self.internal_rank_stake(&stake_key.0, stake_key.1, previous_amount, stake_info.amount);
+        self.internal_index_boosts(stake_key.1, &previous_boosts, &stake_info.boosts());
+        self.internal_checkpoint_stake(&stake_key.0, stake_key.1, stake_info.amount);
     }
 
+    /// Creates a new farm. Only the admin, the master contract and allow-listed farm
+    /// creators may call this.
     #[payable]
     pub fn create_farm(&mut self, input: FarmInput) -> u64 {
+        self.assert_farm_creator();
         let creator = env::predecessor_account_id();
+        let initial_storage = self.billable_storage_usage();
 
         // Validate that session_interval_sec is not zero.
         assert!(
@@ -174,8 +429,6 @@ impl ChildFarmingContract {
         );
 
         let num_rewards = input.reward_tokens.len();
-        let required_bytes = Self::estimate_farm_storage(num_rewards);
-        self.assert_storage_sufficient(creator.clone(), required_bytes);
         assert_eq!(
             num_rewards,
             input.reward_per_session.len(),
@@ -195,10 +448,16 @@ impl ChildFarmingContract {
             start_ns
         };
 
-        let mut rps = Vec::with_capacity(num_rewards);
-        for _ in 0..num_rewards {
-            rps.push(0_u128);
-        }
+        let end_ns = input.end_at_sec.map(|sec| sec * 1_000_000_000);
+        assert!(
+            end_ns.map_or(true, |end| end > initial_dist),
+            "End time must be after the start time"
+        );
+        let emission_schedule =
+            schedule::parse_emission_schedule(&input.emission_schedule, num_rewards, initial_dist, end_ns);
+        let lock_tiers = lockup::parse_lock_tiers(&input.lock_tiers);
+
+        let rps = vec![U256::zero(); num_rewards];
 
         let mut rpsession_values = vec![];
         for x in &input.reward_per_session {
@@ -208,6 +467,12 @@ impl ChildFarmingContract {
         // Initially, the remaining reward pool is zero; rewards must be funded via ADD_REWARD.
         let remaining_reward = vec![0_u128; num_rewards];
 
+        let status = if start_ns > env::block_timestamp() {
+            FarmStatus::Scheduled
+        } else {
+            FarmStatus::Active
+        };
+
         let farm = FarmParams {
             staking_token: input.staking_token,
             reward_tokens: input.reward_tokens,
@@ -219,85 +484,56 @@ impl ChildFarmingContract {
             reward_per_share: rps,
             lockup_period: lockup_ns,
             remaining_reward,
-            status: FarmStatus::Active,
+            status,
+            farm_creator: creator.clone(),
+            early_exit_penalty_bps: 0,
+            penalty_destination: PenaltyDestination::Burn,
+            burned_penalty: 0,
+            reward_funders: vec![vec![]; num_rewards],
+            end_time: end_ns,
+            emission_schedule,
+            distribution_mode: input.distribution_mode,
+            emission_carry: vec![0; num_rewards],
+            idle_reward_policy: input.idle_reward_policy,
+            undistributed_reward: vec![0; num_rewards],
+            unfunded_emission: vec![0; num_rewards],
+            total_distributed: vec![0; num_rewards],
+            total_claimed: vec![0; num_rewards],
+            lock_tiers,
+            total_effective_staked: 0,
         };
 
-        self.farms.insert(&farm_id, &farm);
+        self.internal_save_farm(farm_id, &farm);
+        self.internal_charge_storage(&creator, initial_storage);
 
-        env::log_str(
-            format!(
-                "Created farm {} with session_interval_sec: {}, reward_per_session: {:?}",
-                farm_id, input.session_interval_sec, input.reward_per_session
-            )
-            .as_str()
-        );
+        FarmEvent::FarmCreated {
+            farm_id,
+            farm_creator: creator,
+            staking_token: farm.staking_token,
+            reward_tokens: farm.reward_tokens,
+            reward_per_session: input.reward_per_session,
+            session_interval_sec: input.session_interval_sec,
+            start_at_sec: input.start_at_sec,
+            lockup_period_sec: input.lockup_period_sec,
+            end_at_sec: input.end_at_sec,
+            emission_schedule: input.emission_schedule,
+            distribution_mode: input.distribution_mode,
+            idle_reward_policy: input.idle_reward_policy,
+            lock_tiers: input.lock_tiers,
+        }
+        .emit();
 
         farm_id
     }
 
-    /// Internal method to update this farm’s distribution 
-    /// based on how many sessions have elapsed.
+    /// Internal method to update this farm’s distribution up to now, see
+    /// `FarmParams::update_to`.
     fn update_farm(&mut self, farm_id: u64) {
-        let mut farm = self.farms.get(&farm_id).expect("Farm not found");
-        let current_time = env::block_timestamp();
-
-        // Do not update if the farm already ended.
-        if farm.status == FarmStatus::Ended {
-            self.farms.insert(&farm_id, &farm);
-            return;
-        }
-
-        if current_time < farm.start_time {
-            // not started yet
-            self.farms.insert(&farm_id, &farm);
-            return;
-        }
-
-        if farm.total_staked == 0 {
-            // no stakers => no distribution
-            farm.last_distribution = current_time;
-            self.farms.insert(&farm_id, &farm);
-            return;
+        let mut farm = self.internal_get_farm(farm_id).expect("Farm not found");
+        if self.internal_update_farm_to(farm_id, &mut farm, env::block_timestamp()) {
+            FarmEvent::FarmEnded { farm_id }.emit();
         }
-
-        let elapsed = current_time.saturating_sub(farm.last_distribution);
-        let sessions_elapsed = elapsed / farm.session_interval;
-        if sessions_elapsed == 0 {
-            self.farms.insert(&farm_id, &farm);
-            return;
-        }
-
-        for i in 0..farm.reward_tokens.len() {
-            // Calculate how many tokens should be distributed for these sessions.
-            let potential_reward = (sessions_elapsed as u128).saturating_mul(farm.reward_per_session[i]);
-            // Only distribute up to the available reward tokens.
-            let reward_to_distribute = if potential_reward > farm.remaining_reward[i] {
-                farm.remaining_reward[i]
-            } else {
-                potential_reward
-            };
-            if reward_to_distribute > 0 {
-                // Use the multiplier to update reward per share.
-                let inc = reward_to_distribute.saturating_mul(ACC_REWARD_MULTIPLIER) / farm.total_staked;
-                farm.reward_per_share[i] = farm.reward_per_share[i].saturating_add(inc);
-                // Deduct the distributed reward from the remaining pool.
-                farm.remaining_reward[i] = farm.remaining_reward[i].saturating_sub(reward_to_distribute);
-            }
-        }
-
-        let dist_ns = sessions_elapsed * farm.session_interval;
-        farm.last_distribution = farm.last_distribution.saturating_add(dist_ns);
-        if farm.last_distribution > current_time {
-            farm.last_distribution = current_time;
-        }
-
-        // If all reward pools are empty, mark the farm as ended.
-        if farm.remaining_reward.iter().all(|&r| r == 0) {
-            farm.status = FarmStatus::Ended;
-            env::log_str(format!("Farm {} has ended due to exhausted rewards.", farm_id).as_str());
-        }
-
-        self.farms.insert(&farm_id, &farm);
+        self.internal_save_farm(farm_id, &farm);
     }
 
     #[payable]
@@ -308,248 +544,453 @@ impl ChildFarmingContract {
         msg: String
     ) -> PromiseOrValue<U128> {
         let token_in = env::predecessor_account_id(); 
-        let sender = sender_id.into();
+        let sender = sender_id;
 
-        let parts: Vec<&str> = msg.split(':').collect();
-        if parts.len() < 2 {
-            // unknown message => we reject by returning the amount
+        let message: TransferMessage = match msg.parse() {
+            Ok(message) => message,
+            Err(err) => {
+                // unparseable message => we reject by returning the whole amount
+                env::log_str(format!("Refunding {}: invalid msg {:?}: {}", amount.0, msg, err).as_str());
+                return PromiseOrValue::Value(amount);
+            }
+        };
+
+        let (farm_id, action) = match message {
+            TransferMessage::Stake { farm_id, .. } => (farm_id, PausableAction::Stake),
+            TransferMessage::AddReward { farm_id } => (farm_id, PausableAction::AddReward),
+        };
+        if self.is_paused(farm_id, action) {
+            env::log_str(format!("Refunding {}: {:?} is paused for farm {}", amount.0, action, farm_id).as_str());
             return PromiseOrValue::Value(amount);
         }
-        let action = parts[0];
-        let farm_id: u64 = parts[1].parse().expect("Invalid farm_id in ft_on_transfer");
 
-        match action {
-            MSG_STAKE => {
-                self.stake_tokens(farm_id, token_in, amount.0, &sender);
+        match message {
+            TransferMessage::Stake {
+                farm_id,
+                lock_until_sec,
+                lock_tier,
+                beneficiary,
+            } => {
+                let account_id = beneficiary.unwrap_or_else(|| sender.clone());
+                // Otherwise anyone could fill up another account's lock positions.
+                assert!(
+                    account_id == sender || (lock_until_sec.is_none() && lock_tier.is_none()),
+                    "Only the beneficiary can lock its own stake"
+                );
+                let lock_until = lock_until_sec.map(|sec| sec * 1_000_000_000);
+                self.stake_tokens(farm_id, token_in, amount.0, &account_id, &sender, lock_until, lock_tier);
                 PromiseOrValue::Value(U128(0))
             }
-            MSG_ADD_REWARD => {
+            TransferMessage::AddReward { farm_id } => {
                 self.add_reward(farm_id, token_in, amount.0, &sender);
                 PromiseOrValue::Value(U128(0))
             }
-            _ => PromiseOrValue::Value(amount),
         }
     }
 
-    /// Updates the reward pool for a farm.
+    /// Updates the reward pool for a farm and remembers `sender` as a funder.
     fn add_reward(&mut self, farm_id: u64, token_in: AccountId, amount: u128, sender: &AccountId) {
-        let mut farm = self.farms.get(&farm_id).expect("Farm not found");
+        let mut farm = self.internal_get_farm(farm_id).expect("Farm not found");
+        // A farm past its end time has ended even if nobody has updated it since.
+        let status = farm.status_at(env::block_timestamp());
+        assert!(
+            status != FarmStatus::Ended && status != FarmStatus::Cancelled,
+            "Farm is {}, rewards not accepted",
+            format!("{:?}", status).to_lowercase()
+        );
         let pos = farm.reward_tokens.iter().position(|t| t == &token_in)
             .expect("This token is not a valid reward token for the farm.");
+        let initial_storage = self.billable_storage_usage();
         // Add the incoming reward tokens to the reward pool.
         farm.remaining_reward[pos] = farm.remaining_reward[pos].saturating_add(amount);
-        self.farms.insert(&farm_id, &farm);
-        env::log_str(
-            format!(
-                "User {} added {} tokens as reward to farm {}",
-                sender, amount, farm_id
-            )
-            .as_str(),
-        );
-    }
-
-    fn simulate_update_farm(&self, farm: &FarmParams) -> FarmParams {
-        let mut sim = farm.clone();
-        let current_time = env::block_timestamp();
-        if current_time >= sim.start_time && sim.total_staked > 0 {
-            let elapsed = current_time.saturating_sub(sim.last_distribution);
-            let sessions_elapsed = elapsed / sim.session_interval;
-            if sessions_elapsed > 0 {
-                for i in 0..sim.reward_tokens.len() {
-                    let potential_reward = (sessions_elapsed as u128)
-                        .saturating_mul(sim.reward_per_session[i]);
-                    let reward_to_distribute = if potential_reward > sim.remaining_reward[i] {
-                        sim.remaining_reward[i]
-                    } else {
-                        potential_reward
-                    };
-                    if reward_to_distribute > 0 {
-                        let inc = reward_to_distribute
-                            .saturating_mul(ACC_REWARD_MULTIPLIER)
-                            / sim.total_staked;
-                        sim.reward_per_share[i] = sim.reward_per_share[i].saturating_add(inc);
-                        sim.remaining_reward[i] = sim.remaining_reward[i].saturating_sub(reward_to_distribute);
-                    }
-                }
-                let dist_ns = sessions_elapsed * sim.session_interval;
-                sim.last_distribution = sim.last_distribution.saturating_add(dist_ns);
-                if sim.last_distribution > current_time {
-                    sim.last_distribution = current_time;
-                }
-                if sim.remaining_reward.iter().all(|&r| r == 0) {
-                    sim.status = FarmStatus::Ended;
+        let fundings = &mut farm.reward_funders[pos];
+        match fundings.last_mut() {
+            Some((funder, funded)) if funder == sender => *funded = funded.saturating_add(amount),
+            _ => {
+                fundings.push((sender.clone(), amount));
+                // The oldest deposits are the first to be spent, so they are the ones to forget.
+                if fundings.len() > MAX_REWARD_FUNDINGS {
+                    fundings.remove(0);
                 }
             }
         }
-        sim
+        self.internal_save_farm(farm_id, &farm);
+        // Funder records are bounded, so the farm creator pays for them.
+        self.internal_track_storage(&farm.farm_creator, initial_storage);
+        FarmEvent::RewardAdded {
+            farm_id,
+            sender_id: sender.clone(),
+            reward_token: token_in,
+            amount: U128(amount),
+        }
+        .emit();
     }
 
-    fn stake_tokens(&mut self, farm_id: u64, token_in: AccountId, amount: u128, sender: &AccountId) {
-        let mut farm = self.farms.get(&farm_id).expect("Farm not found");
+    /// Stakes `amount` for `sender`. The stake's storage is paid for by `payer`, and
+    /// both must be registered. The tokens form a new lock position, unlocking after the
+    /// farm's lockup period, the duration of `lock_tier` or at `lock_until` (nanoseconds),
+    /// whichever is latest. The lock tier boosts the position's reward weight.
+    #[allow(clippy::too_many_arguments)]
+    fn stake_tokens(
+        &mut self,
+        farm_id: u64,
+        token_in: AccountId,
+        amount: u128,
+        sender: &AccountId,
+        payer: &AccountId,
+        lock_until: Option<u64>,
+        lock_tier: Option<u8>,
+    ) {
+        let farm = self.internal_get_farm(farm_id).expect("Farm not found");
 
-        // Reject staking if the farm is ended.
-        assert_eq!(farm.status, FarmStatus::Active, "Farm is ended, staking not allowed");
+        // Only running farms accept stakes.
+        let status = farm.status_at(env::block_timestamp());
+        assert!(
+            status.is_running(),
+            "Farm is {}, staking not allowed",
+            format!("{:?}", status).to_lowercase()
+        );
 
         assert_eq!(farm.staking_token, token_in, "Not the correct staking token");
         let stake_key = (sender.clone(), farm_id);
-        if self.stakes.get(&stake_key).is_none() {
-            let required_bytes = Self::estimate_stake_storage(farm.reward_tokens.len());
-            self.assert_storage_sufficient(sender.clone(), required_bytes);
-        }
+        let initial_storage = self.billable_storage_usage();
 
         self.update_farm(farm_id);
+        let mut farm = self.internal_get_farm(farm_id).expect("Farm not found");
 
         // Either create or load existing stake.
         let mut stake_info = self
-            .stakes
-            .get(&stake_key)
-            .unwrap_or_else(|| StakeInfo {
-                amount: 0,
-                lockup_end: env::block_timestamp() + farm.lockup_period,
-                reward_debt: vec![0; farm.reward_tokens.len()],
-                accrued_rewards: vec![0; farm.reward_tokens.len()],
-            });
-
-            // Settle any pending rewards.
-        for i in 0..farm.reward_tokens.len() {
-            let pending = self.calculate_pending(&farm, &stake_info, i);
-            if pending > 0 {
-                stake_info.accrued_rewards[i] =
-                    stake_info.accrued_rewards[i].saturating_add(pending);
-            }
-            stake_info.reward_debt[i] = farm.reward_per_share[i];
-        }
+            .internal_get_stake(&stake_key)
+            .unwrap_or_else(|| StakeInfo::new(&farm));
 
-        // Increase staked amount.
-        stake_info.amount = stake_info.amount.saturating_add(amount);
+        // Settle any pending rewards.
+        self.internal_settle(farm_id, &farm, &mut stake_info);
 
-        // Optionally extend lockup.
-        let new_lockup = env::block_timestamp() + farm.lockup_period;
-        if new_lockup > stake_info.lockup_end {
-            stake_info.lockup_end = new_lockup;
-        }
-
-        farm.total_staked = farm.total_staked.saturating_add(amount);
+        let (lock_duration, multiplier_bps) = match lock_tier {
+            Some(tier) => *farm.lock_tiers.get(tier as usize).expect("Unknown lock tier"),
+            None => (0, BASE_MULTIPLIER_BPS),
+        };
+        // The new tokens are locked on their own, without re-locking earlier ones.
+        let now = env::block_timestamp();
+        let unlock_at = std::cmp::max(
+            now + std::cmp::max(farm.lockup_period, lock_duration),
+            lock_until.unwrap_or(0),
+        );
+        stake_info.add_position(&mut farm, amount, now, unlock_at, multiplier_bps);
+        assert!(
+            stake_info.positions.len() <= MAX_LOCK_POSITIONS,
+            "Cannot hold more than {} locked positions in a farm",
+            MAX_LOCK_POSITIONS
+        );
 
-        self.stakes.insert(&stake_key, &stake_info);
-        self.farms.insert(&farm_id, &farm);
+        self.internal_save_stake(&stake_key, &stake_info);
+        self.internal_save_farm(farm_id, &farm);
+        self.internal_charge_storage_to(sender, payer, initial_storage);
 
-        env::log_str(
-            format!(
-                "User {} staked {} of token {} in farm {}",
-                sender, amount, token_in, farm_id
-            )
-            .as_str(),
-        );
+        FarmEvent::Staked {
+            farm_id,
+            account_id: sender.clone(),
+            amount: U128(amount),
+        }
+        .emit();
     }
 
-    /// Calculates the pending reward for a given reward token index.
-    fn calculate_pending(&self, farm: &FarmParams, stake_info: &StakeInfo, i: usize) -> u128 {
-        let diff = farm.reward_per_share[i].saturating_sub(stake_info.reward_debt[i]);
-        // Unscale the pending reward.
-        stake_info.amount.saturating_mul(diff) / ACC_REWARD_MULTIPLIER
+    /// Builds an `ft_transfer` of `amount` of `token_id` to `receiver_id`.
+    fn ft_transfer(token_id: AccountId, receiver_id: &AccountId, amount: u128) -> Promise {
+        Promise::new(token_id).function_call(
+            "ft_transfer".to_string(),
+            near_sdk::serde_json::to_vec(&serde_json::json!({
+                "receiver_id": receiver_id,
+                "amount": U128(amount),
+            }))
+            .unwrap(),
+            NearToken::from_yoctonear(1),
+            GAS_FOR_FT_TRANSFER,
+        )
     }
 
     #[payable]
     pub fn claim_rewards(&mut self, farm_id: u64) {
         near_sdk::assert_one_yocto();
+        self.assert_not_paused(farm_id, PausableAction::Claim);
         let user = env::predecessor_account_id();
         self.update_farm(farm_id);
 
-        let farm = self.farms.get(&farm_id).expect("Farm not found");
+        let mut farm = self.internal_get_farm(farm_id).expect("Farm not found");
         let stake_key = (user.clone(), farm_id);
-        let mut stake_info = self.stakes.get(&stake_key).expect("No stake found");
+        let mut stake_info = self.internal_get_stake(&stake_key).expect("No stake found");
 
-        for i in 0..farm.reward_tokens.len() {
-            let pending = self.calculate_pending(&farm, &stake_info, i);
-            if pending > 0 {
-                stake_info.accrued_rewards[i] = stake_info.accrued_rewards[i].saturating_add(pending);
-            }
-            stake_info.reward_debt[i] = farm.reward_per_share[i];
+        self.internal_settle(farm_id, &farm, &mut stake_info);
+
+        let initial_storage = self.billable_storage_usage();
+        let running = farm.status_at(env::block_timestamp()).is_running();
+        if stake_info.auto_compound && running && !self.is_paused(farm_id, PausableAction::Stake) {
+            Self::internal_compound(farm_id, &mut farm, &user, &mut stake_info);
         }
 
-        // Cross-contract transfer each accrued reward.
+        // Cross-contract transfer each accrued reward. The books are updated
+        // optimistically and restored in `on_claim_resolved` if a transfer fails.
         for i in 0..farm.reward_tokens.len() {
             let amount = stake_info.accrued_rewards[i];
             if amount > 0 {
                 stake_info.accrued_rewards[i] = 0;
+                farm.total_claimed[i] = farm.total_claimed[i].saturating_add(amount);
                 let reward_token = farm.reward_tokens[i].clone();
-                Promise::new(reward_token).function_call(
-                    "ft_transfer".to_string().into(),
-                    near_sdk::serde_json::to_vec(&serde_json::json!({
-                        "receiver_id": user,
-                        "amount": U128(amount),
-                    }))
-                    .unwrap(),
-                    NearToken::from_yoctonear(1),
-                    GAS_FOR_FT_TRANSFER,
+                Self::ft_transfer(reward_token.clone(), &user, amount).then(
+                    Self::ext(env::current_account_id())
+                        .with_static_gas(GAS_FOR_RESOLVE_TRANSFER)
+                        .on_claim_resolved(user.clone(), farm_id, reward_token.clone(), U128(amount)),
                 );
+                FarmEvent::RewardsClaimed {
+                    farm_id,
+                    account_id: user.clone(),
+                    reward_token,
+                    amount: U128(amount),
+                }
+                .emit();
             }
         }
 
-        self.stakes.insert(&stake_key, &stake_info);
+        self.internal_save_farm(farm_id, &farm);
+        // A fully withdrawn stake is gone once its last rewards are claimed.
+        if stake_info.amount == 0 && stake_info.accrued_rewards.iter().all(|&r| r == 0) {
+            self.internal_remove_stake(&stake_key);
+        } else {
+            self.internal_save_stake(&stake_key, &stake_info);
+        }
+        // A compounded stake adds checkpoints, which the claimer pays for.
+        self.internal_charge_storage(&user, initial_storage);
+    }
 
-        env::log_str(
-            format!("User {} claimed all rewards in farm {}", user, farm_id).as_str(),
+    /// Private callback after a reward `ft_transfer` issued by `claim_rewards`.
+    /// On failure the amount is credited back to the user's accrued rewards.
+    /// Returns the amount that actually left the contract.
+    #[private]
+    pub fn on_claim_resolved(
+        &mut self,
+        account_id: AccountId,
+        farm_id: u64,
+        reward_token: AccountId,
+        amount: U128,
+    ) -> U128 {
+        assert_eq!(
+            env::promise_results_count(),
+            1,
+            "Expected one promise result."
         );
+        if let PromiseResult::Successful(_) = env::promise_result(0) {
+            return amount;
+        }
+
+        let mut farm = self.internal_get_farm(farm_id).expect("Farm not found");
+        let pos = farm
+            .reward_tokens
+            .iter()
+            .position(|t| t == &reward_token)
+            .expect("This token is not a valid reward token for the farm.");
+        farm.total_claimed[pos] = farm.total_claimed[pos].saturating_sub(amount.0);
+        self.internal_save_farm(farm_id, &farm);
+        let stake_key = (account_id.clone(), farm_id);
+        let initial_storage = self.billable_storage_usage();
+        // The stake may have been fully withdrawn while the transfer was in flight.
+        let mut stake_info = self.internal_get_stake(&stake_key).unwrap_or_else(|| StakeInfo::new(&farm));
+        stake_info.accrued_rewards[pos] = stake_info.accrued_rewards[pos].saturating_add(amount.0);
+        self.internal_save_stake(&stake_key, &stake_info);
+        // Callbacks must not panic, so a re-created stake is recorded without
+        // asserting that the deposit covers it.
+        self.internal_track_storage(&account_id, initial_storage);
+
+        FarmEvent::ClaimReverted {
+            farm_id,
+            account_id,
+            reward_token,
+            amount,
+        }
+        .emit();
+        U128(0)
     }
 
+    /// Withdraws `amount` out of the caller's positions whose lockup has expired,
+    /// or out of any position once the farm has been cancelled.
     #[payable]
     pub fn withdraw(&mut self, farm_id: u64, amount: U128) {
         near_sdk::assert_one_yocto();
+        self.assert_not_paused(farm_id, PausableAction::Withdraw);
         let user = env::predecessor_account_id();
         let to_withdraw = amount.0;
+        assert!(to_withdraw > 0, "Nothing to withdraw");
 
-        let mut farm = self.farms.get(&farm_id).expect("Farm not found");
         let stake_key = (user.clone(), farm_id);
-        let mut stake_info = self.stakes.get(&stake_key).expect("No stake found");
+        let mut stake_info = self.internal_get_stake(&stake_key).expect("No stake found");
 
+        let now = env::block_timestamp();
+        let cancelled = self.internal_get_farm(farm_id).map(|farm| farm.status) == Some(FarmStatus::Cancelled);
+        assert!(stake_info.amount >= to_withdraw, "Insufficient staked balance");
         assert!(
-            env::block_timestamp() >= stake_info.lockup_end,
+            cancelled || stake_info.unlocked_amount(now) >= to_withdraw,
             "Lockup period not expired"
         );
-        assert!(stake_info.amount >= to_withdraw, "Insufficient staked balance");
 
         self.update_farm(farm_id);
+        let mut farm = self.internal_get_farm(farm_id).expect("Farm not found");
 
-        // Settle pending rewards.
-        for i in 0..farm.reward_tokens.len() {
-            let pending = self.calculate_pending(&farm, &stake_info, i);
-            if pending > 0 {
-                stake_info.accrued_rewards[i] = stake_info.accrued_rewards[i].saturating_add(pending);
-            }
-            stake_info.reward_debt[i] = farm.reward_per_share[i];
+        self.internal_settle(farm_id, &farm, &mut stake_info);
+        stake_info.release(&mut farm, to_withdraw, now, cancelled);
+
+        let initial_storage = self.billable_storage_usage();
+        // Keep an emptied stake around while it still holds unclaimed rewards.
+        if stake_info.amount == 0 && stake_info.accrued_rewards.iter().all(|&r| r == 0) {
+            self.internal_remove_stake(&stake_key);
+        } else {
+            self.internal_save_stake(&stake_key, &stake_info);
+        }
+        self.internal_save_farm(farm_id, &farm);
+        self.internal_charge_storage(&user, initial_storage);
+
+        // Cross-contract ft_transfer of staking tokens, restored in
+        // `on_withdraw_resolved` if the token contract rejects it.
+        Self::ft_transfer(farm.staking_token.clone(), &user, to_withdraw).then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(GAS_FOR_RESOLVE_TRANSFER)
+                .on_withdraw_resolved(user.clone(), farm_id, U128(to_withdraw)),
+        );
+
+        FarmEvent::Withdrawn {
+            farm_id,
+            account_id: user,
+            amount,
         }
+        .emit();
+    }
 
-        stake_info.amount = stake_info.amount.saturating_sub(to_withdraw);
-        farm.total_staked = farm.total_staked.saturating_sub(to_withdraw);
+    /// Returns the caller's whole principal right away, ignoring the lockup.
+    ///
+    /// All rewards of the stake, accrued or pending, are forfeited back into the
+    /// farm's `remaining_reward`. The farm's early-exit penalty is kept from the
+    /// positions still locked, unless the farm is not running or withdrawals or
+    /// claims are paused for it, globally or for this farm. Nothing is
+    /// distributed first, so a broken distribution cannot block the exit.
+    #[payable]
+    pub fn emergency_withdraw(&mut self, farm_id: u64) {
+        near_sdk::assert_one_yocto();
+        let user = env::predecessor_account_id();
+        let stake_key = (user.clone(), farm_id);
+        let mut stake_info = self.internal_get_stake(&stake_key).expect("No stake found");
+        let mut farm = self.internal_get_farm(farm_id).expect("Farm not found");
+        let amount = stake_info.amount;
+        assert!(amount > 0, "Nothing to withdraw");
 
-        if stake_info.amount == 0 {
-            self.stakes.remove(&stake_key);
+        self.internal_settle(farm_id, &farm, &mut stake_info);
+        for (i, accrued) in stake_info.accrued_rewards.iter().enumerate() {
+            farm.remaining_reward[i] = farm.remaining_reward[i].saturating_add(*accrued);
+            farm.total_distributed[i] = farm.total_distributed[i].saturating_sub(*accrued);
+        }
+        // Only the positions still locked pay the penalty.
+        let locked = stake_info.locked_amount(env::block_timestamp());
+        stake_info.release(&mut farm, amount, env::block_timestamp(), true);
+
+        // Only pauses that keep stakers from leaving or claiming waive the penalty.
+        let penalty_free = !farm.status_at(env::block_timestamp()).is_running()
+            || self.is_paused(farm_id, PausableAction::Withdraw)
+            || self.is_paused(farm_id, PausableAction::Claim);
+        let penalty = if penalty_free {
+            0
         } else {
-            self.stakes.insert(&stake_key, &stake_info);
+            // Split so that `locked * bps` cannot overflow.
+            let bps = farm.early_exit_penalty_bps as u128;
+            locked / MAX_PENALTY_BPS as u128 * bps + locked % MAX_PENALTY_BPS as u128 * bps / MAX_PENALTY_BPS as u128
+        };
+        self.internal_apply_penalty(&mut farm, penalty);
+        let to_withdraw = amount - penalty;
+
+        let initial_storage = self.billable_storage_usage();
+        self.internal_remove_stake(&stake_key);
+        self.internal_save_farm(farm_id, &farm);
+        self.internal_track_storage(&user, initial_storage);
+
+        if to_withdraw > 0 {
+            Self::ft_transfer(farm.staking_token.clone(), &user, to_withdraw).then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RESOLVE_TRANSFER)
+                    .on_withdraw_resolved(user.clone(), farm_id, U128(to_withdraw)),
+            );
         }
-        self.farms.insert(&farm_id, &farm);
 
-        // Cross-contract ft_transfer of staking tokens.
-        let staking_token_id = farm.staking_token.clone();
-        Promise::new(staking_token_id).function_call(
-            "ft_transfer".to_string().into(),
-            near_sdk::serde_json::to_vec(&serde_json::json!({
-                "receiver_id": user,
-                "amount": U128(to_withdraw),
-            }))
-            .unwrap(),
-            NearToken::from_yoctonear(1),
-            GAS_FOR_FT_TRANSFER,
-        );
+        FarmEvent::EmergencyWithdrawn {
+            farm_id,
+            account_id: user,
+            amount: U128(to_withdraw),
+            penalty: U128(penalty),
+            forfeited_rewards: stake_info.accrued_rewards.iter().map(|&r| U128(r)).collect(),
+        }
+        .emit();
+    }
+
+    /// Sends `penalty` staking tokens, already removed from a stake, to the farm's
+    /// `penalty_destination`.
+    fn internal_apply_penalty(&mut self, farm: &mut FarmParams, penalty: u128) {
+        if penalty == 0 {
+            return;
+        }
+        let redistribute_to = farm
+            .reward_tokens
+            .iter()
+            .position(|t| t == &farm.staking_token);
+        match (&farm.penalty_destination, redistribute_to) {
+            (PenaltyDestination::Redistribute, Some(pos)) if farm.total_effective_staked > 0 => {
+                farm.credit_stakers(pos, penalty);
+            }
+            // Nobody is left to share it with, so it joins the emission pool.
+            (PenaltyDestination::Redistribute, Some(pos)) => {
+                farm.remaining_reward[pos] = farm.remaining_reward[pos].saturating_add(penalty);
+            }
+            (PenaltyDestination::Treasury { account_id }, _) => {
+                // If the transfer fails, the tokens stay in the contract.
+                Self::ft_transfer(farm.staking_token.clone(), account_id, penalty);
+            }
+            _ => farm.burned_penalty = farm.burned_penalty.saturating_add(penalty),
+        }
+    }
 
-        env::log_str(
-            format!(
-                "User {} withdrew {} staked tokens from farm {}",
-                user, to_withdraw, farm_id
-            ).as_str()
+    /// Private callback after the staking token `ft_transfer` issued by `withdraw`
+    /// or `emergency_withdraw`.
+    /// On failure the amount is staked back into the farm for the user.
+    /// Returns the amount that actually left the contract.
+    #[private]
+    pub fn on_withdraw_resolved(&mut self, account_id: AccountId, farm_id: u64, amount: U128) -> U128 {
+        assert_eq!(
+            env::promise_results_count(),
+            1,
+            "Expected one promise result."
         );
+        if let PromiseResult::Successful(_) = env::promise_result(0) {
+            return amount;
+        }
+
+        // Bring the farm up to date first so the restored stake does not earn
+        // rewards for the time it was out of the pool.
+        self.update_farm(farm_id);
+        let mut farm = self.internal_get_farm(farm_id).expect("Farm not found");
+        let stake_key = (account_id.clone(), farm_id);
+        let initial_storage = self.billable_storage_usage();
+        let mut stake_info = self.internal_get_stake(&stake_key).unwrap_or_else(|| StakeInfo::new(&farm));
+        self.internal_settle(farm_id, &farm, &mut stake_info);
+
+        // The tokens had been released, so they come back unlocked.
+        let now = env::block_timestamp();
+        stake_info.add_position(&mut farm, amount.0, now, now, BASE_MULTIPLIER_BPS);
+
+        self.internal_save_stake(&stake_key, &stake_info);
+        self.internal_save_farm(farm_id, &farm);
+        self.internal_track_storage(&account_id, initial_storage);
+
+        FarmEvent::WithdrawReverted {
+            farm_id,
+            account_id,
+            amount,
+        }
+        .emit();
+        U128(0)
     }
 }
 
@@ -557,12 +998,20 @@ impl ChildFarmingContract {
 //            TESTS
 //------------------------------------
 #[cfg(test)]
+#[allow(clippy::useless_conversion, clippy::assertions_on_constants)]
 mod tests {
     use near_sdk::test_utils::accounts;
     use super::*;
     use near_sdk::test_utils::VMContextBuilder;
+    use crate::events::{EventLog, EVENT_STANDARD, EVENT_STANDARD_VERSION};
+    use crate::math::ACC_REWARD_MULTIPLIER;
+    use crate::compound::MAX_COMPOUND_STORAGE_BYTES;
+    use crate::migration::ChildFarmingContractV1;
+    use crate::solvency::TokenLiabilities;
+    use near_contract_standards::storage_management::StorageManagement;
     use core::convert::TryFrom;
-    use near_sdk::testing_env;
+    use near_sdk::{testing_env, RuntimeFeesConfig};
+    use near_sdk::json_types::Base64VecU8;
 
     fn get_context(
         predecessor: AccountId,
@@ -577,16 +1026,42 @@ mod tests {
         builder
     }
 
+    /// Parses the NEP-297 events logged since the context was last set.
+    fn emitted_events() -> Vec<FarmEvent> {
+        near_sdk::test_utils::get_logs()
+            .iter()
+            .filter_map(|log| log.strip_prefix("EVENT_JSON:"))
+            .map(|json| {
+                let log: EventLog = near_sdk::serde_json::from_str(json).unwrap();
+                assert_eq!(log.standard, EVENT_STANDARD);
+                assert_eq!(log.version, EVENT_STANDARD_VERSION);
+                log.event
+            })
+            .collect()
+    }
+
+    /// Sets up the context for a private callback that sees `result`.
+    fn set_callback_context(block_timestamp_nanos: u64, result: PromiseResult) {
+        let context = get_context(env::current_account_id(), block_timestamp_nanos, 0);
+        testing_env!(
+            context.build(),
+            near_sdk::test_vm_config(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![result]
+        );
+    }
+
     #[test]
     fn test_storage_deposit_and_create_farm() {
         let mut context = get_context(accounts(0), 0, 0);
         testing_env!(context.build());
-        let mut contract = ChildFarmingContract::new("owner.testnet".parse().unwrap());
+        let mut contract = ChildFarmingContract::new(accounts(0));
 
         // deposit 10 NEAR for storage
         context = get_context(accounts(0), 0, 10_000_000_000_000_000_000_000_000);
         testing_env!(context.build());
-        contract.storage_deposit();
+        contract.storage_deposit(None, None);
 
         // create farm with 1 reward token => should pass
         let input = FarmInput {
@@ -596,12 +1071,17 @@ mod tests {
             reward_per_session: vec![U128(100)],
             session_interval_sec: 10,
             start_at_sec: 0,
+            end_at_sec: None,
+            emission_schedule: vec![],
+            distribution_mode: DistributionMode::Session,
+            idle_reward_policy: IdleRewardPolicy::ReturnToFunder,
+            lock_tiers: vec![],
         };
         let farm_id = contract.create_farm(input);
         assert_eq!(farm_id, 0);
 
         // check stored
-        let farm = contract.farms.get(&0).unwrap();
+        let farm = contract.internal_get_farm(0).unwrap();
         assert_eq!(farm.staking_token, "staking.token");
         assert_eq!(farm.reward_tokens.len(), 1);
         assert_eq!(farm.status, FarmStatus::Active);
@@ -613,7 +1093,7 @@ mod tests {
     fn test_create_farm_insufficient_storage() {
         let context = get_context(accounts(0), 0, 0);
         testing_env!(context.build());
-        let mut contract = ChildFarmingContract::new("owner.testnet".parse().unwrap());
+        let mut contract = ChildFarmingContract::new(accounts(0));
 
         let input = FarmInput {
             staking_token: "token".parse().unwrap(),
@@ -622,6 +1102,11 @@ mod tests {
             reward_per_session: vec![U128(10)],
             session_interval_sec: 5,
             start_at_sec: 0,
+            end_at_sec: None,
+            emission_schedule: vec![],
+            distribution_mode: DistributionMode::Session,
+            idle_reward_policy: IdleRewardPolicy::ReturnToFunder,
+            lock_tiers: vec![],
         };
         // no deposit => create_farm fails
         contract.create_farm(input);
@@ -630,13 +1115,16 @@ mod tests {
     #[test]
     #[should_panic(expected = "Insufficient storage. Need")]
     fn test_create_farm_insufficient_storage_multitoken() {
-        let context = get_context(accounts(0), 0, 1);
+        let mut context = get_context(accounts(0), 0, 0);
+        testing_env!(context.build());
+        let mut contract = ChildFarmingContract::new(accounts(0));
+        let min = contract.storage_balance_bounds().min.as_yoctonear();
+        context = get_context(accounts(0), 0, min);
         testing_env!(context.build());
-        let mut contract = ChildFarmingContract::new("owner.testnet".parse().unwrap());
-        contract.storage_deposit();
+        contract.storage_deposit(None, None);
 
         // tries to create a farm with 2 reward tokens 
-        // => we likely need more deposit
+        // => registration alone does not cover the farm
         let input = FarmInput {
             staking_token: "staking.token".parse().unwrap(),
             reward_tokens: vec!["reward1.token".parse().unwrap(), "reward2.token".parse().unwrap()],
@@ -644,6 +1132,11 @@ mod tests {
             reward_per_session: vec![U128(100), U128(200)],
             session_interval_sec: 10,
             start_at_sec: 0,
+            end_at_sec: None,
+            emission_schedule: vec![],
+            distribution_mode: DistributionMode::Session,
+            idle_reward_policy: IdleRewardPolicy::ReturnToFunder,
+            lock_tiers: vec![],
         };
         contract.create_farm(input);
     }
@@ -653,8 +1146,8 @@ mod tests {
     fn test_staking_flow() {
         let mut context = get_context(accounts(0), 0, 10_000_000_000_000_000_000_000_000);
         testing_env!(context.build());
-        let mut contract = ChildFarmingContract::new("owner.testnet".parse().unwrap());
-        contract.storage_deposit();
+        let mut contract = ChildFarmingContract::new(accounts(0));
+        contract.storage_deposit(None, None);
 
         // create farm
         let input = FarmInput {
@@ -664,6 +1157,11 @@ mod tests {
             reward_per_session: vec![U128(100)],
             session_interval_sec: 5,
             start_at_sec: 0,
+            end_at_sec: None,
+            emission_schedule: vec![],
+            distribution_mode: DistributionMode::Session,
+            idle_reward_policy: IdleRewardPolicy::ReturnToFunder,
+            lock_tiers: vec![],
         };
         let farm_id = contract.create_farm(input);
 
@@ -679,7 +1177,7 @@ mod tests {
 
         // check user stake
         let stake_key = (accounts(0), farm_id);
-        let stake_info = contract.stakes.get(&stake_key).unwrap();
+        let stake_info = contract.internal_get_stake(&stake_key).unwrap();
         assert_eq!(stake_info.amount, 500);
     }
 
@@ -689,8 +1187,8 @@ mod tests {
         // 1) Setup contract & deposit enough for farm creation
         let context = get_context(accounts(0), 0, 10_u128.pow(24)); // 1 NEAR
         testing_env!(context.build());
-        let mut contract = ChildFarmingContract::new("owner.testnet".parse().unwrap());
-        contract.storage_deposit(); // now user(0) can create a farm
+        let mut contract = ChildFarmingContract::new(accounts(0));
+        contract.storage_deposit(None, None); // now user(0) can create a farm
 
         // create farm
         let farm_id = contract.create_farm(FarmInput {
@@ -700,6 +1198,11 @@ mod tests {
             reward_per_session: vec![U128(100)],
             session_interval_sec: 5,
             start_at_sec: 0,
+            end_at_sec: None,
+            emission_schedule: vec![],
+            distribution_mode: DistributionMode::Session,
+            idle_reward_policy: IdleRewardPolicy::ReturnToFunder,
+            lock_tiers: vec![],
         });
 
         // 2) Now reset context for the same user or a different user but with minimal deposit
@@ -720,8 +1223,8 @@ mod tests {
     fn test_add_reward() {
         let mut context = get_context(accounts(0), 0, 10_000_000_000_000_000_000_000_000);
         testing_env!(context.build());
-        let mut contract = ChildFarmingContract::new("owner.testnet".parse().unwrap());
-        contract.storage_deposit();
+        let mut contract = ChildFarmingContract::new(accounts(0));
+        contract.storage_deposit(None, None);
 
         // create farm
         let input = FarmInput {
@@ -731,6 +1234,11 @@ mod tests {
             reward_per_session: vec![U128(100)],
             session_interval_sec: 5,
             start_at_sec: 0,
+            end_at_sec: None,
+            emission_schedule: vec![],
+            distribution_mode: DistributionMode::Session,
+            idle_reward_policy: IdleRewardPolicy::ReturnToFunder,
+            lock_tiers: vec![],
         };
         let farm_id = contract.create_farm(input);
 
@@ -751,8 +1259,8 @@ mod tests {
     fn test_session_based_distribution() {
         let mut context = get_context(accounts(0), 0, 10_000_000_000_000_000_000_000_000);
         testing_env!(context.build());
-        let mut contract = ChildFarmingContract::new("owner.testnet".parse().unwrap());
-        contract.storage_deposit();
+        let mut contract = ChildFarmingContract::new(accounts(0));
+        contract.storage_deposit(None, None);
 
         // create farm: interval=10s, reward_per_session=100
         let input = FarmInput {
@@ -762,6 +1270,11 @@ mod tests {
             reward_per_session: vec![U128(100)],
             session_interval_sec: 10,
             start_at_sec: 0,
+            end_at_sec: None,
+            emission_schedule: vec![],
+            distribution_mode: DistributionMode::Session,
+            idle_reward_policy: IdleRewardPolicy::ReturnToFunder,
+            lock_tiers: vec![],
         };
         let farm_id = contract.create_farm(input);
 
@@ -791,15 +1304,15 @@ mod tests {
         testing_env!(context.build());
         contract.claim_rewards(farm_id);
 
-        let farm = contract.farms.get(&farm_id).unwrap();
+        let farm = contract.internal_get_farm(farm_id).unwrap();
         // With 2 sessions and 100 tokens per session distributed over 100 staked tokens,
         // the raw reward_per_share should have increased by 2 * ACC_REWARD_MULTIPLIER.
         // We check the unscaled value.
-        assert_eq!(farm.reward_per_share[0] / ACC_REWARD_MULTIPLIER, 2);
+        assert_eq!(farm.reward_per_share[0] / U256::from(ACC_REWARD_MULTIPLIER), U256::from(2));
 
         // after claim => accrued rewards should be 0.
         let stake_key = (accounts(0), farm_id);
-        let stake_info = contract.stakes.get(&stake_key).unwrap();
+        let stake_info = contract.internal_get_stake(&stake_key).unwrap();
         assert_eq!(stake_info.accrued_rewards[0], 0);
     }
 
@@ -808,8 +1321,8 @@ mod tests {
     fn test_withdraw_lockup_fail() {
         let mut context = get_context(accounts(0), 0, 10_000_000_000_000_000_000_000_000);
         testing_env!(context.build());
-        let mut contract = ChildFarmingContract::new("owner.testnet".parse().unwrap());
-        contract.storage_deposit();
+        let mut contract = ChildFarmingContract::new(accounts(0));
+        contract.storage_deposit(None, None);
 
         let input = FarmInput {
             staking_token: "staking.token".parse().unwrap(),
@@ -818,6 +1331,11 @@ mod tests {
             reward_per_session: vec![U128(10)],
             session_interval_sec: 5,
             start_at_sec: 0,
+            end_at_sec: None,
+            emission_schedule: vec![],
+            distribution_mode: DistributionMode::Session,
+            idle_reward_policy: IdleRewardPolicy::ReturnToFunder,
+            lock_tiers: vec![],
         };
         let farm_id = contract.create_farm(input);
 
@@ -841,8 +1359,8 @@ mod tests {
     fn test_withdraw_lockup_success() {
         let mut context = get_context(accounts(0), 0, 10_000_000_000_000_000_000_000_000);
         testing_env!(context.build());
-        let mut contract = ChildFarmingContract::new("owner.testnet".parse().unwrap());
-        contract.storage_deposit();
+        let mut contract = ChildFarmingContract::new(accounts(0));
+        contract.storage_deposit(None, None);
 
         let input = FarmInput {
             staking_token: "staking.token".parse().unwrap(),
@@ -851,6 +1369,11 @@ mod tests {
             reward_per_session: vec![U128(10)],
             session_interval_sec: 5,
             start_at_sec: 0,
+            end_at_sec: None,
+            emission_schedule: vec![],
+            distribution_mode: DistributionMode::Session,
+            idle_reward_policy: IdleRewardPolicy::ReturnToFunder,
+            lock_tiers: vec![],
         };
         let farm_id = contract.create_farm(input);
 
@@ -874,7 +1397,7 @@ mod tests {
         contract.withdraw(farm_id, U128(50));
 
         let stake_key = (accounts(0), farm_id);
-        let stake_info = contract.stakes.get(&stake_key).unwrap();
+        let stake_info = contract.internal_get_stake(&stake_key).unwrap();
         // withdrew half, leaving 50 staked.
         assert_eq!(stake_info.amount, 50);
     }
@@ -883,8 +1406,8 @@ mod tests {
     fn test_future_start_time() {
         let mut context = get_context(accounts(0), 0, 10_000_000_000_000_000_000_000_000);
         testing_env!(context.build());
-        let mut contract = ChildFarmingContract::new("owner.testnet".parse().unwrap());
-        contract.storage_deposit();
+        let mut contract = ChildFarmingContract::new(accounts(0));
+        contract.storage_deposit(None, None);
 
         // Create a farm that starts at sec=100.
         let input = FarmInput {
@@ -894,6 +1417,11 @@ mod tests {
             reward_per_session: vec![U128(10)],
             session_interval_sec: 5,
             start_at_sec: 100,
+            end_at_sec: None,
+            emission_schedule: vec![],
+            distribution_mode: DistributionMode::Session,
+            idle_reward_policy: IdleRewardPolicy::ReturnToFunder,
+            lock_tiers: vec![],
         };
         let farm_id = contract.create_farm(input);
 
@@ -912,9 +1440,9 @@ mod tests {
         testing_env!(context.build());
         contract.claim_rewards(farm_id);
 
-        let farm = contract.farms.get(&farm_id).unwrap();
+        let farm = contract.internal_get_farm(farm_id).unwrap();
         // No sessions have elapsed so reward_per_share should be 0.
-        assert_eq!(farm.reward_per_share[0], 0);
+        assert_eq!(farm.reward_per_share[0], U256::zero());
     }
 
     #[test]
@@ -924,12 +1452,12 @@ mod tests {
         let deposit = 1_000_000_000_000_000_000_000_000;
         let mut context = get_context(accounts(0), 0, deposit);
         testing_env!(context.build());
-        let mut contract = ChildFarmingContract::new("owner.testnet".parse().unwrap());
-        contract.storage_deposit();
+        let mut contract = ChildFarmingContract::new(accounts(1));
+        contract.storage_deposit(None, None);
         // Also deposit storage for accounts(1).
         context = get_context(accounts(1), 0, deposit);
         testing_env!(context.build());
-        contract.storage_deposit();
+        contract.storage_deposit(None, None);
         // Create a farm.
         let input = FarmInput {
             staking_token: "staking.token".parse().unwrap(),
@@ -938,6 +1466,11 @@ mod tests {
             reward_per_session: vec![U128(100)],
             session_interval_sec: 5,
             start_at_sec: 0,
+            end_at_sec: None,
+            emission_schedule: vec![],
+            distribution_mode: DistributionMode::Session,
+            idle_reward_policy: IdleRewardPolicy::ReturnToFunder,
+            lock_tiers: vec![],
         };
         let farm_id = contract.create_farm(input);
         // Fund the farm with 50 tokens (insufficient for one full session).
@@ -956,4 +1489,2774 @@ mod tests {
         testing_env!(context.build());
         contract.ft_on_transfer(AccountId::try_from(accounts(1)).unwrap(), U128(50), stake_msg);
     }
+
+    #[test]
+    fn test_withdraw_transfer_failure_restores_stake() {
+        let mut context = get_context(accounts(0), 0, 10_000_000_000_000_000_000_000_000);
+        testing_env!(context.build());
+        let mut contract = ChildFarmingContract::new(accounts(0));
+        contract.storage_deposit(None, None);
+
+        let farm_id = contract.create_farm(FarmInput {
+            staking_token: "staking.token".parse().unwrap(),
+            reward_tokens: vec!["reward.token".parse().unwrap()],
+            lockup_period_sec: 2,
+            reward_per_session: vec![U128(100)],
+            session_interval_sec: 10,
+            start_at_sec: 0,
+            end_at_sec: None,
+            emission_schedule: vec![],
+            distribution_mode: DistributionMode::Session,
+            idle_reward_policy: IdleRewardPolicy::ReturnToFunder,
+            lock_tiers: vec![],
+        });
+
+        context = get_context("reward.token".parse().unwrap(), 0, 0);
+        testing_env!(context.build());
+        contract.ft_on_transfer(accounts(0), U128(1_000), "ADD_REWARD:0".to_string());
+
+        context = get_context("staking.token".parse().unwrap(), 0, 0);
+        testing_env!(context.build());
+        contract.ft_on_transfer(accounts(0), U128(100), "STAKE:0".to_string());
+
+        // Withdraw everything after one session => the stake is emptied optimistically.
+        context = get_context(accounts(0), 10_000_000_000, 1);
+        testing_env!(context.build());
+        contract.withdraw(farm_id, U128(100));
+        assert_eq!(contract.internal_get_stake(&(accounts(0), farm_id)).unwrap().amount, 0);
+        assert_eq!(contract.internal_get_farm(farm_id).unwrap().total_staked, 0);
+
+        // The token contract rejects the transfer.
+        set_callback_context(10_000_000_000, PromiseResult::Failed);
+        let withdrawn = contract.on_withdraw_resolved(accounts(0), farm_id, U128(100));
+        assert_eq!(withdrawn.0, 0);
+
+        let farm = contract.internal_get_farm(farm_id).unwrap();
+        assert_eq!(farm.total_staked, 100);
+        let stake_info = contract.internal_get_stake(&(accounts(0), farm_id)).unwrap();
+        assert_eq!(stake_info.amount, 100);
+        // Rewards settled during the withdraw are kept.
+        assert_eq!(stake_info.accrued_rewards[0], 100);
+        assert_eq!(stake_info.reward_debt[0], farm.reward_per_share[0]);
+    }
+
+    #[test]
+    fn test_withdraw_transfer_success_keeps_books() {
+        let mut context = get_context(accounts(0), 0, 10_000_000_000_000_000_000_000_000);
+        testing_env!(context.build());
+        let mut contract = ChildFarmingContract::new(accounts(0));
+        contract.storage_deposit(None, None);
+
+        let farm_id = contract.create_farm(FarmInput {
+            staking_token: "staking.token".parse().unwrap(),
+            reward_tokens: vec!["reward.token".parse().unwrap()],
+            lockup_period_sec: 0,
+            reward_per_session: vec![U128(100)],
+            session_interval_sec: 10,
+            start_at_sec: 0,
+            end_at_sec: None,
+            emission_schedule: vec![],
+            distribution_mode: DistributionMode::Session,
+            idle_reward_policy: IdleRewardPolicy::ReturnToFunder,
+            lock_tiers: vec![],
+        });
+
+        context = get_context("staking.token".parse().unwrap(), 0, 0);
+        testing_env!(context.build());
+        contract.ft_on_transfer(accounts(0), U128(100), "STAKE:0".to_string());
+
+        context = get_context(accounts(0), 1_000_000_000, 1);
+        testing_env!(context.build());
+        contract.withdraw(farm_id, U128(40));
+
+        set_callback_context(1_000_000_000, PromiseResult::Successful(vec![]));
+        let withdrawn = contract.on_withdraw_resolved(accounts(0), farm_id, U128(40));
+        assert_eq!(withdrawn.0, 40);
+
+        assert_eq!(contract.internal_get_farm(farm_id).unwrap().total_staked, 60);
+        assert_eq!(contract.internal_get_stake(&(accounts(0), farm_id)).unwrap().amount, 60);
+    }
+
+    #[test]
+    fn test_claim_transfer_failure_restores_rewards() {
+        let mut context = get_context(accounts(0), 0, 10_000_000_000_000_000_000_000_000);
+        testing_env!(context.build());
+        let mut contract = ChildFarmingContract::new(accounts(0));
+        contract.storage_deposit(None, None);
+
+        let farm_id = contract.create_farm(FarmInput {
+            staking_token: "staking.token".parse().unwrap(),
+            reward_tokens: vec!["reward1.token".parse().unwrap(), "reward2.token".parse().unwrap()],
+            lockup_period_sec: 0,
+            reward_per_session: vec![U128(100), U128(10)],
+            session_interval_sec: 10,
+            start_at_sec: 0,
+            end_at_sec: None,
+            emission_schedule: vec![],
+            distribution_mode: DistributionMode::Session,
+            idle_reward_policy: IdleRewardPolicy::ReturnToFunder,
+            lock_tiers: vec![],
+        });
+
+        context = get_context("reward1.token".parse().unwrap(), 0, 0);
+        testing_env!(context.build());
+        contract.ft_on_transfer(accounts(0), U128(1_000), "ADD_REWARD:0".to_string());
+        context = get_context("reward2.token".parse().unwrap(), 0, 0);
+        testing_env!(context.build());
+        contract.ft_on_transfer(accounts(0), U128(1_000), "ADD_REWARD:0".to_string());
+
+        context = get_context("staking.token".parse().unwrap(), 0, 0);
+        testing_env!(context.build());
+        contract.ft_on_transfer(accounts(0), U128(100), "STAKE:0".to_string());
+
+        // Two sessions => 200 of reward1 and 20 of reward2.
+        context = get_context(accounts(0), 20_000_000_000, 1);
+        testing_env!(context.build());
+        contract.claim_rewards(farm_id);
+        let stake_info = contract.internal_get_stake(&(accounts(0), farm_id)).unwrap();
+        assert_eq!(stake_info.accrued_rewards, vec![0, 0]);
+
+        // reward1 transfer fails, reward2 transfer succeeds.
+        set_callback_context(20_000_000_000, PromiseResult::Failed);
+        let claimed = contract.on_claim_resolved(
+            accounts(0),
+            farm_id,
+            "reward1.token".parse().unwrap(),
+            U128(200),
+        );
+        assert_eq!(claimed.0, 0);
+        set_callback_context(20_000_000_000, PromiseResult::Successful(vec![]));
+        let claimed = contract.on_claim_resolved(
+            accounts(0),
+            farm_id,
+            "reward2.token".parse().unwrap(),
+            U128(20),
+        );
+        assert_eq!(claimed.0, 20);
+
+        let stake_info = contract.internal_get_stake(&(accounts(0), farm_id)).unwrap();
+        assert_eq!(stake_info.amount, 100);
+        assert_eq!(stake_info.accrued_rewards, vec![200, 0]);
+    }
+
+    #[test]
+    fn test_claim_transfer_failure_after_full_withdraw() {
+        let mut context = get_context(accounts(0), 0, 10_000_000_000_000_000_000_000_000);
+        testing_env!(context.build());
+        let mut contract = ChildFarmingContract::new(accounts(0));
+        contract.storage_deposit(None, None);
+
+        let farm_id = contract.create_farm(FarmInput {
+            staking_token: "staking.token".parse().unwrap(),
+            reward_tokens: vec!["reward.token".parse().unwrap()],
+            lockup_period_sec: 0,
+            reward_per_session: vec![U128(100)],
+            session_interval_sec: 10,
+            start_at_sec: 0,
+            end_at_sec: None,
+            emission_schedule: vec![],
+            distribution_mode: DistributionMode::Session,
+            idle_reward_policy: IdleRewardPolicy::ReturnToFunder,
+            lock_tiers: vec![],
+        });
+
+        context = get_context("reward.token".parse().unwrap(), 0, 0);
+        testing_env!(context.build());
+        contract.ft_on_transfer(accounts(0), U128(1_000), "ADD_REWARD:0".to_string());
+        context = get_context("staking.token".parse().unwrap(), 0, 0);
+        testing_env!(context.build());
+        contract.ft_on_transfer(accounts(0), U128(100), "STAKE:0".to_string());
+
+        context = get_context(accounts(0), 10_000_000_000, 1);
+        testing_env!(context.build());
+        contract.claim_rewards(farm_id);
+        contract.withdraw(farm_id, U128(100));
+        assert!(contract.internal_get_stake(&(accounts(0), farm_id)).is_none());
+
+        // The reward transfer fails after the stake is gone => rewards are still owed.
+        set_callback_context(10_000_000_000, PromiseResult::Failed);
+        contract.on_claim_resolved(accounts(0), farm_id, "reward.token".parse().unwrap(), U128(100));
+
+        let stake_info = contract.internal_get_stake(&(accounts(0), farm_id)).unwrap();
+        assert_eq!(stake_info.amount, 0);
+        assert_eq!(stake_info.accrued_rewards[0], 100);
+    }
+
+    #[test]
+    fn test_storage_deposit_registration_only() {
+        let mut context = get_context(accounts(0), 0, 0);
+        testing_env!(context.build());
+        let mut contract = ChildFarmingContract::new(accounts(0));
+        let min = contract.storage_balance_bounds().min;
+        assert!(min.as_yoctonear() > 0);
+        assert!(contract.storage_balance_of(accounts(1)).is_none());
+
+        // accounts(0) registers accounts(1) and overpays => only the minimum is kept.
+        context = get_context(accounts(0), 0, min.as_yoctonear() * 3);
+        testing_env!(context.build());
+        let balance = contract.storage_deposit(Some(accounts(1)), Some(true));
+        assert_eq!(balance.total, min);
+        assert_eq!(balance.available.as_yoctonear(), 0);
+
+        let balance = contract.storage_balance_of(accounts(1)).unwrap();
+        assert_eq!(balance.total, min);
+        assert!(contract.storage_balance_of(accounts(0)).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "The attached deposit is less than the minimum storage balance")]
+    fn test_storage_deposit_below_minimum() {
+        let context = get_context(accounts(0), 0, 1);
+        testing_env!(context.build());
+        let mut contract = ChildFarmingContract::new(accounts(0));
+        contract.storage_deposit(None, None);
+    }
+
+    #[test]
+    fn test_storage_tracks_farm_and_stake_usage() {
+        let deposit = 10_000_000_000_000_000_000_000_000;
+        let mut context = get_context(accounts(0), 0, deposit);
+        testing_env!(context.build());
+        let mut contract = ChildFarmingContract::new(accounts(0));
+        contract.storage_deposit(None, None);
+        let registered = contract.storage_balance_of(accounts(0)).unwrap();
+        assert_eq!(registered.total.as_yoctonear(), deposit);
+
+        let farm_id = contract.create_farm(FarmInput {
+            staking_token: "staking.token".parse().unwrap(),
+            reward_tokens: vec!["reward.token".parse().unwrap()],
+            lockup_period_sec: 0,
+            reward_per_session: vec![U128(100)],
+            session_interval_sec: 10,
+            start_at_sec: 0,
+            end_at_sec: None,
+            emission_schedule: vec![],
+            distribution_mode: DistributionMode::Session,
+            idle_reward_policy: IdleRewardPolicy::ReturnToFunder,
+            lock_tiers: vec![],
+        });
+        let after_farm = contract.storage_balance_of(accounts(0)).unwrap();
+        assert_eq!(after_farm.total.as_yoctonear(), deposit);
+        assert!(after_farm.available < registered.available);
+
+        context = get_context("staking.token".parse().unwrap(), 0, 0);
+        testing_env!(context.build());
+        contract.ft_on_transfer(accounts(0), U128(100), "STAKE:0".to_string());
+        let after_stake = contract.storage_balance_of(accounts(0)).unwrap();
+        assert!(after_stake.available < after_farm.available);
+
+        // Withdrawing the whole stake frees its bytes again. Its checkpoints stay and
+        // remain paid for out of the deposit.
+        context = get_context(accounts(0), 0, 1);
+        testing_env!(context.build());
+        contract.withdraw(farm_id, U128(100));
+        let after_withdraw = contract.storage_balance_of(accounts(0)).unwrap();
+        let history_cost = contract.history_storage_usage as u128 * env::storage_byte_cost().as_yoctonear();
+        assert_eq!(after_withdraw.total.as_yoctonear(), deposit - history_cost);
+        assert_eq!(after_withdraw.available.as_yoctonear(), after_farm.available.as_yoctonear() - history_cost);
+    }
+
+    #[test]
+    #[should_panic(expected = "Insufficient storage")]
+    fn test_checkpoints_need_storage_deposit() {
+        let (mut contract, farm_id) = setup_staked_farm(0);
+        // Leave accounts(1) with only what its stake needs.
+        let context = get_context(accounts(1), 0, 1);
+        testing_env!(context.build());
+        contract.storage_withdraw(None);
+
+        // A withdrawal in a later block adds checkpoints it can no longer pay for.
+        withdraw_at(&mut contract, farm_id, 1, 10);
+    }
+
+    #[test]
+    fn test_unregister_after_stake_and_withdraw_in_later_blocks() {
+        let mut context = get_context(accounts(0), 0, 10_000_000_000_000_000_000_000_000);
+        testing_env!(context.build());
+        let mut contract = ChildFarmingContract::new(accounts(0));
+        contract.storage_deposit(None, None);
+        let farm_id = contract.create_farm(default_farm_input());
+        context = get_context(accounts(1), 0, 1_000_000_000_000_000_000_000_000);
+        testing_env!(context.build());
+        contract.storage_deposit(None, None);
+
+        // Each block adds balance history, which the staker pays for once.
+        let before = contract.storage_balance_of(accounts(1)).unwrap();
+        stake_at(&mut contract, farm_id, 100, 0);
+        withdraw_at(&mut contract, farm_id, 40, 10);
+        withdraw_at(&mut contract, farm_id, 60, 20);
+        assert_eq!(contract.stake_at(accounts(1), farm_id, 15).0, 60);
+        assert_eq!(contract.storage_deposits.get(&accounts(1)).unwrap().used_bytes, 0);
+        let history_cost = contract.history_storage_usage as u128 * env::storage_byte_cost().as_yoctonear();
+        let after = contract.storage_balance_of(accounts(1)).unwrap();
+        assert_eq!(after.total.as_yoctonear(), before.total.as_yoctonear() - history_cost);
+
+        context = get_context(accounts(1), 30_000_000_000, 1);
+        testing_env!(context.build());
+        assert!(contract.storage_unregister(None));
+        assert!(contract.storage_balance_of(accounts(1)).is_none());
+        assert_eq!(contract.stake_at(accounts(1), farm_id, 15).0, 60);
+    }
+
+    #[test]
+    #[should_panic(expected = "The amount is greater than the available storage balance")]
+    fn test_storage_withdraw_cannot_drain_used_storage() {
+        let mut context = get_context(accounts(0), 0, 10_000_000_000_000_000_000_000_000);
+        testing_env!(context.build());
+        let mut contract = ChildFarmingContract::new(accounts(0));
+        contract.storage_deposit(None, None);
+        contract.create_farm(FarmInput {
+            staking_token: "staking.token".parse().unwrap(),
+            reward_tokens: vec!["reward.token".parse().unwrap()],
+            lockup_period_sec: 0,
+            reward_per_session: vec![U128(100)],
+            session_interval_sec: 10,
+            start_at_sec: 0,
+            end_at_sec: None,
+            emission_schedule: vec![],
+            distribution_mode: DistributionMode::Session,
+            idle_reward_policy: IdleRewardPolicy::ReturnToFunder,
+            lock_tiers: vec![],
+        });
+
+        context = get_context(accounts(0), 0, 1);
+        testing_env!(context.build());
+        // Withdrawing everything that is available leaves the farm covered.
+        let balance = contract.storage_withdraw(None);
+        assert_eq!(balance.available.as_yoctonear(), 0);
+        assert!(balance.total.as_yoctonear() > 0);
+        contract.storage_withdraw(Some(NearToken::from_yoctonear(1)));
+    }
+
+    #[test]
+    fn test_storage_unregister() {
+        let mut context = get_context(accounts(1), 0, 1_000_000_000_000_000_000_000_000);
+        testing_env!(context.build());
+        let mut contract = ChildFarmingContract::new(accounts(0));
+        contract.storage_deposit(None, None);
+
+        context = get_context(accounts(1), 0, 1);
+        testing_env!(context.build());
+        assert!(contract.storage_unregister(None));
+        assert!(contract.storage_balance_of(accounts(1)).is_none());
+        assert!(!contract.storage_unregister(None));
+    }
+
+    #[test]
+    #[should_panic(expected = "Can't unregister an account with active farms or stakes")]
+    fn test_storage_unregister_with_farm() {
+        let mut context = get_context(accounts(0), 0, 10_000_000_000_000_000_000_000_000);
+        testing_env!(context.build());
+        let mut contract = ChildFarmingContract::new(accounts(0));
+        contract.storage_deposit(None, None);
+        contract.create_farm(FarmInput {
+            staking_token: "staking.token".parse().unwrap(),
+            reward_tokens: vec!["reward.token".parse().unwrap()],
+            lockup_period_sec: 0,
+            reward_per_session: vec![U128(100)],
+            session_interval_sec: 10,
+            start_at_sec: 0,
+            end_at_sec: None,
+            emission_schedule: vec![],
+            distribution_mode: DistributionMode::Session,
+            idle_reward_policy: IdleRewardPolicy::ReturnToFunder,
+            lock_tiers: vec![],
+        });
+
+        context = get_context(accounts(0), 0, 1);
+        testing_env!(context.build());
+        contract.storage_unregister(Some(true));
+    }
+
+    fn default_farm_input() -> FarmInput {
+        FarmInput {
+            staking_token: "staking.token".parse().unwrap(),
+            reward_tokens: vec!["reward.token".parse().unwrap()],
+            lockup_period_sec: 0,
+            reward_per_session: vec![U128(100)],
+            session_interval_sec: 10,
+            start_at_sec: 0,
+            end_at_sec: None,
+            emission_schedule: vec![],
+            distribution_mode: DistributionMode::Session,
+            idle_reward_policy: IdleRewardPolicy::ReturnToFunder,
+            lock_tiers: vec![],
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the admin or an allowed farm creator can create farms")]
+    fn test_create_farm_not_allowed() {
+        let mut context = get_context(accounts(0), 0, 0);
+        testing_env!(context.build());
+        let mut contract = ChildFarmingContract::new(accounts(0));
+
+        context = get_context(accounts(1), 0, 10_000_000_000_000_000_000_000_000);
+        testing_env!(context.build());
+        contract.storage_deposit(None, None);
+        contract.create_farm(default_farm_input());
+    }
+
+    #[test]
+    fn test_allowed_farm_creator() {
+        let mut context = get_context(accounts(0), 0, 1);
+        testing_env!(context.build());
+        let mut contract = ChildFarmingContract::new(accounts(0));
+        contract.add_farm_creator(accounts(1));
+        assert_eq!(contract.list_farm_creators(), vec![accounts(1)]);
+
+        context = get_context(accounts(1), 0, 10_000_000_000_000_000_000_000_000);
+        testing_env!(context.build());
+        contract.storage_deposit(None, None);
+        let farm_id = contract.create_farm(default_farm_input());
+        assert_eq!(contract.get_farm(farm_id).unwrap().farm_creator, accounts(1));
+
+        // The creator can manage its own farm.
+        context = get_context(accounts(1), 0, 1);
+        testing_env!(context.build());
+        contract.set_lockup_period(farm_id, 30);
+        assert_eq!(contract.get_farm(farm_id).unwrap().lockup_period_sec, 30);
+
+        // Once removed, the account can no longer create farms.
+        context = get_context(accounts(0), 0, 1);
+        testing_env!(context.build());
+        contract.remove_farm_creator(accounts(1));
+        assert!(contract.list_farm_creators().is_empty());
+    }
+
+    #[test]
+    fn test_master_creates_farm() {
+        let master: AccountId = "master.near".parse().unwrap();
+        let mut context = get_context(master.clone(), 0, 10_000_000_000_000_000_000_000_000);
+        context.current_account_id("farm.master.near".parse().unwrap());
+        testing_env!(context.build());
+        let mut contract = ChildFarmingContract::new(accounts(0));
+        contract.storage_deposit(None, None);
+
+        let farm_id = contract.create_farm(default_farm_input());
+        assert_eq!(contract.get_farm(farm_id).unwrap().farm_creator, master);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the farm creator or admin can manage this farm")]
+    fn test_manage_farm_not_creator() {
+        let mut context = get_context(accounts(0), 0, 10_000_000_000_000_000_000_000_000);
+        testing_env!(context.build());
+        let mut contract = ChildFarmingContract::new(accounts(0));
+        contract.storage_deposit(None, None);
+        let farm_id = contract.create_farm(default_farm_input());
+
+        context = get_context(accounts(2), 0, 1);
+        testing_env!(context.build());
+        contract.set_reward_per_session(farm_id, vec![U128(1)]);
+    }
+
+    #[test]
+    fn test_set_reward_per_session_settles_old_rate() {
+        let mut context = get_context(accounts(0), 0, 10_000_000_000_000_000_000_000_000);
+        testing_env!(context.build());
+        let mut contract = ChildFarmingContract::new(accounts(0));
+        contract.storage_deposit(None, None);
+        let farm_id = contract.create_farm(default_farm_input());
+
+        context = get_context("reward.token".parse().unwrap(), 0, 0);
+        testing_env!(context.build());
+        contract.ft_on_transfer(accounts(0), U128(10_000), "ADD_REWARD:0".to_string());
+        context = get_context("staking.token".parse().unwrap(), 0, 0);
+        testing_env!(context.build());
+        contract.ft_on_transfer(accounts(0), U128(100), "STAKE:0".to_string());
+
+        // One session at 100 per session, then the rate changes to 300.
+        context = get_context(accounts(0), 10_000_000_000, 1);
+        testing_env!(context.build());
+        contract.set_reward_per_session(farm_id, vec![U128(300)]);
+        let farm = contract.internal_get_farm(farm_id).unwrap();
+        assert_eq!(farm.remaining_reward[0], 9_900);
+        assert_eq!(farm.reward_per_session, vec![300]);
+
+        context = get_context(accounts(0), 20_000_000_000, 1);
+        testing_env!(context.build());
+        contract.claim_rewards(farm_id);
+        let farm = contract.internal_get_farm(farm_id).unwrap();
+        assert_eq!(farm.remaining_reward[0], 9_600);
+    }
+
+    #[test]
+    fn test_two_step_admin_transfer() {
+        let mut context = get_context(accounts(0), 0, 1);
+        testing_env!(context.build());
+        let mut contract = ChildFarmingContract::new(accounts(0));
+        contract.propose_admin(accounts(1));
+        assert_eq!(contract.get_admin(), accounts(0));
+        assert_eq!(contract.get_pending_admin(), Some(accounts(1)));
+
+        context = get_context(accounts(1), 0, 1);
+        testing_env!(context.build());
+        contract.accept_admin();
+        assert_eq!(contract.get_admin(), accounts(1));
+        assert_eq!(contract.get_pending_admin(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the pending admin can accept the admin role")]
+    fn test_accept_admin_not_pending() {
+        let mut context = get_context(accounts(0), 0, 1);
+        testing_env!(context.build());
+        let mut contract = ChildFarmingContract::new(accounts(0));
+        contract.propose_admin(accounts(1));
+
+        context = get_context(accounts(2), 0, 1);
+        testing_env!(context.build());
+        contract.accept_admin();
+    }
+
+    #[test]
+    #[should_panic(expected = "Only admin can call this method")]
+    fn test_propose_admin_not_admin() {
+        let mut context = get_context(accounts(0), 0, 1);
+        testing_env!(context.build());
+        let mut contract = ChildFarmingContract::new(accounts(0));
+
+        context = get_context(accounts(1), 0, 1);
+        testing_env!(context.build());
+        contract.propose_admin(accounts(1));
+    }
+
+    #[test]
+    fn test_event_json_format() {
+        let context = get_context(accounts(0), 0, 0);
+        testing_env!(context.build());
+        FarmEvent::Staked {
+            farm_id: 3,
+            account_id: accounts(1),
+            amount: U128(100),
+        }
+        .emit();
+        assert_eq!(
+            near_sdk::test_utils::get_logs(),
+            vec![r#"EVENT_JSON:{"standard":"farm","version":"1.0.0","event":"staked","data":{"farm_id":3,"account_id":"bob","amount":"100"}}"#]
+        );
+    }
+
+    #[test]
+    fn test_farm_lifecycle_events() {
+        let mut context = get_context(accounts(0), 0, 10_000_000_000_000_000_000_000_000);
+        testing_env!(context.build());
+        let mut contract = ChildFarmingContract::new(accounts(0));
+        contract.storage_deposit(None, None);
+        assert_eq!(
+            emitted_events(),
+            vec![FarmEvent::StorageDeposit {
+                account_id: accounts(0),
+                amount: U128(10_000_000_000_000_000_000_000_000),
+            }]
+        );
+
+        context = get_context(accounts(0), 0, 0);
+        testing_env!(context.build());
+        let farm_id = contract.create_farm(default_farm_input());
+        assert_eq!(
+            emitted_events(),
+            vec![FarmEvent::FarmCreated {
+                farm_id,
+                farm_creator: accounts(0),
+                staking_token: "staking.token".parse().unwrap(),
+                reward_tokens: vec!["reward.token".parse().unwrap()],
+                reward_per_session: vec![U128(100)],
+                session_interval_sec: 10,
+                start_at_sec: 0,
+                lockup_period_sec: 0,
+                end_at_sec: None,
+                emission_schedule: vec![],
+                distribution_mode: DistributionMode::Session,
+                idle_reward_policy: IdleRewardPolicy::ReturnToFunder,
+                lock_tiers: vec![],
+            }]
+        );
+
+        context = get_context("reward.token".parse().unwrap(), 0, 0);
+        testing_env!(context.build());
+        contract.ft_on_transfer(accounts(1), U128(150), "ADD_REWARD:0".to_string());
+        assert_eq!(
+            emitted_events(),
+            vec![FarmEvent::RewardAdded {
+                farm_id,
+                sender_id: accounts(1),
+                reward_token: "reward.token".parse().unwrap(),
+                amount: U128(150),
+            }]
+        );
+
+        context = get_context("staking.token".parse().unwrap(), 0, 0);
+        testing_env!(context.build());
+        contract.ft_on_transfer(accounts(0), U128(100), "STAKE:0".to_string());
+        assert_eq!(
+            emitted_events(),
+            vec![FarmEvent::Staked {
+                farm_id,
+                account_id: accounts(0),
+                amount: U128(100),
+            }]
+        );
+
+        // Two sessions elapse but only 150 is funded => the pool empties and the farm ends.
+        context = get_context(accounts(0), 20_000_000_000, 1);
+        testing_env!(context.build());
+        contract.claim_rewards(farm_id);
+        assert_eq!(
+            emitted_events(),
+            vec![
+                FarmEvent::FarmEnded { farm_id },
+                FarmEvent::RewardsClaimed {
+                    farm_id,
+                    account_id: accounts(0),
+                    reward_token: "reward.token".parse().unwrap(),
+                    amount: U128(150),
+                },
+            ]
+        );
+
+        context = get_context(accounts(0), 20_000_000_000, 1);
+        testing_env!(context.build());
+        contract.withdraw(farm_id, U128(100));
+        assert_eq!(
+            emitted_events(),
+            vec![FarmEvent::Withdrawn {
+                farm_id,
+                account_id: accounts(0),
+                amount: U128(100),
+            }]
+        );
+
+        set_callback_context(20_000_000_000, PromiseResult::Failed);
+        contract.on_withdraw_resolved(accounts(0), farm_id, U128(100));
+        assert_eq!(
+            emitted_events(),
+            vec![FarmEvent::WithdrawReverted {
+                farm_id,
+                account_id: accounts(0),
+                amount: U128(100),
+            }]
+        );
+    }
+
+    fn unwrap_value(result: PromiseOrValue<U128>) -> u128 {
+        match result {
+            PromiseOrValue::Value(v) => v.0,
+            PromiseOrValue::Promise(_) => panic!("Expected a value"),
+        }
+    }
+
+    #[test]
+    fn test_parse_transfer_message() {
+        assert_eq!(
+            "STAKE:3".parse::<TransferMessage>().unwrap(),
+            TransferMessage::Stake {
+                farm_id: 3,
+                lock_until_sec: None,
+                lock_tier: None,
+                beneficiary: None,
+            }
+        );
+        assert_eq!(
+            "ADD_REWARD:7".parse::<TransferMessage>().unwrap(),
+            TransferMessage::AddReward { farm_id: 7 }
+        );
+        assert_eq!(
+            r#"{"action":"stake","farm_id":3,"lock_until_sec":100,"beneficiary":"bob"}"#
+                .parse::<TransferMessage>()
+                .unwrap(),
+            TransferMessage::Stake {
+                farm_id: 3,
+                lock_until_sec: Some(100),
+                lock_tier: None,
+                beneficiary: Some(accounts(1)),
+            }
+        );
+        assert_eq!(
+            r#"{"action":"add_reward","farm_id":1}"#.parse::<TransferMessage>().unwrap(),
+            TransferMessage::AddReward { farm_id: 1 }
+        );
+
+        for msg in [
+            "",
+            "STAKE",
+            "STAKE:abc",
+            "STAKE:-1",
+            "UNSTAKE:0",
+            "{",
+            r#"{"action":"stake"}"#,
+            r#"{"action":"burn","farm_id":0}"#,
+            r#"{"action":"stake","farm_id":0,"beneficiary":"Not Valid"}"#,
+            r#"{"action":"stake","farm_id":0,"lock_until":4000000000}"#,
+            r#"{"action":"add_reward","farm_id":0,"amount":"1"}"#,
+        ] {
+            assert!(msg.parse::<TransferMessage>().is_err(), "{:?} should not parse", msg);
+        }
+    }
+
+    #[test]
+    fn test_ft_on_transfer_invalid_msg_refunds() {
+        let mut context = get_context(accounts(0), 0, 10_000_000_000_000_000_000_000_000);
+        testing_env!(context.build());
+        let mut contract = ChildFarmingContract::new(accounts(0));
+        contract.storage_deposit(None, None);
+        let farm_id = contract.create_farm(default_farm_input());
+
+        context = get_context("staking.token".parse().unwrap(), 0, 0);
+        testing_env!(context.build());
+        for msg in ["STAKE:abc", "nonsense", r#"{"action":"stake","farm":0}"#] {
+            let refund = contract.ft_on_transfer(accounts(0), U128(500), msg.to_string());
+            assert_eq!(unwrap_value(refund), 500);
+        }
+        assert!(contract.internal_get_stake(&(accounts(0), farm_id)).is_none());
+        assert_eq!(contract.internal_get_farm(farm_id).unwrap().total_staked, 0);
+    }
+
+    #[test]
+    fn test_ft_on_transfer_misspelled_field_refunds() {
+        let mut context = get_context(accounts(0), 0, 10_000_000_000_000_000_000_000_000);
+        testing_env!(context.build());
+        let mut contract = ChildFarmingContract::new(accounts(0));
+        contract.storage_deposit(None, None);
+        let farm_id = contract.create_farm(default_farm_input());
+
+        // `lock_until` instead of `lock_until_sec` must not stake without the lock.
+        context = get_context("staking.token".parse().unwrap(), 0, 0);
+        testing_env!(context.build());
+        let msg = format!(r#"{{"action":"stake","farm_id":{},"lock_until":4000000000}}"#, farm_id);
+        let refund = contract.ft_on_transfer(accounts(0), U128(500), msg);
+        assert_eq!(unwrap_value(refund), 500);
+        assert!(contract.internal_get_stake(&(accounts(0), farm_id)).is_none());
+        assert_eq!(contract.internal_get_farm(farm_id).unwrap().total_staked, 0);
+    }
+
+    #[test]
+    fn test_json_stake_for_beneficiary() {
+        let mut context = get_context(accounts(0), 0, 10_000_000_000_000_000_000_000_000);
+        testing_env!(context.build());
+        let mut contract = ChildFarmingContract::new(accounts(0));
+        contract.storage_deposit(None, None);
+        let farm_id = contract.create_farm(FarmInput {
+            lockup_period_sec: 10,
+            ..default_farm_input()
+        });
+        context = get_context(accounts(1), 0, 1_000_000_000_000_000_000_000_000);
+        testing_env!(context.build());
+        contract.storage_deposit(None, None);
+        let payer_before = contract.storage_balance_of(accounts(0)).unwrap();
+        let beneficiary_before = contract.storage_balance_of(accounts(1)).unwrap();
+
+        // accounts(0) stakes on behalf of accounts(1).
+        context = get_context("staking.token".parse().unwrap(), 0, 0);
+        testing_env!(context.build());
+        let msg = format!(
+            r#"{{"action":"stake","farm_id":{},"beneficiary":"{}"}}"#,
+            farm_id,
+            accounts(1)
+        );
+        let refund = contract.ft_on_transfer(accounts(0), U128(500), msg);
+        assert_eq!(unwrap_value(refund), 0);
+
+        assert!(contract.internal_get_stake(&(accounts(0), farm_id)).is_none());
+        let stake_info = contract.internal_get_stake(&(accounts(1), farm_id)).unwrap();
+        assert_eq!(stake_info.amount, 500);
+        assert_eq!(
+            stake_info.positions,
+            vec![LockPosition {
+                amount: 500,
+                start: 0,
+                unlock_at: 10_000_000_000,
+                multiplier_bps: BASE_MULTIPLIER_BPS,
+            }]
+        );
+
+        // The sender pays for the new stake and its checkpoints; the beneficiary's
+        // available balance is untouched.
+        let payer_after = contract.storage_balance_of(accounts(0)).unwrap();
+        let beneficiary_after = contract.storage_balance_of(accounts(1)).unwrap();
+        let history_cost = contract.history_storage_usage as u128 * env::storage_byte_cost().as_yoctonear();
+        assert!(history_cost > 0);
+        assert_eq!(beneficiary_after.available, beneficiary_before.available);
+        assert_eq!(
+            beneficiary_after.total.as_yoctonear() - beneficiary_before.total.as_yoctonear() + history_cost,
+            payer_before.total.as_yoctonear() - payer_after.total.as_yoctonear()
+        );
+        assert_eq!(
+            emitted_events(),
+            vec![FarmEvent::Staked {
+                farm_id,
+                account_id: accounts(1),
+                amount: U128(500),
+            }]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the beneficiary can lock its own stake")]
+    fn test_json_stake_for_beneficiary_cannot_lock() {
+        let mut context = get_context(accounts(0), 0, 10_000_000_000_000_000_000_000_000);
+        testing_env!(context.build());
+        let mut contract = ChildFarmingContract::new(accounts(0));
+        contract.storage_deposit(None, None);
+        let farm_id = contract.create_farm(default_farm_input());
+        context = get_context(accounts(1), 0, 1_000_000_000_000_000_000_000_000);
+        testing_env!(context.build());
+        contract.storage_deposit(None, None);
+
+        // A far-future lock would take up one of accounts(1)'s lock positions for good.
+        context = get_context("staking.token".parse().unwrap(), 0, 0);
+        testing_env!(context.build());
+        let msg = format!(
+            r#"{{"action":"stake","farm_id":{},"lock_until_sec":4000000000,"beneficiary":"{}"}}"#,
+            farm_id,
+            accounts(1)
+        );
+        contract.ft_on_transfer(accounts(0), U128(1), msg);
+    }
+
+    #[test]
+    #[should_panic(expected = "Insufficient storage. Need")]
+    fn test_json_stake_for_beneficiary_charges_sender() {
+        let mut context = get_context(accounts(0), 0, 10_000_000_000_000_000_000_000_000);
+        testing_env!(context.build());
+        let mut contract = ChildFarmingContract::new(accounts(0));
+        contract.storage_deposit(None, None);
+        let farm_id = contract.create_farm(default_farm_input());
+        context = get_context(accounts(1), 0, 1_000_000_000_000_000_000_000_000);
+        testing_env!(context.build());
+        contract.storage_deposit(None, None);
+        // accounts(2) is registered, but its deposit does not cover a stake.
+        let min_balance = contract.storage_balance_bounds().min;
+        context = get_context(accounts(2), 0, min_balance.as_yoctonear());
+        testing_env!(context.build());
+        contract.storage_deposit(None, None);
+
+        context = get_context("staking.token".parse().unwrap(), 0, 0);
+        testing_env!(context.build());
+        let msg = format!(r#"{{"action":"stake","farm_id":{},"beneficiary":"{}"}}"#, farm_id, accounts(1));
+        contract.ft_on_transfer(accounts(2), U128(1), msg);
+    }
+
+    fn v1_farm() -> FarmParamsV1 {
+        FarmParamsV1 {
+            staking_token: "staking.token".parse().unwrap(),
+            reward_tokens: vec!["reward.token".parse().unwrap()],
+            reward_per_session: vec![100],
+            session_interval: 10_000_000_000,
+            start_time: 0,
+            last_distribution: 0,
+            total_staked: 100,
+            reward_per_share: vec![0],
+            lockup_period: 0,
+            remaining_reward: vec![1_000],
+            status: FarmStatus::Active,
+        }
+    }
+
+    #[test]
+    fn test_versioned_farm_params_reads_v1_blob() {
+        let blob = near_sdk::borsh::to_vec(&VersionedFarmParams::V1(v1_farm())).unwrap();
+        // The first byte is the layout tag, followed by the untagged V1 bytes.
+        assert_eq!(blob[0], 0);
+        assert_eq!(blob[1..], near_sdk::borsh::to_vec(&v1_farm()).unwrap()[..]);
+
+        let farm = match VersionedFarmParams::try_from_slice(&blob).unwrap() {
+            VersionedFarmParams::V1(farm) => farm.into_current(accounts(0)),
+            VersionedFarmParams::V2(_) => panic!("Expected a V1 farm"),
+        };
+        assert_eq!(farm.staking_token, "staking.token");
+        assert_eq!(farm.reward_per_session, vec![100]);
+        assert_eq!(farm.remaining_reward, vec![1_000]);
+        assert_eq!(farm.farm_creator, accounts(0));
+    }
+
+    #[test]
+    fn test_migrate_from_v1_state() {
+        let mut context = get_context(accounts(0), 0, 0);
+        testing_env!(context.build());
+        let mut old = ChildFarmingContractV1 {
+            farms: UnorderedMap::new(b"farms".to_vec()),
+            stakes: UnorderedMap::new(b"stakes".to_vec()),
+            farm_count: 1,
+            storage_deposits: UnorderedMap::new(b"storage_deposits".to_vec()),
+            admin: accounts(0),
+        };
+        old.farms.insert(&0, &v1_farm());
+        old.stakes.insert(
+            &(accounts(1), 0),
+            &StakeInfoV1 {
+                amount: 100,
+                lockup_end: 0,
+                reward_debt: vec![0],
+                accrued_rewards: vec![7],
+            },
+        );
+        old.storage_deposits
+            .insert(&accounts(1), &10_000_000_000_000_000_000_000_000);
+        old.storage_deposits
+            .insert(&accounts(2), &10_000_000_000_000_000_000_000_000);
+        old.storage_deposits
+            .insert(&accounts(0), &10_000_000_000_000_000_000_000_000);
+        env::state_write(&old);
+
+        let mut contract = ChildFarmingContract::migrate();
+        assert_eq!(contract.state_version, CURRENT_STATE_VERSION);
+        assert_eq!(contract.farm_count, 1);
+        assert_eq!(contract.get_admin(), accounts(0));
+
+        let farm = contract.get_farm(0).unwrap();
+        assert_eq!(farm.farm_creator, accounts(0));
+        assert_eq!(farm.total_staked.0, 100);
+        let stake_info = contract.internal_get_stake(&(accounts(1), 0)).unwrap();
+        assert_eq!(stake_info.amount, 100);
+        assert_eq!(stake_info.accrued_rewards, vec![7]);
+        assert_eq!(
+            stake_info.positions,
+            vec![LockPosition {
+                amount: 100,
+                start: 0,
+                unlock_at: 0,
+                multiplier_bps: BASE_MULTIPLIER_BPS,
+            }]
+        );
+        assert_eq!(
+            contract.storage_balance_of(accounts(1)).unwrap().total.as_yoctonear(),
+            10_000_000_000_000_000_000_000_000
+        );
+        // The migrated stake is billed to its owner. Anyone could create V1 farms, so
+        // their bytes are not billed to the admin.
+        let byte_cost = env::storage_byte_cost().as_yoctonear();
+        let used_bytes = contract.storage_deposits.get(&accounts(1)).unwrap().used_bytes;
+        assert!(used_bytes > 0);
+        assert_eq!(
+            contract.storage_balance_of(accounts(1)).unwrap().available.as_yoctonear(),
+            10_000_000_000_000_000_000_000_000
+                - (contract.account_storage_usage + used_bytes) as u128 * byte_cost
+        );
+        assert_eq!(
+            contract.storage_balance_of(accounts(2)).unwrap().available.as_yoctonear(),
+            10_000_000_000_000_000_000_000_000 - contract.account_storage_usage as u128 * byte_cost
+        );
+        assert_eq!(contract.storage_deposits.get(&accounts(0)).unwrap().used_bytes, 0);
+        let listed = contract.list_stakes_by_user(accounts(1), 0, 10);
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].amount.0, 100);
+        assert!(contract.farm_stakers.get(&0).unwrap().contains(&accounts(1)));
+        assert_eq!(contract.get_top_stakers(0, 1)[0].account_id, accounts(1));
+        assert_eq!(contract.stake_at(accounts(1), 0, 0).0, 100);
+        assert_eq!(contract.total_staked_at(0, 0).0, 100);
+
+        // The migrated farm keeps working: one session later a top-up settles rewards.
+        context = get_context("staking.token".parse().unwrap(), 10_000_000_000, 0);
+        testing_env!(context.build());
+        contract.ft_on_transfer(accounts(1), U128(100), "STAKE:0".to_string());
+        let stake_info = contract.internal_get_stake(&(accounts(1), 0)).unwrap();
+        assert_eq!(stake_info.amount, 200);
+        assert_eq!(stake_info.accrued_rewards, vec![107]);
+        assert!(matches!(
+            contract.farms.get(&0).unwrap(),
+            VersionedFarmParams::V2(_)
+        ));
+
+        // Migrating an already current state is a no-op.
+        env::state_write(&contract);
+        let contract = ChildFarmingContract::migrate();
+        assert_eq!(contract.internal_get_farm(0).unwrap().total_staked, 200);
+    }
+
+    #[test]
+    fn test_upgrade_by_master_or_admin() {
+        let child: AccountId = "farm.master.near".parse().unwrap();
+        let mut context = get_context(accounts(0), 0, 0);
+        context.current_account_id(child.clone());
+        testing_env!(context.build());
+        let mut contract = ChildFarmingContract::new(accounts(0));
+
+        // The admin may push code directly.
+        let code = Base64VecU8(vec![0u8; 8]);
+        let _ = contract.upgrade(code.clone(), None);
+
+        // So may the master, which the child was deployed under.
+        context.predecessor_account_id("master.near".parse().unwrap());
+        testing_env!(context.build());
+        let _ = contract.upgrade(code, Some(Base64VecU8(b"{}".to_vec())));
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the master contract or admin can upgrade this contract")]
+    fn test_upgrade_unauthorized() {
+        let mut context = get_context(accounts(0), 0, 0);
+        context.current_account_id("farm.master.near".parse().unwrap());
+        testing_env!(context.build());
+        let mut contract = ChildFarmingContract::new(accounts(0));
+
+        // A sibling sub-account of the master is not the master.
+        context.predecessor_account_id("other.master.near".parse().unwrap());
+        testing_env!(context.build());
+        contract.upgrade(Base64VecU8(vec![0u8; 8]), None);
+    }
+
+    /// Admin accounts(0) creates a farm funded with 1_000 reward tokens and
+    /// accounts(1) stakes 100 in it, all at t=0.
+    fn setup_staked_farm(lockup_period_sec: u64) -> (ChildFarmingContract, u64) {
+        setup_staked_farm_with(FarmInput {
+            lockup_period_sec,
+            ..default_farm_input()
+        })
+    }
+
+    /// Like `setup_staked_farm`, for a farm created from `input`.
+    fn setup_staked_farm_with(input: FarmInput) -> (ChildFarmingContract, u64) {
+        let (mut contract, farm_id) = setup_funded_farm(input);
+        stake_at(&mut contract, farm_id, 100, 0);
+        (contract, farm_id)
+    }
+
+    /// Like `setup_staked_farm_with`, without the stake.
+    fn setup_funded_farm(input: FarmInput) -> (ChildFarmingContract, u64) {
+        let context = get_context(accounts(0), 0, 10_000_000_000_000_000_000_000_000);
+        testing_env!(context.build());
+        let mut contract = ChildFarmingContract::new(accounts(0));
+        contract.storage_deposit(None, None);
+        let farm_id = contract.create_farm(input);
+        let context = get_context(accounts(1), 0, 1_000_000_000_000_000_000_000_000);
+        testing_env!(context.build());
+        contract.storage_deposit(None, None);
+
+        let context = get_context("reward.token".parse().unwrap(), 0, 0);
+        testing_env!(context.build());
+        contract.ft_on_transfer(accounts(0), U128(1_000), format!("ADD_REWARD:{}", farm_id));
+        (contract, farm_id)
+    }
+
+    /// Stakes `amount` for accounts(1) at `time_sec`.
+    fn stake_at(contract: &mut ChildFarmingContract, farm_id: u64, amount: u128, time_sec: u64) {
+        let context = get_context("staking.token".parse().unwrap(), time_sec * 1_000_000_000, 0);
+        testing_env!(context.build());
+        contract.ft_on_transfer(accounts(1), U128(amount), format!("STAKE:{}", farm_id));
+    }
+
+    #[test]
+    fn test_pause_refunds_deposits() {
+        let (mut contract, farm_id) = setup_staked_farm(0);
+        let context = get_context(accounts(0), 0, 1);
+        testing_env!(context.build());
+        contract.pause(None, None);
+        assert_eq!(contract.get_paused_actions(None), PausableAction::ALL.to_vec());
+        assert_eq!(
+            emitted_events(),
+            vec![FarmEvent::Paused {
+                farm_id: None,
+                actions: PausableAction::ALL.to_vec(),
+            }]
+        );
+
+        let context = get_context("staking.token".parse().unwrap(), 0, 0);
+        testing_env!(context.build());
+        let refund = contract.ft_on_transfer(accounts(1), U128(50), format!("STAKE:{}", farm_id));
+        assert_eq!(unwrap_value(refund), 50);
+        let context = get_context("reward.token".parse().unwrap(), 0, 0);
+        testing_env!(context.build());
+        let refund = contract.ft_on_transfer(accounts(0), U128(50), format!("ADD_REWARD:{}", farm_id));
+        assert_eq!(unwrap_value(refund), 50);
+
+        let farm = contract.internal_get_farm(farm_id).unwrap();
+        assert_eq!(farm.total_staked, 100);
+        assert_eq!(farm.remaining_reward, vec![1_000]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Claim is paused for farm 0")]
+    fn test_pause_farm_claim() {
+        let (mut contract, farm_id) = setup_staked_farm(0);
+        let context = get_context(accounts(0), 0, 1);
+        testing_env!(context.build());
+        contract.pause(Some(farm_id), Some(vec![PausableAction::Claim]));
+        assert_eq!(
+            contract.list_paused_farms(),
+            vec![(farm_id, vec![PausableAction::Claim])]
+        );
+
+        // Other actions of the farm keep working.
+        let context = get_context(accounts(1), 10_000_000_000, 1);
+        testing_env!(context.build());
+        contract.withdraw(farm_id, U128(10));
+        contract.claim_rewards(farm_id);
+    }
+
+    #[test]
+    fn test_unpause_farm_keeps_global_pause() {
+        let (mut contract, farm_id) = setup_staked_farm(0);
+        let context = get_context(accounts(0), 0, 1);
+        testing_env!(context.build());
+        contract.pause(None, Some(vec![PausableAction::Withdraw]));
+        contract.pause(Some(farm_id), Some(vec![PausableAction::Withdraw, PausableAction::Stake]));
+        contract.unpause(Some(farm_id), None);
+
+        assert!(contract.list_paused_farms().is_empty());
+        assert_eq!(
+            contract.get_paused_actions(Some(farm_id)),
+            vec![PausableAction::Withdraw]
+        );
+
+        contract.unpause(None, None);
+        assert!(contract.get_paused_actions(Some(farm_id)).is_empty());
+    }
+
+    #[test]
+    fn test_pause_by_master() {
+        let mut context = get_context(accounts(0), 0, 0);
+        context.current_account_id("farm.master.near".parse().unwrap());
+        testing_env!(context.build());
+        let mut contract = ChildFarmingContract::new(accounts(0));
+
+        context
+            .predecessor_account_id("master.near".parse().unwrap())
+            .attached_deposit(NearToken::from_yoctonear(1));
+        testing_env!(context.build());
+        contract.pause(None, None);
+        assert_eq!(contract.get_paused_actions(None).len(), 4);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the master contract or admin can change the pause state")]
+    fn test_pause_not_admin() {
+        let (mut contract, _) = setup_staked_farm(0);
+        let context = get_context(accounts(1), 0, 1);
+        testing_env!(context.build());
+        contract.pause(None, None);
+    }
+
+    #[test]
+    fn test_emergency_withdraw_while_paused() {
+        let (mut contract, farm_id) = setup_staked_farm(100);
+        let context = get_context(accounts(0), 0, 1);
+        testing_env!(context.build());
+        contract.set_early_exit_penalty(farm_id, 1_000, PenaltyDestination::Burn);
+
+        // Settle one session of rewards into accrued_rewards with a small top-up.
+        let context = get_context("staking.token".parse().unwrap(), 10_000_000_000, 0);
+        testing_env!(context.build());
+        contract.ft_on_transfer(accounts(1), U128(100), format!("STAKE:{}", farm_id));
+
+        let context = get_context(accounts(0), 10_000_000_000, 1);
+        testing_env!(context.build());
+        contract.pause(Some(farm_id), None);
+
+        // Still inside the lockup, but the pause waives the penalty.
+        let context = get_context(accounts(1), 20_000_000_000, 1);
+        testing_env!(context.build());
+        contract.emergency_withdraw(farm_id);
+
+        let farm = contract.internal_get_farm(farm_id).unwrap();
+        assert_eq!(farm.total_staked, 0);
+        // The accrued 100 goes back into the pool; the second session was never distributed.
+        assert_eq!(farm.remaining_reward, vec![1_000]);
+        assert_eq!(farm.burned_penalty, 0);
+        assert!(contract.internal_get_stake(&(accounts(1), farm_id)).is_none());
+        assert_eq!(
+            emitted_events(),
+            vec![FarmEvent::EmergencyWithdrawn {
+                farm_id,
+                account_id: accounts(1),
+                amount: U128(200),
+                penalty: U128(0),
+                forfeited_rewards: vec![U128(100)],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_emergency_withdraw_penalty_while_add_reward_paused() {
+        let (mut contract, farm_id) = setup_staked_farm(100);
+        let context = get_context(accounts(0), 0, 1);
+        testing_env!(context.build());
+        contract.set_early_exit_penalty(farm_id, 2_500, PenaltyDestination::Burn);
+        contract.pause(Some(farm_id), Some(vec![PausableAction::AddReward]));
+        contract.pause(None, Some(vec![PausableAction::Stake]));
+
+        // Stakers can still leave as usual, so the penalty applies.
+        let context = get_context(accounts(1), 5_000_000_000, 1);
+        testing_env!(context.build());
+        contract.emergency_withdraw(farm_id);
+        assert_eq!(contract.internal_get_farm(farm_id).unwrap().burned_penalty, 25);
+    }
+
+    #[test]
+    fn test_emergency_withdraw_burns_penalty_during_lockup() {
+        let (mut contract, farm_id) = setup_staked_farm(100);
+        let context = get_context(accounts(0), 0, 1);
+        testing_env!(context.build());
+        contract.set_early_exit_penalty(farm_id, 2_500, PenaltyDestination::Burn);
+
+        let context = get_context(accounts(1), 5_000_000_000, 1);
+        testing_env!(context.build());
+        contract.emergency_withdraw(farm_id);
+
+        let farm = contract.internal_get_farm(farm_id).unwrap();
+        assert_eq!(farm.burned_penalty, 25);
+        assert_eq!(farm.total_staked, 0);
+        assert_eq!(
+            emitted_events(),
+            vec![FarmEvent::EmergencyWithdrawn {
+                farm_id,
+                account_id: accounts(1),
+                amount: U128(75),
+                penalty: U128(25),
+                forfeited_rewards: vec![U128(0)],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_emergency_withdraw_no_penalty_after_lockup() {
+        let (mut contract, farm_id) = setup_staked_farm(10);
+        let context = get_context(accounts(0), 0, 1);
+        testing_env!(context.build());
+        contract.set_early_exit_penalty(farm_id, 2_500, PenaltyDestination::Burn);
+
+        let context = get_context(accounts(1), 10_000_000_000, 1);
+        testing_env!(context.build());
+        contract.emergency_withdraw(farm_id);
+        assert_eq!(contract.internal_get_farm(farm_id).unwrap().burned_penalty, 0);
+    }
+
+    #[test]
+    fn test_emergency_withdraw_redistributes_penalty() {
+        let context = get_context(accounts(0), 0, 10_000_000_000_000_000_000_000_000);
+        testing_env!(context.build());
+        let mut contract = ChildFarmingContract::new(accounts(0));
+        contract.storage_deposit(None, None);
+        // The staking token doubles as the reward token.
+        let farm_id = contract.create_farm(FarmInput {
+            reward_tokens: vec!["staking.token".parse().unwrap()],
+            lockup_period_sec: 100,
+            ..default_farm_input()
+        });
+        let context = get_context(accounts(0), 0, 1);
+        testing_env!(context.build());
+        contract.set_early_exit_penalty(farm_id, 1_000, PenaltyDestination::Redistribute);
+        for account_id in [accounts(1), accounts(2)].iter() {
+            let context = get_context(account_id.clone(), 0, 1_000_000_000_000_000_000_000_000);
+            testing_env!(context.build());
+            contract.storage_deposit(None, None);
+            let context = get_context("staking.token".parse().unwrap(), 0, 0);
+            testing_env!(context.build());
+            contract.ft_on_transfer(account_id.clone(), U128(1_000), format!("STAKE:{}", farm_id));
+        }
+
+        let context = get_context(accounts(1), 0, 1);
+        testing_env!(context.build());
+        contract.emergency_withdraw(farm_id);
+
+        // accounts(2) is the only staker left and earns the whole 100 penalty.
+        let stake_info = contract.get_stake_info(accounts(2), farm_id).unwrap();
+        assert_eq!(stake_info.accrued_rewards, vec![U128(100)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Redistribution requires the staking token to be a reward token")]
+    fn test_redistribute_needs_staking_reward_token() {
+        let (mut contract, farm_id) = setup_staked_farm(0);
+        let context = get_context(accounts(0), 0, 1);
+        testing_env!(context.build());
+        contract.set_early_exit_penalty(farm_id, 1_000, PenaltyDestination::Redistribute);
+    }
+
+    #[test]
+    #[should_panic(expected = "Penalty cannot exceed 10000 bps")]
+    fn test_penalty_above_max() {
+        let (mut contract, farm_id) = setup_staked_farm(0);
+        let context = get_context(accounts(0), 0, 1);
+        testing_env!(context.build());
+        contract.set_early_exit_penalty(farm_id, 10_001, PenaltyDestination::Burn);
+    }
+
+    #[test]
+    fn test_end_farm_and_reclaim() {
+        let (mut contract, farm_id) = setup_staked_farm(0);
+        let context = get_context("reward.token".parse().unwrap(), 0, 0);
+        testing_env!(context.build());
+        contract.ft_on_transfer(accounts(2), U128(300), format!("ADD_REWARD:{}", farm_id));
+
+        // Two sessions (200) are distributed before the farm ends.
+        let context = get_context(accounts(0), 25_000_000_000, 1);
+        testing_env!(context.build());
+        contract.end_farm(farm_id);
+        let farm = contract.internal_get_farm(farm_id).unwrap();
+        assert_eq!(farm.status, FarmStatus::Ended);
+        assert_eq!(farm.remaining_reward, vec![1_100]);
+        assert_eq!(emitted_events(), vec![FarmEvent::FarmEnded { farm_id }]);
+
+        let context = get_context(accounts(0), 30_000_000_000, 1);
+        testing_env!(context.build());
+        contract.reclaim_remaining_rewards(farm_id);
+        let farm = contract.internal_get_farm(farm_id).unwrap();
+        assert_eq!(farm.remaining_reward, vec![0]);
+        assert!(contract.get_reward_funders(farm_id)[0].is_empty());
+        // The latest deposit is refunded in full, the older one only what was left of it.
+        assert_eq!(
+            emitted_events(),
+            vec![
+                FarmEvent::RewardsReclaimed {
+                    farm_id,
+                    account_id: accounts(2),
+                    reward_token: "reward.token".parse().unwrap(),
+                    amount: U128(300),
+                },
+                FarmEvent::RewardsReclaimed {
+                    farm_id,
+                    account_id: accounts(0),
+                    reward_token: "reward.token".parse().unwrap(),
+                    amount: U128(800),
+                },
+            ]
+        );
+
+        // Stakers keep what was distributed before the end.
+        let stake_info = contract.get_stake_info(accounts(1), farm_id).unwrap();
+        assert_eq!(stake_info.accrued_rewards, vec![U128(200)]);
+    }
+
+    #[test]
+    fn test_split_remaining_reward() {
+        let creator = accounts(0);
+        let fundings = vec![(accounts(1), 100), (accounts(2), 50), (accounts(1), 30)];
+        assert_eq!(
+            ChildFarmingContract::split_remaining_reward(60, &fundings, &creator),
+            vec![(accounts(1), 30), (accounts(2), 30)]
+        );
+        // More than the remembered deposits: the rest goes to the creator.
+        assert_eq!(
+            ChildFarmingContract::split_remaining_reward(500, &fundings, &creator),
+            vec![(accounts(1), 130), (accounts(2), 50), (accounts(0), 320)]
+        );
+        assert!(ChildFarmingContract::split_remaining_reward(0, &fundings, &creator).is_empty());
+    }
+
+    #[test]
+    fn test_reclaim_transfer_failure_restores_pool() {
+        let (mut contract, farm_id) = setup_staked_farm(0);
+        let context = get_context(accounts(0), 25_000_000_000, 1);
+        testing_env!(context.build());
+        contract.end_farm(farm_id);
+        contract.reclaim_remaining_rewards(farm_id);
+
+        set_callback_context(30_000_000_000, PromiseResult::Failed);
+        let sent = contract.on_reclaim_resolved(
+            farm_id,
+            "reward.token".parse().unwrap(),
+            accounts(0),
+            U128(800),
+        );
+        assert_eq!(sent, U128(0));
+        let farm = contract.internal_get_farm(farm_id).unwrap();
+        assert_eq!(farm.remaining_reward, vec![800]);
+        assert_eq!(
+            contract.get_reward_funders(farm_id),
+            vec![vec![(accounts(0), U128(800))]]
+        );
+        assert_eq!(
+            emitted_events(),
+            vec![FarmEvent::ReclaimReverted {
+                farm_id,
+                account_id: accounts(0),
+                reward_token: "reward.token".parse().unwrap(),
+                amount: U128(800),
+            }]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Rewards can only be reclaimed after the farm has ended or been cancelled")]
+    fn test_reclaim_running_farm() {
+        let (mut contract, farm_id) = setup_staked_farm(0);
+        let context = get_context(accounts(0), 0, 1);
+        testing_env!(context.build());
+        contract.reclaim_remaining_rewards(farm_id);
+    }
+
+    #[test]
+    #[should_panic(expected = "Farm is ended, rewards not accepted")]
+    fn test_add_reward_to_ended_farm() {
+        let (mut contract, farm_id) = setup_staked_farm(0);
+        let context = get_context(accounts(0), 0, 1);
+        testing_env!(context.build());
+        contract.end_farm(farm_id);
+
+        let context = get_context("reward.token".parse().unwrap(), 0, 0);
+        testing_env!(context.build());
+        contract.ft_on_transfer(accounts(0), U128(100), format!("ADD_REWARD:{}", farm_id));
+    }
+
+    #[test]
+    #[should_panic(expected = "Farm is ended, rewards not accepted")]
+    fn test_add_reward_after_end_time() {
+        let context = get_context(accounts(0), 0, 10_000_000_000_000_000_000_000_000);
+        testing_env!(context.build());
+        let mut contract = ChildFarmingContract::new(accounts(0));
+        contract.storage_deposit(None, None);
+        let farm_id = contract.create_farm(FarmInput {
+            end_at_sec: Some(100),
+            ..default_farm_input()
+        });
+
+        // Nobody touched the farm since it ended, so its stored status is still active.
+        let context = get_context("reward.token".parse().unwrap(), 150_000_000_000, 0);
+        testing_env!(context.build());
+        contract.ft_on_transfer(accounts(0), U128(100), format!("ADD_REWARD:{}", farm_id));
+    }
+
+    #[test]
+    fn test_cancel_scheduled_farm() {
+        let context = get_context(accounts(0), 0, 10_000_000_000_000_000_000_000_000);
+        testing_env!(context.build());
+        let mut contract = ChildFarmingContract::new(accounts(0));
+        contract.storage_deposit(None, None);
+        let farm_id = contract.create_farm(FarmInput {
+            lockup_period_sec: 1_000,
+            start_at_sec: 100,
+            ..default_farm_input()
+        });
+        assert_eq!(contract.get_farm(farm_id).unwrap().status, FarmStatus::Scheduled);
+        let context = get_context("staking.token".parse().unwrap(), 0, 0);
+        testing_env!(context.build());
+        contract.ft_on_transfer(accounts(0), U128(100), format!("STAKE:{}", farm_id));
+
+        let context = get_context(accounts(0), 50_000_000_000, 1);
+        testing_env!(context.build());
+        contract.cancel_farm(farm_id);
+        assert_eq!(emitted_events(), vec![FarmEvent::FarmCancelled { farm_id }]);
+        assert_eq!(contract.get_farm(farm_id).unwrap().status, FarmStatus::Cancelled);
+
+        // The lockup no longer applies.
+        contract.withdraw(farm_id, U128(100));
+        assert_eq!(contract.internal_get_farm(farm_id).unwrap().total_staked, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only a farm that has not started can be cancelled")]
+    fn test_cancel_started_farm() {
+        let (mut contract, farm_id) = setup_staked_farm(0);
+        let context = get_context(accounts(0), 0, 1);
+        testing_env!(context.build());
+        contract.cancel_farm(farm_id);
+    }
+
+    #[test]
+    fn test_pause_and_resume_farm() {
+        let (mut contract, farm_id) = setup_staked_farm(0);
+        let context = get_context(accounts(0), 10_000_000_000, 1);
+        testing_env!(context.build());
+        contract.pause_farm(farm_id);
+        assert_eq!(contract.get_farm(farm_id).unwrap().status, FarmStatus::Paused);
+
+        // Nothing is emitted while the farm is paused.
+        let context = get_context(accounts(1), 50_000_000_000, 1);
+        testing_env!(context.build());
+        assert_eq!(
+            contract.get_stake_info(accounts(1), farm_id).unwrap().accrued_rewards,
+            vec![U128(100)]
+        );
+
+        let context = get_context(accounts(0), 50_000_000_000, 1);
+        testing_env!(context.build());
+        contract.resume_farm(farm_id);
+        assert_eq!(
+            emitted_events(),
+            vec![FarmEvent::FarmResumed { farm_id }]
+        );
+
+        let context = get_context(accounts(1), 60_000_000_000, 1);
+        testing_env!(context.build());
+        assert_eq!(
+            contract.get_stake_info(accounts(1), farm_id).unwrap().accrued_rewards,
+            vec![U128(200)]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Farm is paused, staking not allowed")]
+    fn test_stake_on_paused_farm() {
+        let (mut contract, farm_id) = setup_staked_farm(0);
+        let context = get_context(accounts(0), 0, 1);
+        testing_env!(context.build());
+        contract.pause_farm(farm_id);
+
+        let context = get_context("staking.token".parse().unwrap(), 0, 0);
+        testing_env!(context.build());
+        contract.ft_on_transfer(accounts(1), U128(100), format!("STAKE:{}", farm_id));
+    }
+
+    #[test]
+    fn test_emission_schedule_halving() {
+        let (mut contract, farm_id) = setup_staked_farm_with(FarmInput {
+            end_at_sec: Some(60),
+            emission_schedule: vec![EmissionStep {
+                from_sec: 30,
+                reward_per_session: vec![U128(50)],
+            }],
+            ..default_farm_input()
+        });
+        let farm = contract.get_farm(farm_id).unwrap();
+        assert_eq!(farm.end_at_sec, Some(60));
+        assert_eq!(farm.emission_schedule.len(), 1);
+
+        // Three sessions at 100, then three at 50.
+        let context = get_context(accounts(1), 65_000_000_000, 1);
+        testing_env!(context.build());
+        let stake_info = contract.get_stake_info(accounts(1), farm_id).unwrap();
+        assert_eq!(stake_info.accrued_rewards, vec![U128(450)]);
+        assert_eq!(contract.get_farm(farm_id).unwrap().status, FarmStatus::Ended);
+
+        contract.claim_rewards(farm_id);
+        let farm = contract.internal_get_farm(farm_id).unwrap();
+        assert_eq!(farm.status, FarmStatus::Ended);
+        assert_eq!(farm.remaining_reward, vec![550]);
+        assert_eq!(farm.reward_per_session, vec![50]);
+        assert!(farm.emission_schedule.is_empty());
+        assert_eq!(emitted_events()[0], FarmEvent::FarmEnded { farm_id });
+    }
+
+    #[test]
+    fn test_emission_step_inside_session() {
+        let (mut contract, farm_id) = setup_staked_farm_with(FarmInput {
+            emission_schedule: vec![EmissionStep {
+                from_sec: 35,
+                reward_per_session: vec![U128(50)],
+            }],
+            ..default_farm_input()
+        });
+
+        // The session from 30 to 40 emits half of it at each rate: 300 + 50 + 25.
+        let context = get_context(accounts(1), 45_000_000_000, 1);
+        testing_env!(context.build());
+        assert_eq!(
+            contract.get_stake_info(accounts(1), farm_id).unwrap().accrued_rewards,
+            vec![U128(375)]
+        );
+        contract.claim_rewards(farm_id);
+        let farm = contract.internal_get_farm(farm_id).unwrap();
+        assert_eq!(farm.remaining_reward, vec![625]);
+        assert_eq!(farm.last_distribution, 40_000_000_000);
+    }
+
+    #[test]
+    fn test_end_time_emits_last_partial_session() {
+        let (mut contract, farm_id) = setup_staked_farm_with(FarmInput {
+            end_at_sec: Some(45),
+            ..default_farm_input()
+        });
+        let context = get_context(accounts(1), 100_000_000_000, 1);
+        testing_env!(context.build());
+        assert_eq!(
+            contract.get_stake_info(accounts(1), farm_id).unwrap().accrued_rewards,
+            vec![U128(450)]
+        );
+        contract.claim_rewards(farm_id);
+        let farm = contract.internal_get_farm(farm_id).unwrap();
+        assert_eq!(farm.status, FarmStatus::Ended);
+        assert_eq!(farm.last_distribution, 45_000_000_000);
+    }
+
+    #[test]
+    fn test_end_time_farm_survives_empty_pool() {
+        let (mut contract, farm_id) = setup_staked_farm_with(FarmInput {
+            end_at_sec: Some(1_000),
+            ..default_farm_input()
+        });
+        // The 1_000 funded at t=0 run out after ten sessions, but the farm keeps
+        // running until its end time and can be topped up.
+        let context = get_context(accounts(1), 200_000_000_000, 1);
+        testing_env!(context.build());
+        contract.claim_rewards(farm_id);
+        let farm = contract.internal_get_farm(farm_id).unwrap();
+        assert_eq!(farm.remaining_reward, vec![0]);
+        assert_eq!(farm.status, FarmStatus::Active);
+    }
+
+    #[test]
+    #[should_panic(expected = "Farm is ended, staking not allowed")]
+    fn test_stake_after_end_time() {
+        let (mut contract, farm_id) = setup_staked_farm_with(FarmInput {
+            end_at_sec: Some(60),
+            ..default_farm_input()
+        });
+        let context = get_context("staking.token".parse().unwrap(), 60_000_000_000, 0);
+        testing_env!(context.build());
+        contract.ft_on_transfer(accounts(1), U128(100), format!("STAKE:{}", farm_id));
+    }
+
+    #[test]
+    #[should_panic(expected = "Emission schedule steps must be in increasing order after the start time")]
+    fn test_emission_schedule_out_of_order() {
+        let context = get_context(accounts(0), 0, 10_000_000_000_000_000_000_000_000);
+        testing_env!(context.build());
+        let mut contract = ChildFarmingContract::new(accounts(0));
+        contract.storage_deposit(None, None);
+        contract.create_farm(FarmInput {
+            emission_schedule: vec![
+                EmissionStep {
+                    from_sec: 50,
+                    reward_per_session: vec![U128(50)],
+                },
+                EmissionStep {
+                    from_sec: 40,
+                    reward_per_session: vec![U128(25)],
+                },
+            ],
+            ..default_farm_input()
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "Emission schedule steps must start before the end time")]
+    fn test_emission_step_after_end() {
+        let context = get_context(accounts(0), 0, 10_000_000_000_000_000_000_000_000);
+        testing_env!(context.build());
+        let mut contract = ChildFarmingContract::new(accounts(0));
+        contract.storage_deposit(None, None);
+        contract.create_farm(FarmInput {
+            end_at_sec: Some(40),
+            emission_schedule: vec![EmissionStep {
+                from_sec: 40,
+                reward_per_session: vec![U128(50)],
+            }],
+            ..default_farm_input()
+        });
+    }
+
+    /// Claims for accounts(1) at each of `times_sec` and returns the total claimed.
+    fn claim_at(contract: &mut ChildFarmingContract, farm_id: u64, times_sec: &[u64]) -> u128 {
+        let mut claimed = 0;
+        for time_sec in times_sec {
+            let context = get_context(accounts(1), time_sec * 1_000_000_000, 1);
+            testing_env!(context.build());
+            contract.claim_rewards(farm_id);
+            for event in emitted_events() {
+                if let FarmEvent::RewardsClaimed { amount, .. } = event {
+                    claimed += amount.0;
+                }
+            }
+        }
+        claimed
+    }
+
+    /// Claims every 3 seconds for three 10 second sessions emitting 7 each, so
+    /// the updates do not line up with the sessions.
+    fn assert_three_sessions_paid(distribution_mode: DistributionMode) {
+        let (mut contract, farm_id) = setup_staked_farm_with(FarmInput {
+            reward_per_session: vec![U128(7)],
+            distribution_mode,
+            ..default_farm_input()
+        });
+        let times_sec: Vec<u64> = (1..=10).map(|i| i * 3).collect();
+        assert_eq!(claim_at(&mut contract, farm_id, &times_sec), 21);
+        let farm = contract.internal_get_farm(farm_id).unwrap();
+        assert_eq!(farm.remaining_reward, vec![979]);
+        assert_eq!(farm.emission_carry, vec![0]);
+    }
+
+    #[test]
+    fn test_session_mode_pays_whole_sessions() {
+        assert_three_sessions_paid(DistributionMode::Session);
+    }
+
+    #[test]
+    fn test_continuous_matches_session_over_whole_sessions() {
+        assert_three_sessions_paid(DistributionMode::Continuous);
+    }
+
+    #[test]
+    fn test_continuous_accrues_partial_sessions() {
+        let (mut contract, farm_id) = setup_staked_farm_with(FarmInput {
+            reward_per_session: vec![U128(7)],
+            distribution_mode: DistributionMode::Continuous,
+            ..default_farm_input()
+        });
+        assert_eq!(contract.get_farm(farm_id).unwrap().distribution_mode, DistributionMode::Continuous);
+
+        let context = get_context(accounts(1), 5_000_000_000, 1);
+        testing_env!(context.build());
+        assert_eq!(
+            contract.get_stake_info(accounts(1), farm_id).unwrap().accrued_rewards,
+            vec![U128(3)]
+        );
+
+        // 2.1 and 2.2 are paid as 2 each; the fractions add up to the last token.
+        assert_eq!(claim_at(&mut contract, farm_id, &[3, 6]), 4);
+        assert_eq!(contract.internal_get_farm(farm_id).unwrap().emission_carry, vec![2_000_000_000]);
+        assert_eq!(claim_at(&mut contract, farm_id, &[10]), 3);
+    }
+
+    #[test]
+    fn test_session_mode_ignores_partial_sessions() {
+        let (contract, farm_id) = setup_staked_farm(0);
+        let context = get_context(accounts(1), 9_000_000_000, 1);
+        testing_env!(context.build());
+        assert_eq!(
+            contract.get_stake_info(accounts(1), farm_id).unwrap().accrued_rewards,
+            vec![U128(0)]
+        );
+    }
+
+    #[test]
+    fn test_rounding_dust_stays_in_pool() {
+        let context = get_context(accounts(0), 0, 10_000_000_000_000_000_000_000_000);
+        testing_env!(context.build());
+        let mut contract = ChildFarmingContract::new(accounts(0));
+        contract.storage_deposit(None, None);
+        let farm_id = contract.create_farm(default_farm_input());
+        let context = get_context(accounts(1), 0, 1_000_000_000_000_000_000_000_000);
+        testing_env!(context.build());
+        contract.storage_deposit(None, None);
+        let context = get_context("reward.token".parse().unwrap(), 0, 0);
+        testing_env!(context.build());
+        contract.ft_on_transfer(accounts(0), U128(1_000), format!("ADD_REWARD:{}", farm_id));
+        let context = get_context("staking.token".parse().unwrap(), 0, 0);
+        testing_env!(context.build());
+        contract.ft_on_transfer(accounts(1), U128(3), format!("STAKE:{}", farm_id));
+
+        // 100 over 3 staked tokens credits just under 100, paid out as 99.
+        assert_eq!(claim_at(&mut contract, farm_id, &[10]), 99);
+        let farm = contract.internal_get_farm(farm_id).unwrap();
+        assert_eq!(farm.remaining_reward, vec![901]);
+    }
+
+    #[test]
+    fn test_idle_rewards_return_to_funder() {
+        let (mut contract, farm_id) = setup_funded_farm(default_farm_input());
+        // Nobody is staked for the first three sessions.
+        stake_at(&mut contract, farm_id, 100, 30);
+        assert_eq!(claim_at(&mut contract, farm_id, &[50]), 200);
+        let farm = contract.internal_get_farm(farm_id).unwrap();
+        assert_eq!(farm.undistributed_reward, vec![300]);
+        assert_eq!(farm.remaining_reward, vec![500]);
+
+        let context = get_context(accounts(0), 50_000_000_000, 1);
+        testing_env!(context.build());
+        contract.end_farm(farm_id);
+        contract.reclaim_remaining_rewards(farm_id);
+        assert_eq!(
+            emitted_events().last(),
+            Some(&FarmEvent::RewardsReclaimed {
+                farm_id,
+                account_id: accounts(0),
+                reward_token: "reward.token".parse().unwrap(),
+                amount: U128(800),
+            })
+        );
+    }
+
+    #[test]
+    fn test_idle_rewards_carry_forward() {
+        let (mut contract, farm_id) = setup_funded_farm(FarmInput {
+            idle_reward_policy: IdleRewardPolicy::CarryForward,
+            ..default_farm_input()
+        });
+        stake_at(&mut contract, farm_id, 100, 30);
+        let context = get_context(accounts(1), 40_000_000_000, 1);
+        testing_env!(context.build());
+        assert_eq!(
+            contract.get_stake_info(accounts(1), farm_id).unwrap().accrued_rewards,
+            vec![U128(400)]
+        );
+        assert_eq!(claim_at(&mut contract, farm_id, &[50]), 500);
+        let farm = contract.internal_get_farm(farm_id).unwrap();
+        assert_eq!(farm.undistributed_reward, vec![0]);
+        assert_eq!(farm.remaining_reward, vec![500]);
+    }
+
+    #[test]
+    fn test_unfunded_emission_is_recorded() {
+        let (mut contract, farm_id) = setup_staked_farm_with(FarmInput {
+            end_at_sec: Some(120),
+            ..default_farm_input()
+        });
+        // The 1_000 funded only cover ten of the twelve sessions.
+        assert_eq!(claim_at(&mut contract, farm_id, &[120]), 1_000);
+        let farm = contract.internal_get_farm(farm_id).unwrap();
+        assert_eq!(farm.remaining_reward, vec![0]);
+        assert_eq!(farm.unfunded_emission, vec![200]);
+        assert_eq!(farm.status, FarmStatus::Ended);
+    }
+
+    #[test]
+    fn test_reward_totals_and_liabilities() {
+        let (mut contract, farm_id) = setup_staked_farm(0);
+        assert_eq!(claim_at(&mut contract, farm_id, &[20]), 200);
+        let farm = contract.internal_get_farm(farm_id).unwrap();
+        assert_eq!(farm.total_distributed, vec![200]);
+        assert_eq!(farm.total_claimed, vec![200]);
+
+        let context = get_context(accounts(1), 25_000_000_000, 0);
+        testing_env!(context.build());
+        assert_eq!(
+            contract.get_token_liabilities("reward.token".parse().unwrap()),
+            TokenLiabilities {
+                token_id: "reward.token".parse().unwrap(),
+                staked: U128(0),
+                remaining_reward: U128(800),
+                accrued_rewards: U128(0),
+            }
+        );
+        assert_eq!(
+            contract.get_token_liabilities("staking.token".parse().unwrap()).total(),
+            100
+        );
+
+        // A failed claim is owed again.
+        set_callback_context(25_000_000_000, PromiseResult::Failed);
+        contract.on_claim_resolved(accounts(1), farm_id, "reward.token".parse().unwrap(), U128(200));
+        let liabilities = contract.get_token_liabilities("reward.token".parse().unwrap());
+        assert_eq!(liabilities.accrued_rewards, U128(200));
+        assert_eq!(liabilities.total(), 1_000);
+    }
+
+    #[test]
+    fn test_emergency_withdraw_forfeit_reduces_distributed() {
+        let (mut contract, farm_id) = setup_staked_farm(0);
+        let context = get_context(accounts(1), 20_000_000_000, 1);
+        testing_env!(context.build());
+        contract.update_farm(farm_id);
+        contract.emergency_withdraw(farm_id);
+        let farm = contract.internal_get_farm(farm_id).unwrap();
+        assert_eq!(farm.total_distributed, vec![0]);
+        assert_eq!(farm.remaining_reward, vec![1_000]);
+    }
+
+    #[test]
+    fn test_solvency_report() {
+        let (contract, _) = setup_staked_farm(0);
+        set_callback_context(0, PromiseResult::Successful(b"\"1000\"".to_vec()));
+        let report = contract.on_solvency_checked("reward.token".parse().unwrap());
+        assert!(report.solvent);
+        assert_eq!(report.balance, U128(1_000));
+
+        set_callback_context(0, PromiseResult::Successful(b"\"999\"".to_vec()));
+        assert!(!contract.on_solvency_checked("reward.token".parse().unwrap()).solvent);
+    }
+
+    #[test]
+    fn test_reward_math_properties() {
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand::rngs::StdRng::seed_from_u64(16);
+        let scale = U256::from(ACC_REWARD_MULTIPLIER);
+        for _ in 0..2_000 {
+            // Up to a billion tokens with up to 24 decimals on either side.
+            let stake_unit = 10_u128.pow(rng.gen_range(0..=24));
+            let reward_unit = 10_u128.pow(rng.gen_range(0..=24));
+            let stakes: Vec<u128> = (0..rng.gen_range(1..=5))
+                .map(|_| rng.gen_range(1..=1_000_000_000) * stake_unit)
+                .collect();
+            let total_staked: u128 = stakes.iter().sum();
+            let reward_per_session = rng.gen_range(1..=1_000_000) * reward_unit;
+            let sessions = rng.gen_range(1..=10_000_u128);
+
+            let increment = math::reward_per_share_increment(reward_per_session, total_staked);
+            let reward_per_share = math::add_reward_per_share(increment * U256::from(sessions - 1), increment);
+            let paid: u128 = stakes.iter().map(|&amount| math::reward_for(amount, reward_per_share)).sum();
+
+            // Never more than emitted, and short by less than one unit per staker plus
+            // the per-session rounding of the increment.
+            let emitted = reward_per_session * sessions;
+            assert!(paid <= emitted);
+            let rounding = U256::from(sessions) * U256::from(total_staked) / scale;
+            assert!(U256::from(emitted - paid) <= U256::from(stakes.len()) + rounding + U256::one());
+        }
+    }
+
+    #[test]
+    fn test_reward_math_small_rewards_on_large_stakes() {
+        // A 6-decimal reward shared by a billion 24-decimal tokens, which a 10^12
+        // scale would have rounded down to nothing.
+        let total_staked = 1_000_000_000 * 10_u128.pow(24);
+        let increment = math::reward_per_share_increment(1_000_000, total_staked);
+        assert_eq!(math::reward_for(total_staked, increment), 1_000_000);
+        assert_eq!(math::reward_for(total_staked / 4, increment), 250_000);
+    }
+
+    #[test]
+    fn test_reward_math_extremes_fit() {
+        let increment = math::reward_per_share_increment(u128::MAX, 1);
+        assert_eq!(math::reward_for(1, increment), u128::MAX);
+        let increment = math::reward_per_share_increment(1, u128::MAX);
+        assert_eq!(math::reward_for(u128::MAX, increment), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Reward calculation overflow")]
+    fn test_reward_math_overflow_panics() {
+        let increment = math::reward_per_share_increment(u128::MAX, 1);
+        math::reward_for(2, increment);
+    }
+
+    #[test]
+    #[should_panic(expected = "Reward per share overflow")]
+    fn test_reward_per_share_overflow_panics() {
+        math::add_reward_per_share(U256::MAX, U256::one());
+    }
+
+    #[test]
+    fn test_v1_reward_per_share_is_rescaled() {
+        let farm = FarmParamsV1 {
+            reward_per_share: vec![2_000_000_000_000],
+            ..v1_farm()
+        }
+        .into_current(accounts(0));
+        assert_eq!(farm.reward_per_share, vec![U256::from(2 * ACC_REWARD_MULTIPLIER)]);
+        let stake_info = StakeInfoV1 {
+            amount: 100,
+            lockup_end: 0,
+            reward_debt: vec![1_000_000_000_000],
+            accrued_rewards: vec![0],
+        }
+        .into_current();
+        assert_eq!(stake_info.reward_debt, vec![U256::from(ACC_REWARD_MULTIPLIER)]);
+    }
+
+    /// At each of `times_sec`, checks that the farm and the rewards the views report
+    /// for accounts(1) are what an update and a claim at that time commit.
+    fn assert_views_match_commits(contract: &mut ChildFarmingContract, farm_id: u64, times_sec: &[u64]) {
+        for &time_sec in times_sec {
+            let context = get_context(accounts(1), time_sec * 1_000_000_000, 1);
+            testing_env!(context.build());
+            let farm = contract.internal_get_farm(farm_id).unwrap();
+            let (simulated, _) = contract.internal_farm_updated_to(farm_id, &farm, env::block_timestamp());
+            let viewed = contract.get_stake_info(accounts(1), farm_id).unwrap().accrued_rewards;
+            let listed = contract.list_stakes_by_user(accounts(1), 0, 10);
+            assert_eq!(listed[0].accrued_rewards, viewed, "listed rewards at {}s", time_sec);
+
+            contract.update_farm(farm_id);
+            let committed = contract.internal_get_farm(farm_id).unwrap();
+            assert_eq!(
+                borsh::to_vec(&simulated).unwrap(),
+                borsh::to_vec(&committed).unwrap(),
+                "farm at {}s",
+                time_sec
+            );
+            assert_eq!(
+                viewed,
+                vec![U128(claim_at(contract, farm_id, &[time_sec]))],
+                "rewards at {}s",
+                time_sec
+            );
+        }
+    }
+
+    #[test]
+    fn test_views_match_commits_session_mode() {
+        let (mut contract, farm_id) = setup_staked_farm(0);
+        assert_views_match_commits(&mut contract, farm_id, &[3, 9, 10, 14, 25, 31, 31, 60]);
+    }
+
+    #[test]
+    fn test_views_match_commits_continuous_mode() {
+        let (mut contract, farm_id) = setup_staked_farm_with(FarmInput {
+            reward_per_session: vec![U128(7)],
+            distribution_mode: DistributionMode::Continuous,
+            ..default_farm_input()
+        });
+        assert_views_match_commits(&mut contract, farm_id, &[3, 9, 10, 14, 25, 31, 31, 60]);
+    }
+
+    #[test]
+    fn test_views_match_commits_schedule_and_end_time() {
+        let (mut contract, farm_id) = setup_staked_farm_with(FarmInput {
+            emission_schedule: vec![EmissionStep {
+                from_sec: 15,
+                reward_per_session: vec![U128(50)],
+            }],
+            end_at_sec: Some(42),
+            ..default_farm_input()
+        });
+        assert_views_match_commits(&mut contract, farm_id, &[5, 12, 20, 37, 42, 50]);
+        assert_eq!(contract.internal_get_farm(farm_id).unwrap().status, FarmStatus::Ended);
+    }
+
+    #[test]
+    fn test_views_match_commits_until_depleted() {
+        let (mut contract, farm_id) = setup_staked_farm_with(FarmInput {
+            reward_per_session: vec![U128(300)],
+            ..default_farm_input()
+        });
+        assert_views_match_commits(&mut contract, farm_id, &[10, 25, 40, 45, 60]);
+        let farm = contract.internal_get_farm(farm_id).unwrap();
+        assert_eq!(farm.status, FarmStatus::Ended);
+        assert_eq!(farm.unfunded_emission, vec![200]);
+    }
+
+    #[test]
+    fn test_views_match_commits_before_start() {
+        let (mut contract, farm_id) = setup_staked_farm_with(FarmInput {
+            start_at_sec: 20,
+            ..default_farm_input()
+        });
+        assert_views_match_commits(&mut contract, farm_id, &[5, 20, 35, 40]);
+    }
+
+    #[test]
+    fn test_views_match_commits_while_paused() {
+        let (mut contract, farm_id) = setup_staked_farm(0);
+        let context = get_context(accounts(0), 15_000_000_000, 1);
+        testing_env!(context.build());
+        contract.pause_farm(farm_id);
+        assert_views_match_commits(&mut contract, farm_id, &[20, 30]);
+        let context = get_context(accounts(0), 30_000_000_000, 1);
+        testing_env!(context.build());
+        contract.resume_farm(farm_id);
+        assert_views_match_commits(&mut contract, farm_id, &[35, 40, 55]);
+    }
+
+    #[test]
+    fn test_views_match_commits_idle_carry_forward() {
+        let (mut contract, farm_id) = setup_funded_farm(FarmInput {
+            idle_reward_policy: IdleRewardPolicy::CarryForward,
+            ..default_farm_input()
+        });
+        stake_at(&mut contract, farm_id, 100, 25);
+        assert_views_match_commits(&mut contract, farm_id, &[25, 30, 47]);
+    }
+
+    #[test]
+    fn test_settle_matches_unclaimed_rewards() {
+        let (contract, farm_id) = setup_staked_farm(0);
+        let mut farm = contract.internal_get_farm(farm_id).unwrap();
+        farm.update_to(25_000_000_000);
+        let mut stake_info = contract.internal_get_stake(&(accounts(1), farm_id)).unwrap();
+        assert_eq!(stake_info.pending_reward(&farm, 0), 200);
+        let unclaimed = stake_info.unclaimed_rewards(&farm);
+        stake_info.settle(&farm);
+        assert_eq!(stake_info.accrued_rewards, unclaimed);
+        assert_eq!(stake_info.reward_debt, farm.reward_per_share);
+        assert_eq!(stake_info.pending_reward(&farm, 0), 0);
+    }
+
+    /// Withdraws `amount` for accounts(1) at `time_sec`.
+    fn withdraw_at(contract: &mut ChildFarmingContract, farm_id: u64, amount: u128, time_sec: u64) {
+        let context = get_context(accounts(1), time_sec * 1_000_000_000, 1);
+        testing_env!(context.build());
+        contract.withdraw(farm_id, U128(amount));
+    }
+
+    #[test]
+    fn test_claim_after_full_withdraw_removes_stake() {
+        let (mut contract, farm_id) = setup_staked_farm(0);
+        withdraw_at(&mut contract, farm_id, 100, 10);
+        // The rewards accrued until the withdrawal keep the stake around.
+        assert!(contract.internal_get_stake(&(accounts(1), farm_id)).is_some());
+
+        let context = get_context(accounts(1), 20_000_000_000, 1);
+        testing_env!(context.build());
+        contract.claim_rewards(farm_id);
+        assert!(contract.internal_get_stake(&(accounts(1), farm_id)).is_none());
+        assert_eq!(contract.get_farm_staker_count(farm_id), 0);
+        assert_eq!(contract.storage_deposits.get(&accounts(1)).unwrap().used_bytes, 0);
+        assert!(contract.storage_unregister(None));
+    }
+
+    #[test]
+    #[should_panic(expected = "Nothing to withdraw")]
+    fn test_withdraw_zero() {
+        let (mut contract, farm_id) = setup_staked_farm(0);
+        withdraw_at(&mut contract, farm_id, 0, 10);
+    }
+
+    #[test]
+    fn test_top_up_keeps_earlier_lockup() {
+        let (mut contract, farm_id) = setup_staked_farm(10);
+        stake_at(&mut contract, farm_id, 50, 8);
+
+        let context = get_context(accounts(1), 12_000_000_000, 0);
+        testing_env!(context.build());
+        let view = contract.get_stake_info(accounts(1), farm_id).unwrap();
+        assert_eq!(view.lockup_end_sec, 18);
+        let positions: Vec<(u128, u64, u64)> = view
+            .positions
+            .iter()
+            .map(|p| (p.amount.0, p.start_sec, p.unlock_at_sec))
+            .collect();
+        assert_eq!(positions, vec![(100, 0, 10), (50, 8, 18)]);
+
+        // The first position has unlocked, the top-up has not.
+        withdraw_at(&mut contract, farm_id, 100, 12);
+        let stake_info = contract.internal_get_stake(&(accounts(1), farm_id)).unwrap();
+        assert_eq!(stake_info.amount, 50);
+        assert_eq!(stake_info.unlocked_amount(12_000_000_000), 0);
+        withdraw_at(&mut contract, farm_id, 50, 18);
+        assert_eq!(contract.internal_get_farm(farm_id).unwrap().total_staked, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Lockup period not expired")]
+    fn test_withdraw_beyond_unlocked_positions() {
+        let (mut contract, farm_id) = setup_staked_farm(10);
+        stake_at(&mut contract, farm_id, 50, 8);
+        withdraw_at(&mut contract, farm_id, 101, 12);
+    }
+
+    #[test]
+    fn test_unlocked_positions_merge() {
+        let (mut contract, farm_id) = setup_staked_farm(10);
+        stake_at(&mut contract, farm_id, 50, 5);
+        stake_at(&mut contract, farm_id, 20, 5);
+        let stake_info = contract.internal_get_stake(&(accounts(1), farm_id)).unwrap();
+        assert_eq!(stake_info.positions.len(), 2);
+        assert_eq!(stake_info.positions[1].amount, 70);
+
+        // Both have unlocked by now, so they merge ahead of the new position.
+        stake_at(&mut contract, farm_id, 30, 20);
+        let stake_info = contract.internal_get_stake(&(accounts(1), farm_id)).unwrap();
+        assert_eq!(
+            stake_info.positions,
+            vec![
+                LockPosition {
+                    amount: 170,
+                    start: 0,
+                    unlock_at: 15_000_000_000,
+                    multiplier_bps: BASE_MULTIPLIER_BPS,
+                },
+                LockPosition {
+                    amount: 30,
+                    start: 20_000_000_000,
+                    unlock_at: 30_000_000_000,
+                    multiplier_bps: BASE_MULTIPLIER_BPS,
+                },
+            ]
+        );
+        assert_eq!(stake_info.amount, 200);
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot hold more than 16 locked positions in a farm")]
+    fn test_too_many_lock_positions() {
+        let (mut contract, farm_id) = setup_staked_farm(100);
+        for time_sec in 1..=MAX_LOCK_POSITIONS as u64 {
+            stake_at(&mut contract, farm_id, 1, time_sec);
+        }
+    }
+
+    #[test]
+    fn test_emergency_withdraw_penalizes_locked_positions_only() {
+        let (mut contract, farm_id) = setup_staked_farm(10);
+        stake_at(&mut contract, farm_id, 40, 8);
+        let context = get_context(accounts(0), 8_000_000_000, 1);
+        testing_env!(context.build());
+        contract.set_early_exit_penalty(farm_id, 2_500, PenaltyDestination::Burn);
+
+        let context = get_context(accounts(1), 12_000_000_000, 1);
+        testing_env!(context.build());
+        contract.emergency_withdraw(farm_id);
+        assert_eq!(contract.internal_get_farm(farm_id).unwrap().burned_penalty, 10);
+    }
+
+    fn tiered_farm_input() -> FarmInput {
+        FarmInput {
+            lock_tiers: vec![
+                LockTier {
+                    duration_sec: 30,
+                    multiplier_bps: 30_000,
+                },
+                LockTier {
+                    duration_sec: 90,
+                    multiplier_bps: 15_000,
+                },
+            ],
+            ..default_farm_input()
+        }
+    }
+
+    /// Registers `account_id` and stakes `amount` for it in `lock_tier` at `time_sec`.
+    fn stake_in_tier_at(
+        contract: &mut ChildFarmingContract,
+        farm_id: u64,
+        account_id: AccountId,
+        amount: u128,
+        lock_tier: u8,
+        time_sec: u64,
+    ) {
+        let context = get_context(account_id.clone(), time_sec * 1_000_000_000, 1_000_000_000_000_000_000_000_000);
+        testing_env!(context.build());
+        contract.storage_deposit(None, None);
+        let context = get_context("staking.token".parse().unwrap(), time_sec * 1_000_000_000, 0);
+        testing_env!(context.build());
+        let msg = format!(r#"{{"action":"stake","farm_id":{},"lock_tier":{}}}"#, farm_id, lock_tier);
+        contract.ft_on_transfer(account_id, U128(amount), msg);
+    }
+
+    fn accrued_at(contract: &ChildFarmingContract, farm_id: u64, account_id: AccountId, time_sec: u64) -> u128 {
+        let context = get_context(account_id.clone(), time_sec * 1_000_000_000, 0);
+        testing_env!(context.build());
+        contract.get_stake_info(account_id, farm_id).unwrap().accrued_rewards[0].0
+    }
+
+    #[test]
+    fn test_lock_tier_boosts_rewards() {
+        let (mut contract, farm_id) = setup_staked_farm_with(tiered_farm_input());
+        stake_in_tier_at(&mut contract, farm_id, accounts(2), 100, 1, 0);
+
+        let stake_info = contract.internal_get_stake(&(accounts(2), farm_id)).unwrap();
+        assert_eq!(stake_info.effective_amount, 150);
+        assert_eq!(stake_info.lockup_end(), 90_000_000_000);
+        let farm = contract.get_farm(farm_id).unwrap();
+        assert_eq!(farm.total_staked, U128(200));
+        assert_eq!(farm.total_effective_staked, U128(250));
+        assert_eq!(farm.lock_tiers, tiered_farm_input().lock_tiers);
+
+        // 100 per session over 250 weighted tokens.
+        assert_eq!(accrued_at(&contract, farm_id, accounts(1), 10), 40);
+        assert_eq!(accrued_at(&contract, farm_id, accounts(2), 10), 60);
+    }
+
+    #[test]
+    fn test_boost_expires_after_unlock() {
+        let (mut contract, farm_id) = setup_staked_farm_with(tiered_farm_input());
+        stake_in_tier_at(&mut contract, farm_id, accounts(2), 100, 0, 0);
+
+        // Three sessions at 3x, then the boost is dropped once the lock has ended.
+        let context = get_context(accounts(0), 30_000_000_000, 0);
+        testing_env!(context.build());
+        contract.refresh_boost(accounts(2), farm_id);
+        let stake_info = contract.internal_get_stake(&(accounts(2), farm_id)).unwrap();
+        assert_eq!(stake_info.effective_amount, 100);
+        assert_eq!(stake_info.positions[0].multiplier_bps, BASE_MULTIPLIER_BPS);
+        assert_eq!(contract.internal_get_farm(farm_id).unwrap().total_effective_staked, 200);
+
+        assert_eq!(accrued_at(&contract, farm_id, accounts(1), 40), 125);
+        assert_eq!(accrued_at(&contract, farm_id, accounts(2), 40), 275);
+    }
+
+    #[test]
+    fn test_boost_stops_earning_at_unlock_without_refresh() {
+        let (mut contract, farm_id) = setup_staked_farm_with(tiered_farm_input());
+        stake_in_tier_at(&mut contract, farm_id, accounts(2), 100, 0, 0);
+
+        // Three sessions at 3x, then both stakes weigh the same although nobody
+        // touched the boosted one.
+        assert_eq!(accrued_at(&contract, farm_id, accounts(1), 60), 225);
+        assert_eq!(accrued_at(&contract, farm_id, accounts(2), 60), 375);
+
+        // An update after the unlock drops the boost from the farm's totals.
+        assert_eq!(claim_at(&mut contract, farm_id, &[40]), 125);
+        assert_eq!(contract.internal_get_farm(farm_id).unwrap().total_effective_staked, 200);
+        assert_eq!(contract.internal_get_stake(&(accounts(2), farm_id)).unwrap().effective_amount, 300);
+
+        // The boosted stake earns 3x until the unlock and 1x after it.
+        let context = get_context(accounts(2), 60_000_000_000, 1);
+        testing_env!(context.build());
+        contract.claim_rewards(farm_id);
+        let claimed: u128 = emitted_events()
+            .into_iter()
+            .map(|event| match event {
+                FarmEvent::RewardsClaimed { amount, .. } => amount.0,
+                _ => 0,
+            })
+            .sum();
+        assert_eq!(claimed, 375);
+        let stake_info = contract.internal_get_stake(&(accounts(2), farm_id)).unwrap();
+        assert_eq!(stake_info.effective_amount, 100);
+        assert!(contract.expired_boosts.get(&(farm_id, 30_000_000_000)).is_none());
+        assert_eq!(accrued_at(&contract, farm_id, accounts(1), 60), 100);
+    }
+
+    #[test]
+    fn test_refresh_boost_keeps_locked_boost() {
+        let (mut contract, farm_id) = setup_staked_farm_with(tiered_farm_input());
+        stake_in_tier_at(&mut contract, farm_id, accounts(2), 100, 0, 0);
+        let context = get_context(accounts(0), 20_000_000_000, 0);
+        testing_env!(context.build());
+        contract.refresh_boost(accounts(2), farm_id);
+        let stake_info = contract.internal_get_stake(&(accounts(2), farm_id)).unwrap();
+        assert_eq!(stake_info.effective_amount, 300);
+        assert_eq!(stake_info.accrued_rewards, vec![150]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown lock tier")]
+    fn test_unknown_lock_tier() {
+        let (mut contract, farm_id) = setup_staked_farm_with(tiered_farm_input());
+        stake_in_tier_at(&mut contract, farm_id, accounts(2), 100, 2, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Lock tiers must be in increasing order of duration")]
+    fn test_lock_tiers_out_of_order() {
+        let mut input = tiered_farm_input();
+        input.lock_tiers.reverse();
+        setup_funded_farm(input);
+    }
+
+    #[test]
+    #[should_panic(expected = "Lock tier multipliers must be between 10000 and 100000 bps")]
+    fn test_lock_tier_multiplier_out_of_range() {
+        setup_funded_farm(FarmInput {
+            lock_tiers: vec![LockTier {
+                duration_sec: 30,
+                multiplier_bps: 5_000,
+            }],
+            ..default_farm_input()
+        });
+    }
+
+    /// A farm paying rewards in its staking token, funded with 1000 and with
+    /// 100 staked by accounts(1) at t=0.
+    fn setup_compounding_farm() -> (ChildFarmingContract, u64) {
+        let context = get_context(accounts(0), 0, 10_000_000_000_000_000_000_000_000);
+        testing_env!(context.build());
+        let mut contract = ChildFarmingContract::new(accounts(0));
+        contract.storage_deposit(None, None);
+        let farm_id = contract.create_farm(FarmInput {
+            reward_tokens: vec!["staking.token".parse().unwrap()],
+            ..default_farm_input()
+        });
+        for account_id in [accounts(1), accounts(2)] {
+            let context = get_context(account_id, 0, 1_000_000_000_000_000_000_000_000);
+            testing_env!(context.build());
+            contract.storage_deposit(None, None);
+        }
+        let context = get_context("staking.token".parse().unwrap(), 0, 0);
+        testing_env!(context.build());
+        contract.ft_on_transfer(accounts(0), U128(1_000), format!("ADD_REWARD:{}", farm_id));
+        stake_at(&mut contract, farm_id, 100, 0);
+        (contract, farm_id)
+    }
+
+    #[test]
+    fn test_compound_restakes_rewards() {
+        let (mut contract, farm_id) = setup_compounding_farm();
+        let context = get_context(accounts(1), 10_000_000_000, 1);
+        testing_env!(context.build());
+        assert_eq!(contract.compound(farm_id), U128(100));
+        assert_eq!(
+            emitted_events(),
+            vec![FarmEvent::Compounded {
+                farm_id,
+                account_id: accounts(1),
+                amount: U128(100),
+            }]
+        );
+
+        let stake_info = contract.internal_get_stake(&(accounts(1), farm_id)).unwrap();
+        assert_eq!(stake_info.amount, 200);
+        assert_eq!(stake_info.accrued_rewards, vec![0]);
+        assert_eq!(stake_info.positions.len(), 1);
+        // The contract holds 1100 staking tokens and owes exactly that.
+        let liabilities = contract.get_token_liabilities("staking.token".parse().unwrap());
+        assert_eq!(liabilities.staked, U128(200));
+        assert_eq!(liabilities.remaining_reward, U128(900));
+        assert_eq!(liabilities.accrued_rewards, U128(0));
+
+        // The compounded stake earns from then on, and can be withdrawn.
+        assert_eq!(accrued_at(&contract, farm_id, accounts(1), 20), 100);
+        withdraw_at(&mut contract, farm_id, 200, 20);
+    }
+
+    #[test]
+    fn test_claim_with_auto_compound_after_end_time() {
+        let context = get_context(accounts(0), 0, 10_000_000_000_000_000_000_000_000);
+        testing_env!(context.build());
+        let mut contract = ChildFarmingContract::new(accounts(0));
+        contract.storage_deposit(None, None);
+        let farm_id = contract.create_farm(FarmInput {
+            reward_tokens: vec!["staking.token".parse().unwrap()],
+            end_at_sec: Some(20),
+            ..default_farm_input()
+        });
+        let context = get_context(accounts(1), 0, 1_000_000_000_000_000_000_000_000);
+        testing_env!(context.build());
+        contract.storage_deposit(None, None);
+        let context = get_context("staking.token".parse().unwrap(), 0, 0);
+        testing_env!(context.build());
+        contract.ft_on_transfer(accounts(0), U128(1_000), format!("ADD_REWARD:{}", farm_id));
+        stake_at(&mut contract, farm_id, 100, 0);
+        let context = get_context(accounts(1), 0, 1);
+        testing_env!(context.build());
+        contract.set_auto_compound(farm_id, true);
+
+        // The farm has ended, so the rewards are paid out instead of restaked.
+        assert_eq!(claim_at(&mut contract, farm_id, &[30]), 200);
+        assert_eq!(contract.internal_get_stake(&(accounts(1), farm_id)).unwrap().amount, 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "Farm is ended, compounding not allowed")]
+    fn test_compound_after_end_time() {
+        let context = get_context(accounts(0), 0, 10_000_000_000_000_000_000_000_000);
+        testing_env!(context.build());
+        let mut contract = ChildFarmingContract::new(accounts(0));
+        contract.storage_deposit(None, None);
+        let farm_id = contract.create_farm(FarmInput {
+            reward_tokens: vec!["staking.token".parse().unwrap()],
+            end_at_sec: Some(20),
+            ..default_farm_input()
+        });
+        let context = get_context(accounts(1), 0, 1_000_000_000_000_000_000_000_000);
+        testing_env!(context.build());
+        contract.storage_deposit(None, None);
+        stake_at(&mut contract, farm_id, 100, 0);
+
+        let context = get_context(accounts(1), 30_000_000_000, 1);
+        testing_env!(context.build());
+        contract.compound(farm_id);
+    }
+
+    #[test]
+    #[should_panic(expected = "The staking token is not a reward token of this farm")]
+    fn test_compound_needs_staking_reward_token() {
+        let (mut contract, farm_id) = setup_staked_farm(0);
+        let context = get_context(accounts(1), 10_000_000_000, 1);
+        testing_env!(context.build());
+        contract.compound(farm_id);
+    }
+
+    #[test]
+    fn test_compound_for_only_opted_in_stakes() {
+        let (mut contract, farm_id) = setup_compounding_farm();
+        let context = get_context("staking.token".parse().unwrap(), 0, 0);
+        testing_env!(context.build());
+        let msg = format!(r#"{{"action":"stake","farm_id":{},"beneficiary":"{}"}}"#, farm_id, accounts(2));
+        contract.ft_on_transfer(accounts(2), U128(100), msg);
+        let context = get_context(accounts(1), 0, 1);
+        testing_env!(context.build());
+        contract.set_auto_compound(farm_id, true);
+
+        let context = get_context(accounts(3), 10_000_000_000, 0);
+        testing_env!(context.build());
+        let compounded = contract.compound_for(farm_id, vec![accounts(1), accounts(2), accounts(3)]);
+        assert_eq!(compounded, U128(50));
+        assert_eq!(contract.internal_get_stake(&(accounts(1), farm_id)).unwrap().amount, 150);
+        let stake_info = contract.internal_get_stake(&(accounts(2), farm_id)).unwrap();
+        assert_eq!(stake_info.amount, 100);
+        assert_eq!(contract.internal_get_farm(farm_id).unwrap().total_staked, 250);
+        assert_eq!(accrued_at(&contract, farm_id, accounts(2), 10), 50);
+    }
+
+    #[test]
+    fn test_compound_for_merges_into_unlocked_position() {
+        let (mut contract, farm_id) = setup_compounding_farm();
+        let context = get_context(accounts(1), 0, 1);
+        testing_env!(context.build());
+        contract.set_auto_compound(farm_id, true);
+        let before = contract.storage_deposits.get(&accounts(1)).unwrap();
+
+        let context = get_context(accounts(3), 10_000_000_000, 0);
+        testing_env!(context.build());
+        assert_eq!(contract.compound_for(farm_id, vec![accounts(1)]), U128(100));
+        let stake_info = contract.internal_get_stake(&(accounts(1), farm_id)).unwrap();
+        assert_eq!(stake_info.positions.len(), 1);
+        assert_eq!(stake_info.positions[0].amount, 200);
+
+        // The owner pays for what was added, which stays within the bound checked upfront.
+        let after = contract.storage_deposits.get(&accounts(1)).unwrap();
+        let byte_cost = env::storage_byte_cost().as_yoctonear();
+        let paid = before.deposit - after.deposit + (after.used_bytes - before.used_bytes) as u128 * byte_cost;
+        assert!(paid > 0);
+        assert!(paid <= MAX_COMPOUND_STORAGE_BYTES as u128 * byte_cost);
+    }
+
+    #[test]
+    fn test_compound_for_skips_locked_and_underfunded_stakes() {
+        let (mut contract, farm_id) = setup_compounding_farm();
+        let context = get_context("staking.token".parse().unwrap(), 0, 0);
+        testing_env!(context.build());
+        let msg = format!(r#"{{"action":"stake","farm_id":{},"lock_until_sec":100}}"#, farm_id);
+        contract.ft_on_transfer(accounts(2), U128(100), msg);
+        for account_id in [accounts(1), accounts(2)] {
+            let context = get_context(account_id, 0, 1);
+            testing_env!(context.build());
+            contract.set_auto_compound(farm_id, true);
+        }
+        // accounts(1) keeps no available balance to pay for compounding.
+        let context = get_context(accounts(1), 0, 1);
+        testing_env!(context.build());
+        contract.storage_withdraw(None);
+        let used_bytes = contract.storage_deposits.get(&accounts(1)).unwrap().used_bytes;
+
+        let context = get_context(accounts(3), 10_000_000_000, 0);
+        testing_env!(context.build());
+        assert_eq!(contract.compound_for(farm_id, vec![accounts(1), accounts(2)]), U128(0));
+        assert_eq!(contract.internal_get_stake(&(accounts(1), farm_id)).unwrap().amount, 100);
+        let stake_info = contract.internal_get_stake(&(accounts(2), farm_id)).unwrap();
+        assert_eq!((stake_info.amount, stake_info.positions.len()), (100, 1));
+        assert_eq!(contract.storage_deposits.get(&accounts(1)).unwrap().used_bytes, used_bytes);
+    }
+
+    #[test]
+    fn test_claim_with_auto_compound() {
+        let (mut contract, farm_id) = setup_compounding_farm();
+        let context = get_context(accounts(1), 0, 1);
+        testing_env!(context.build());
+        contract.set_auto_compound(farm_id, true);
+        assert!(contract.get_stake_info(accounts(1), farm_id).unwrap().auto_compound);
+
+        assert_eq!(claim_at(&mut contract, farm_id, &[10]), 0);
+        assert_eq!(contract.internal_get_stake(&(accounts(1), farm_id)).unwrap().amount, 200);
+    }
+
+    #[test]
+    #[should_panic(expected = "The staking token is not a reward token of this farm")]
+    fn test_auto_compound_needs_staking_reward_token() {
+        let (mut contract, farm_id) = setup_staked_farm(0);
+        let context = get_context(accounts(1), 0, 1);
+        testing_env!(context.build());
+        contract.set_auto_compound(farm_id, true);
+    }
+
+    #[test]
+    fn test_stake_index_follows_stakes() {
+        let (mut contract, first) = setup_funded_farm(default_farm_input());
+        let context = get_context(accounts(0), 0, 0);
+        testing_env!(context.build());
+        let second = contract.create_farm(default_farm_input());
+        let third = contract.create_farm(default_farm_input());
+        let listed_farms = |contract: &ChildFarmingContract, from_index: u64, limit: u64| -> Vec<u64> {
+            contract
+                .list_stakes_by_user(accounts(1), from_index, limit)
+                .iter()
+                .map(|view| view.farm_id)
+                .collect()
+        };
+        assert!(listed_farms(&contract, 0, 10).is_empty());
+
+        stake_at(&mut contract, first, 100, 0);
+        stake_at(&mut contract, second, 50, 0);
+        stake_at(&mut contract, second, 50, 1);
+        stake_at(&mut contract, third, 10, 1);
+        assert_eq!(listed_farms(&contract, 0, 10), vec![first, second, third]);
+        assert_eq!(listed_farms(&contract, 1, 1), vec![second]);
+        assert_eq!(listed_farms(&contract, 2, 10), vec![third]);
+        assert!(listed_farms(&contract, 3, 10).is_empty());
+        assert!(listed_farms(&contract, u64::MAX, u64::MAX).is_empty());
+        assert_eq!(contract.list_stakes_by_user(accounts(1), 1, 1)[0].amount.0, 100);
+        assert!(contract.list_stakes_by_user(accounts(2), 0, 10).is_empty());
+
+        // A partial withdrawal keeps the stake indexed, withdrawing the rest drops it.
+        withdraw_at(&mut contract, second, 40, 2);
+        assert_eq!(listed_farms(&contract, 0, 10), vec![first, second, third]);
+        withdraw_at(&mut contract, second, 60, 2);
+        assert_eq!(listed_farms(&contract, 0, 10), vec![first, third]);
+        assert!(contract.farm_stakers.get(&second).is_none());
+
+        let context = get_context(accounts(1), 3_000_000_000, 1);
+        testing_env!(context.build());
+        contract.emergency_withdraw(first);
+        assert_eq!(listed_farms(&contract, 0, 10), vec![third]);
+        assert!(contract.farm_stakers.get(&first).is_none());
+        let stakers = contract.farm_stakers.get(&third).unwrap();
+        assert_eq!(stakers.to_vec(), vec![accounts(1)]);
+
+        withdraw_at(&mut contract, third, 10, 4);
+        assert!(listed_farms(&contract, 0, 10).is_empty());
+        assert!(contract.user_farms.get(&accounts(1)).is_none());
+    }
+
+    #[test]
+    fn test_farm_stakers_and_leaderboard() {
+        let (mut contract, farm_id) = setup_funded_farm(default_farm_input());
+        let stake_for = |contract: &mut ChildFarmingContract, account_id: AccountId, amount: u128, time_sec: u64| {
+            let context = get_context(account_id.clone(), time_sec * 1_000_000_000, 1_000_000_000_000_000_000_000_000);
+            testing_env!(context.build());
+            contract.storage_deposit(None, None);
+            let context = get_context("staking.token".parse().unwrap(), time_sec * 1_000_000_000, 0);
+            testing_env!(context.build());
+            contract.ft_on_transfer(account_id, U128(amount), format!("STAKE:{}", farm_id));
+        };
+        let top = |contract: &ChildFarmingContract, limit: u64| -> Vec<(AccountId, u128)> {
+            contract
+                .get_top_stakers(farm_id, limit)
+                .into_iter()
+                .map(|staker| (staker.account_id, staker.stake.amount.0))
+                .collect()
+        };
+        assert_eq!(contract.get_farm_staker_count(farm_id), 0);
+        assert!(contract.list_farm_stakers(farm_id, 0, 10).is_empty());
+        assert!(top(&contract, 10).is_empty());
+
+        stake_at(&mut contract, farm_id, 100, 0);
+        stake_for(&mut contract, accounts(2), 300, 0);
+        stake_for(&mut contract, accounts(3), 200, 0);
+        assert_eq!(contract.get_farm_staker_count(farm_id), 3);
+        assert_eq!(
+            top(&contract, 10),
+            vec![(accounts(2), 300), (accounts(3), 200), (accounts(1), 100)]
+        );
+        assert_eq!(top(&contract, 2), vec![(accounts(2), 300), (accounts(3), 200)]);
+
+        let listed: Vec<AccountId> = contract
+            .list_farm_stakers(farm_id, 0, 10)
+            .into_iter()
+            .map(|staker| staker.account_id)
+            .collect();
+        assert_eq!(listed, vec![accounts(1), accounts(2), accounts(3)]);
+        let page = contract.list_farm_stakers(farm_id, 1, 1);
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].account_id, accounts(2));
+        assert!(contract.list_farm_stakers(farm_id, 3, 10).is_empty());
+
+        // Top-ups and withdrawals reorder the leaderboard.
+        stake_at(&mut contract, farm_id, 250, 1);
+        withdraw_at(&mut contract, farm_id, 30, 2);
+        assert_eq!(
+            top(&contract, 10),
+            vec![(accounts(1), 320), (accounts(2), 300), (accounts(3), 200)]
+        );
+        withdraw_at(&mut contract, farm_id, 320, 3);
+        assert_eq!(contract.get_farm_staker_count(farm_id), 2);
+        assert_eq!(top(&contract, 10), vec![(accounts(2), 300), (accounts(3), 200)]);
+
+        // Rewards are reported as of now, as by `get_stake_info`.
+        let context = get_context(accounts(2), 25_000_000_000, 0);
+        testing_env!(context.build());
+        let staker = &contract.get_top_stakers(farm_id, 1)[0];
+        let viewed = contract.get_stake_info(accounts(2), farm_id).unwrap();
+        assert!(viewed.accrued_rewards[0].0 > 0);
+        assert_eq!(staker.stake.accrued_rewards, viewed.accrued_rewards);
+    }
+
+    #[test]
+    fn test_balance_checkpoints() {
+        let (mut contract, farm_id) = setup_funded_farm(tiered_farm_input());
+        stake_at(&mut contract, farm_id, 100, 10);
+        stake_at(&mut contract, farm_id, 50, 20);
+        stake_in_tier_at(&mut contract, farm_id, accounts(2), 200, 0, 25);
+        // Changes within one block leave a single checkpoint.
+        stake_at(&mut contract, farm_id, 30, 30);
+        withdraw_at(&mut contract, farm_id, 10, 30);
+        withdraw_at(&mut contract, farm_id, 170, 40);
+        assert_eq!(contract.stake_checkpoints.get(&(accounts(1), farm_id)).unwrap().len(), 4);
+
+        let stake_history: Vec<u128> = [0, 9, 10, 15, 20, 29, 30, 39, 40, 1_000, u64::MAX]
+            .iter()
+            .map(|&time_sec| contract.stake_at(accounts(1), farm_id, time_sec).0)
+            .collect();
+        assert_eq!(stake_history, vec![0, 0, 100, 100, 150, 150, 170, 170, 0, 0, 0]);
+        assert_eq!(contract.stake_at(accounts(2), farm_id, 24).0, 0);
+        assert_eq!(contract.stake_at(accounts(2), farm_id, 25).0, 200);
+        assert_eq!(contract.stake_at(accounts(3), farm_id, 25).0, 0);
+
+        let total_history: Vec<u128> = [9, 10, 20, 25, 30, 40]
+            .iter()
+            .map(|&time_sec| contract.total_staked_at(farm_id, time_sec).0)
+            .collect();
+        assert_eq!(total_history, vec![0, 100, 150, 350, 370, 200]);
+        assert_eq!(contract.total_staked_at(farm_id + 1, 40).0, 0);
+    }
+
+    #[test]
+    fn test_emission_rate_and_remaining_duration() {
+        let (mut contract, farm_id) = setup_funded_farm(FarmInput {
+            emission_schedule: vec![EmissionStep {
+                from_sec: 50,
+                reward_per_session: vec![U128(200)],
+            }],
+            ..default_farm_input()
+        });
+        let rate_at = |contract: &ChildFarmingContract, time_sec: u64| {
+            testing_env!(get_context(accounts(1), time_sec * 1_000_000_000, 0).build());
+            contract.get_farm_emission_rate(farm_id).remove(0)
+        };
+        let remaining_at = |contract: &ChildFarmingContract, farm_id: u64, time_sec: u64| {
+            testing_env!(get_context(accounts(1), time_sec * 1_000_000_000, 0).build());
+            let view = contract.get_remaining_duration(farm_id).remove(0);
+            (view.remaining_reward.0, view.depletes_at_sec, view.remaining_sec)
+        };
+
+        let rate = rate_at(&contract, 0);
+        assert_eq!(rate.reward_token, "reward.token".parse::<AccountId>().unwrap());
+        assert_eq!(rate.reward_per_second.0, 10);
+        assert_eq!(rate.reward_per_second_per_token, None);
+        // 500 at 10/s until the step at 50s, then the other 500 at 20/s.
+        assert_eq!(remaining_at(&contract, farm_id, 0), (1_000, Some(75), Some(75)));
+
+        stake_at(&mut contract, farm_id, 100, 0);
+        let rate = rate_at(&contract, 20);
+        assert_eq!(rate.reward_per_second.0, 10);
+        // 10 per second shared by 100 tokens.
+        assert_eq!(rate.reward_per_second_per_token, Some((ACC_REWARD_MULTIPLIER / 10).to_string()));
+        assert_eq!(remaining_at(&contract, farm_id, 25), (800, Some(75), Some(50)));
+        assert_eq!(rate_at(&contract, 60).reward_per_second.0, 20);
+
+        // Once the pool is empty the farm ends and nothing is emitted.
+        assert_eq!(rate_at(&contract, 80).reward_per_second.0, 0);
+        assert_eq!(remaining_at(&contract, farm_id, 80), (0, None, Some(0)));
+
+        // A pool that outlasts the farm runs until the end time.
+        let context = get_context(accounts(0), 0, 0);
+        testing_env!(context.build());
+        let ending = contract.create_farm(FarmInput {
+            end_at_sec: Some(60),
+            ..default_farm_input()
+        });
+        testing_env!(get_context("reward.token".parse().unwrap(), 0, 0).build());
+        contract.ft_on_transfer(accounts(0), U128(1_000), format!("ADD_REWARD:{}", ending));
+        // Nobody is staked, so the first session was set aside.
+        assert_eq!(remaining_at(&contract, ending, 10), (900, None, Some(50)));
+    }
+
+    #[test]
+    fn test_estimate_rewards_matches_staking() {
+        let (mut contract, farm_id) = setup_staked_farm_with(tiered_farm_input());
+        let context = get_context(accounts(2), 0, 0);
+        testing_env!(context.build());
+        // Half of 3 sessions of 100.
+        assert_eq!(contract.estimate_rewards(farm_id, U128(100), 30, None), vec![U128(150)]);
+        // The pool of 1000 runs out after 100s.
+        assert_eq!(contract.estimate_rewards(farm_id, U128(100), 1_000, None), vec![U128(500)]);
+        assert_eq!(contract.estimate_rewards(farm_id, U128(0), 30, None), vec![U128(0)]);
+        let estimated = contract.estimate_rewards(farm_id, U128(100), 60, Some(0));
+        assert!(estimated[0].0 > 300);
+
+        stake_in_tier_at(&mut contract, farm_id, accounts(2), 100, 0, 0);
+        testing_env!(get_context(accounts(2), 30_000_000_000, 0).build());
+        contract.refresh_boost(accounts(2), farm_id);
+        assert_eq!(vec![U128(accrued_at(&contract, farm_id, accounts(2), 60))], estimated);
+
+        // The farm ends once the pool is empty.
+        testing_env!(get_context(accounts(2), 200_000_000_000, 0).build());
+        assert_eq!(contract.estimate_rewards(farm_id, U128(100), 30, None), vec![U128(0)]);
+    }
+
+    #[test]
+    fn test_farm_view_totals_and_live_view() {
+        let (mut contract, farm_id) = setup_staked_farm_with(default_farm_input());
+        let totals = |view: &view::FarmView| {
+            (
+                view.remaining_reward[0].0,
+                view.total_distributed[0].0,
+                view.total_claimed[0].0,
+            )
+        };
+        testing_env!(get_context(accounts(1), 25_000_000_000, 0).build());
+        let stored = contract.get_farm(farm_id).unwrap();
+        assert_eq!(totals(&stored), (1_000, 0, 0));
+        assert_eq!(stored.staker_count, 1);
+        // 1000 at 100 per 10s lasts until 100s.
+        assert_eq!(stored.end_estimate_sec, Some(100));
+        let live = contract.get_farm_live(farm_id).unwrap();
+        assert_eq!(totals(&live), (800, 200, 0));
+        assert_eq!(live.last_distribution_sec, 20);
+        assert_eq!(live.end_estimate_sec, Some(100));
+        assert!(contract.get_farm_live(farm_id + 1).is_none());
+
+        assert_eq!(claim_at(&mut contract, farm_id, &[30]), 300);
+        testing_env!(get_context(accounts(1), 30_000_000_000, 0).build());
+        assert_eq!(totals(&contract.get_farm(farm_id).unwrap()), (700, 300, 300));
+        assert_eq!(
+            totals(&contract.list_farms(0, 10)[0]),
+            totals(&contract.get_farm_live(farm_id).unwrap())
+        );
+
+        // A farm with an end time ends then, and an ended farm has no estimate.
+        testing_env!(get_context(accounts(0), 30_000_000_000, 0).build());
+        let ending = contract.create_farm(FarmInput {
+            end_at_sec: Some(60),
+            ..default_farm_input()
+        });
+        let view = contract.get_farm(ending).unwrap();
+        assert_eq!((view.staker_count, view.end_estimate_sec), (0, Some(60)));
+        testing_env!(get_context(accounts(1), 200_000_000_000, 0).build());
+        assert_eq!(contract.get_farm(farm_id).unwrap().end_estimate_sec, Some(100));
+        assert_eq!(contract.get_farm_live(farm_id).unwrap().end_estimate_sec, None);
+    }
 }
@@ -0,0 +1,199 @@
+use crate::*;
+
+impl ChildFarmingContract {
+    /// Splits the undistributed `remaining` rewards of one token between its funders.
+    /// Rewards are spent in the order they were deposited, so what is left belongs to
+    /// the latest deposits; anything older than the remembered deposits goes to the
+    /// farm creator. Payouts to the same account are merged.
+    pub(crate) fn split_remaining_reward(
+        remaining: u128,
+        fundings: &[(AccountId, u128)],
+        farm_creator: &AccountId,
+    ) -> Vec<(AccountId, u128)> {
+        let mut payouts: Vec<(AccountId, u128)> = Vec::new();
+        let mut pay = |account_id: &AccountId, amount: u128| {
+            match payouts.iter_mut().find(|(a, _)| a == account_id) {
+                Some((_, total)) => *total += amount,
+                None => payouts.push((account_id.clone(), amount)),
+            }
+        };
+        let mut left = remaining;
+        for (funder, funded) in fundings.iter().rev() {
+            if left == 0 {
+                break;
+            }
+            let amount = std::cmp::min(*funded, left);
+            pay(funder, amount);
+            left -= amount;
+        }
+        if left > 0 {
+            pay(farm_creator, left);
+        }
+        payouts
+    }
+}
+
+#[near_bindgen]
+impl ChildFarmingContract {
+    /// **(Creator or admin)** Ends a running or paused farm now. Rewards are
+    /// distributed up to this moment first; the rest can then be reclaimed.
+    #[payable]
+    pub fn end_farm(&mut self, farm_id: u64) {
+        near_sdk::assert_one_yocto();
+        let farm = self.internal_get_farm(farm_id).expect("Farm not found");
+        self.assert_farm_manager(&farm);
+        assert!(
+            farm.status.is_running() || farm.status == FarmStatus::Paused,
+            "Farm has already ended or been cancelled"
+        );
+
+        self.update_farm(farm_id);
+        let mut farm = self.internal_get_farm(farm_id).expect("Farm not found");
+        // `update_farm` may have ended it already by draining the pools.
+        if farm.status != FarmStatus::Ended {
+            farm.status = FarmStatus::Ended;
+            self.internal_save_farm(farm_id, &farm);
+            FarmEvent::FarmEnded { farm_id }.emit();
+        }
+    }
+
+    /// **(Creator or admin)** Cancels a farm that has not started yet. Its stakers
+    /// may withdraw without waiting for the lockup, and all rewards can be reclaimed.
+    #[payable]
+    pub fn cancel_farm(&mut self, farm_id: u64) {
+        near_sdk::assert_one_yocto();
+        let mut farm = self.internal_get_farm(farm_id).expect("Farm not found");
+        self.assert_farm_manager(&farm);
+        assert_eq!(
+            farm.status_at(env::block_timestamp()),
+            FarmStatus::Scheduled,
+            "Only a farm that has not started can be cancelled"
+        );
+        farm.status = FarmStatus::Cancelled;
+        self.internal_save_farm(farm_id, &farm);
+        FarmEvent::FarmCancelled { farm_id }.emit();
+    }
+
+    /// **(Creator or admin)** Stops emission after distributing up to now. Unlike
+    /// `pause`, which blocks user actions, this only stops rewards from accruing:
+    /// new stakes are rejected, while claims and withdrawals keep working.
+    #[payable]
+    pub fn pause_farm(&mut self, farm_id: u64) {
+        near_sdk::assert_one_yocto();
+        let farm = self.internal_get_farm(farm_id).expect("Farm not found");
+        self.assert_farm_manager(&farm);
+        assert!(farm.status.is_running(), "Only a running farm can be paused");
+
+        self.update_farm(farm_id);
+        let mut farm = self.internal_get_farm(farm_id).expect("Farm not found");
+        if farm.status == FarmStatus::Ended {
+            return;
+        }
+        farm.status = FarmStatus::Paused;
+        self.internal_save_farm(farm_id, &farm);
+        FarmEvent::FarmPaused { farm_id }.emit();
+    }
+
+    /// **(Creator or admin)** Restarts emission of a paused farm. The time it was
+    /// paused is not emitted.
+    #[payable]
+    pub fn resume_farm(&mut self, farm_id: u64) {
+        near_sdk::assert_one_yocto();
+        let mut farm = self.internal_get_farm(farm_id).expect("Farm not found");
+        self.assert_farm_manager(&farm);
+        assert_eq!(farm.status, FarmStatus::Paused, "Farm is not paused");
+
+        let now = env::block_timestamp();
+        farm.last_distribution = std::cmp::max(now, farm.start_time);
+        farm.status = if now < farm.start_time {
+            FarmStatus::Scheduled
+        } else {
+            FarmStatus::Active
+        };
+        self.internal_save_farm(farm_id, &farm);
+        FarmEvent::FarmResumed { farm_id }.emit();
+    }
+
+    /// **(Creator or admin)** Sends the rewards that were never distributed by an
+    /// ended or cancelled farm back to the accounts that deposited them. Each
+    /// transfer is resolved by `on_reclaim_resolved`.
+    #[payable]
+    pub fn reclaim_remaining_rewards(&mut self, farm_id: u64) {
+        near_sdk::assert_one_yocto();
+        let farm = self.internal_get_farm(farm_id).expect("Farm not found");
+        self.assert_farm_manager(&farm);
+        // A farm past its end time is only marked as ended by its final distribution.
+        self.update_farm(farm_id);
+        let mut farm = self.internal_get_farm(farm_id).expect("Farm not found");
+        assert!(
+            farm.status == FarmStatus::Ended || farm.status == FarmStatus::Cancelled,
+            "Rewards can only be reclaimed after the farm has ended or been cancelled"
+        );
+
+        let initial_storage = self.billable_storage_usage();
+        for i in 0..farm.reward_tokens.len() {
+            let remaining = std::mem::take(&mut farm.remaining_reward[i])
+                .saturating_add(std::mem::take(&mut farm.undistributed_reward[i]));
+            let fundings = std::mem::take(&mut farm.reward_funders[i]);
+            let reward_token = farm.reward_tokens[i].clone();
+            for (account_id, amount) in Self::split_remaining_reward(remaining, &fundings, &farm.farm_creator) {
+                Self::ft_transfer(reward_token.clone(), &account_id, amount).then(
+                    Self::ext(env::current_account_id())
+                        .with_static_gas(GAS_FOR_RESOLVE_TRANSFER)
+                        .on_reclaim_resolved(farm_id, reward_token.clone(), account_id.clone(), U128(amount)),
+                );
+                FarmEvent::RewardsReclaimed {
+                    farm_id,
+                    account_id,
+                    reward_token: reward_token.clone(),
+                    amount: U128(amount),
+                }
+                .emit();
+            }
+        }
+        self.internal_save_farm(farm_id, &farm);
+        self.internal_track_storage(&farm.farm_creator, initial_storage);
+    }
+
+    /// Private callback after a transfer issued by `reclaim_remaining_rewards`.
+    /// On failure the amount goes back into the pool, still owed to `account_id`.
+    /// Returns the amount that actually left the contract.
+    #[private]
+    pub fn on_reclaim_resolved(
+        &mut self,
+        farm_id: u64,
+        reward_token: AccountId,
+        account_id: AccountId,
+        amount: U128,
+    ) -> U128 {
+        assert_eq!(
+            env::promise_results_count(),
+            1,
+            "Expected one promise result."
+        );
+        if let PromiseResult::Successful(_) = env::promise_result(0) {
+            return amount;
+        }
+
+        let mut farm = self.internal_get_farm(farm_id).expect("Farm not found");
+        let pos = farm
+            .reward_tokens
+            .iter()
+            .position(|t| t == &reward_token)
+            .expect("This token is not a valid reward token for the farm.");
+        let initial_storage = self.billable_storage_usage();
+        farm.remaining_reward[pos] = farm.remaining_reward[pos].saturating_add(amount.0);
+        farm.reward_funders[pos].push((account_id.clone(), amount.0));
+        self.internal_save_farm(farm_id, &farm);
+        self.internal_track_storage(&farm.farm_creator, initial_storage);
+
+        FarmEvent::ReclaimReverted {
+            farm_id,
+            account_id,
+            reward_token,
+            amount,
+        }
+        .emit();
+        U128(0)
+    }
+}
@@ -0,0 +1,389 @@
+use crate::*;
+
+/// Locked positions a stake may hold at once. Positions that have unlocked are
+/// merged into one, so this only limits stakes that are still locked.
+pub const MAX_LOCK_POSITIONS: usize = 16;
+
+/// Lock tiers a farm may offer.
+pub const MAX_LOCK_TIERS: usize = 8;
+
+/// Reward weight of a staked token, in basis points, when it is not boosted.
+pub const BASE_MULTIPLIER_BPS: u32 = 10_000;
+
+/// Highest reward weight a lock tier may give, in basis points (10x).
+pub const MAX_MULTIPLIER_BPS: u32 = 100_000;
+
+/// Storage prefix of the boosts of a farm yet to expire, by unlock time.
+fn boost_expiries_prefix(farm_id: u64) -> Vec<u8> {
+    let mut prefix = b"be".to_vec();
+    prefix.extend(farm_id.to_le_bytes());
+    prefix
+}
+
+/// Boosts that have expired in their farm's totals. They are kept until every
+/// stake that still counts them has settled.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct ExpiredBoost {
+    /// `reward_per_share` of the farm when the boosts expired.
+    pub reward_per_share: Vec<U256>,
+    /// Extra reward weight of the positions that still count the boosts.
+    pub weight: u128,
+}
+
+/// Tokens added to a stake at `start`, locked until `unlock_at` (nanoseconds).
+/// Until then, each token weighs `multiplier_bps / BASE_MULTIPLIER_BPS` in the
+/// reward distribution.
+#[derive(BorshDeserialize, BorshSerialize, Clone, Debug, PartialEq, Eq)]
+pub struct LockPosition {
+    pub amount: u128,
+    pub start: u64,
+    pub unlock_at: u64,
+    pub multiplier_bps: u32,
+}
+
+impl LockPosition {
+    /// The weight of this position in the reward distribution.
+    pub fn effective_amount(&self) -> u128 {
+        // Split so that `amount * multiplier_bps` cannot overflow.
+        let (multiplier, base) = (self.multiplier_bps as u128, BASE_MULTIPLIER_BPS as u128);
+        (self.amount / base * multiplier).saturating_add(self.amount % base * multiplier / base)
+    }
+
+    /// The weight this position's boost adds to its amount.
+    pub fn boost_weight(&self) -> u128 {
+        self.effective_amount().saturating_sub(self.amount)
+    }
+}
+
+/// One lock tier of a farm: stakes locked for at least `duration_sec` earn rewards
+/// with a weight of `multiplier_bps` (10_000 being 1x).
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LockTier {
+    pub duration_sec: u64,
+    pub multiplier_bps: u32,
+}
+
+impl From<&(u64, u32)> for LockTier {
+    fn from((duration, multiplier_bps): &(u64, u32)) -> Self {
+        LockTier {
+            duration_sec: duration / 1_000_000_000,
+            multiplier_bps: *multiplier_bps,
+        }
+    }
+}
+
+/// Checks `tiers` and converts them to `(duration, multiplier_bps)`, with the
+/// duration in nanoseconds.
+pub(crate) fn parse_lock_tiers(tiers: &[LockTier]) -> Vec<(u64, u32)> {
+    assert!(
+        tiers.len() <= MAX_LOCK_TIERS,
+        "Cannot have more than {} lock tiers",
+        MAX_LOCK_TIERS
+    );
+    let mut previous = 0;
+    tiers
+        .iter()
+        .map(|tier| {
+            assert!(
+                tier.duration_sec > previous,
+                "Lock tiers must be in increasing order of duration"
+            );
+            assert!(
+                (BASE_MULTIPLIER_BPS..=MAX_MULTIPLIER_BPS).contains(&tier.multiplier_bps),
+                "Lock tier multipliers must be between {} and {} bps",
+                BASE_MULTIPLIER_BPS,
+                MAX_MULTIPLIER_BPS
+            );
+            previous = tier.duration_sec;
+            (tier.duration_sec * 1_000_000_000, tier.multiplier_bps)
+        })
+        .collect()
+}
+
+impl StakeInfo {
+    /// Adds `amount` staked at `now` and locked until `unlock_at` with a weight of
+    /// `multiplier_bps`. Positions unlocked at `now` lose their boost and are merged
+    /// into one first, so the stake must have been settled. The new tokens join that
+    /// position if they are not locked, or else the position with the same unlock
+    /// time and weight, if any.
+    pub(crate) fn add_position(
+        &mut self,
+        farm: &mut FarmParams,
+        amount: u128,
+        now: u64,
+        unlock_at: u64,
+        multiplier_bps: u32,
+    ) {
+        self.merge_unlocked_positions(now);
+        let unlock_at = std::cmp::max(unlock_at, now);
+        let multiplier_bps = if unlock_at > now { multiplier_bps } else { BASE_MULTIPLIER_BPS };
+        let pos = self.positions.partition_point(|p| p.unlock_at < unlock_at);
+        let existing = if unlock_at == now {
+            self.positions.first_mut().filter(|p| p.unlock_at <= now)
+        } else {
+            self.positions[pos..]
+                .iter_mut()
+                .take_while(|p| p.unlock_at == unlock_at)
+                .find(|p| p.multiplier_bps == multiplier_bps)
+        };
+        match existing {
+            Some(position) => position.amount = position.amount.saturating_add(amount),
+            None => self.positions.insert(
+                pos,
+                LockPosition {
+                    amount,
+                    start: now,
+                    unlock_at,
+                    multiplier_bps,
+                },
+            ),
+        }
+        self.update_totals(farm);
+    }
+
+    /// Takes `amount` out of the stake, from the positions that unlock first.
+    /// Unless `ignore_lockup` is set, only positions unlocked at `now` are used.
+    pub(crate) fn release(&mut self, farm: &mut FarmParams, amount: u128, now: u64, ignore_lockup: bool) {
+        let available = if ignore_lockup { self.amount } else { self.unlocked_amount(now) };
+        assert!(available >= amount, "Lockup period not expired");
+        let mut left = amount;
+        for position in self.positions.iter_mut() {
+            let taken = std::cmp::min(position.amount, left);
+            position.amount -= taken;
+            left -= taken;
+        }
+        self.positions.retain(|p| p.amount > 0);
+        self.update_totals(farm);
+    }
+
+    /// Drops the boost of the positions unlocked at `now`. The stake must have
+    /// been settled, as its weight changes.
+    pub(crate) fn expire_boosts(&mut self, farm: &mut FarmParams, now: u64) {
+        for position in self.positions.iter_mut().filter(|p| p.unlock_at <= now) {
+            position.multiplier_bps = BASE_MULTIPLIER_BPS;
+        }
+        self.update_totals(farm);
+    }
+
+    /// Settles up to `reward_per_share`, the farm's as the boosts unlocking at
+    /// `unlock_at` expired, and drops those boosts. The farm's totals no longer
+    /// count them, so they are left alone.
+    pub(crate) fn drop_expired_boost(&mut self, unlock_at: u64, reward_per_share: &[U256]) {
+        self.settle_to(reward_per_share);
+        for position in self.positions.iter_mut().filter(|p| p.unlock_at == unlock_at) {
+            self.effective_amount -= position.boost_weight();
+            position.multiplier_bps = BASE_MULTIPLIER_BPS;
+        }
+    }
+
+    /// The weight added by boosts, by unlock time in increasing order.
+    pub(crate) fn boosts(&self) -> Vec<(u64, u128)> {
+        let mut boosts: Vec<(u64, u128)> = vec![];
+        for position in self.positions.iter().filter(|p| p.multiplier_bps > BASE_MULTIPLIER_BPS) {
+            match boosts.last_mut() {
+                Some((unlock_at, weight)) if *unlock_at == position.unlock_at => *weight += position.boost_weight(),
+                _ => boosts.push((position.unlock_at, position.boost_weight())),
+            }
+        }
+        boosts
+    }
+
+    /// Staked tokens whose lockup has expired at `now`.
+    pub fn unlocked_amount(&self, now: u64) -> u128 {
+        self.positions
+            .iter()
+            .filter(|p| p.unlock_at <= now)
+            .map(|p| p.amount)
+            .sum()
+    }
+
+    /// Staked tokens still locked at `now`.
+    pub fn locked_amount(&self, now: u64) -> u128 {
+        self.amount - self.unlocked_amount(now)
+    }
+
+    /// When the last position unlocks, or 0 for an empty stake.
+    pub fn lockup_end(&self) -> u64 {
+        self.positions.iter().map(|p| p.unlock_at).max().unwrap_or(0)
+    }
+
+    fn merge_unlocked_positions(&mut self, now: u64) {
+        let unlocked = self.positions.partition_point(|p| p.unlock_at <= now);
+        if unlocked == 0 {
+            return;
+        }
+        let merged = self
+            .positions
+            .drain(..unlocked)
+            .reduce(|merged, p| LockPosition {
+                amount: merged.amount.saturating_add(p.amount),
+                start: std::cmp::min(merged.start, p.start),
+                ..p
+            })
+            .unwrap();
+        self.positions.insert(
+            0,
+            LockPosition {
+                multiplier_bps: BASE_MULTIPLIER_BPS,
+                ..merged
+            },
+        );
+    }
+
+    /// Recomputes `amount` and `effective_amount` from the positions and applies
+    /// the difference to the farm's totals.
+    fn update_totals(&mut self, farm: &mut FarmParams) {
+        let amount: u128 = self.positions.iter().map(|p| p.amount).sum();
+        let effective_amount: u128 = self.positions.iter().map(LockPosition::effective_amount).sum();
+        farm.total_staked = farm.total_staked.saturating_sub(self.amount).saturating_add(amount);
+        farm.total_effective_staked = farm
+            .total_effective_staked
+            .saturating_sub(self.effective_amount)
+            .saturating_add(effective_amount);
+        self.amount = amount;
+        self.effective_amount = effective_amount;
+    }
+}
+
+impl ChildFarmingContract {
+    /// Brings `farm` up to `now`, expiring the boosts that unlock on the way at their
+    /// unlock time. Returns whether the farm ended.
+    pub(crate) fn internal_update_farm_to(&mut self, farm_id: u64, farm: &mut FarmParams, now: u64) -> bool {
+        let expiring = self.internal_expiring_boosts(farm_id, now);
+        let (ended, expired_at) = farm.update_expiring(now, &expiring);
+        if expiring.is_empty() {
+            return ended;
+        }
+        let initial_storage = env::storage_usage();
+        let mut expiries = self.boost_expiries.get(&farm_id).unwrap();
+        for ((unlock_at, weight), reward_per_share) in expiring.into_iter().zip(expired_at) {
+            expiries.remove(&unlock_at);
+            self.expired_boosts
+                .insert(&(farm_id, unlock_at), &ExpiredBoost { reward_per_share, weight });
+        }
+        if expiries.is_empty() {
+            self.boost_expiries.remove(&farm_id);
+        } else {
+            self.boost_expiries.insert(&farm_id, &expiries);
+        }
+        self.internal_track_history_storage(initial_storage);
+        ended
+    }
+
+    /// `farm` as `internal_update_farm_to` would leave it, for views. Also returns
+    /// the `reward_per_share` as each boost expired on the way.
+    pub(crate) fn internal_farm_updated_to(
+        &self,
+        farm_id: u64,
+        farm: &FarmParams,
+        now: u64,
+    ) -> (FarmParams, Vec<(u64, Vec<U256>)>) {
+        let expiring = self.internal_expiring_boosts(farm_id, now);
+        let mut farm = farm.clone();
+        let (_, expired_at) = farm.update_expiring(now, &expiring);
+        let expired = expiring.into_iter().map(|(unlock_at, _)| unlock_at).zip(expired_at).collect();
+        (farm, expired)
+    }
+
+    /// Boosts of `farm_id` due to expire by `now`, as `(unlock_at, weight)` in order of time.
+    fn internal_expiring_boosts(&self, farm_id: u64, now: u64) -> Vec<(u64, u128)> {
+        self.boost_expiries.get(&farm_id).map_or_else(Vec::new, |expiries| {
+            expiries.iter().take_while(|(unlock_at, _)| *unlock_at <= now).collect()
+        })
+    }
+
+    /// Settles `stake_info` in `farm`, which must be up to date. The boosts that have
+    /// expired in the farm earn up to their expiry and are dropped first.
+    pub(crate) fn internal_settle(&self, farm_id: u64, farm: &FarmParams, stake_info: &mut StakeInfo) {
+        self.internal_drop_expired_boosts(farm_id, stake_info, &[]);
+        stake_info.settle(farm);
+    }
+
+    /// Drops the boosts of `stake_info` that have expired in `farm_id`, either stored
+    /// or in `expired` from `internal_farm_updated_to`.
+    pub(crate) fn internal_drop_expired_boosts(
+        &self,
+        farm_id: u64,
+        stake_info: &mut StakeInfo,
+        expired: &[(u64, Vec<U256>)],
+    ) {
+        for (unlock_at, _) in stake_info.boosts() {
+            let reward_per_share = expired
+                .iter()
+                .find(|(expired_at, _)| *expired_at == unlock_at)
+                .map(|(_, reward_per_share)| reward_per_share.clone())
+                .or_else(|| {
+                    self.expired_boosts
+                        .get(&(farm_id, unlock_at))
+                        .map(|boost| boost.reward_per_share)
+                });
+            if let Some(reward_per_share) = reward_per_share {
+                stake_info.drop_expired_boost(unlock_at, &reward_per_share);
+            }
+        }
+    }
+
+    /// Moves a stake's boosts in `farm_id` from `previous` to `current`, both from
+    /// `StakeInfo::boosts`, in the boosts yet to expire or the expired ones they
+    /// belong to. Like checkpoints, this bookkeeping is paid for once.
+    pub(crate) fn internal_index_boosts(&mut self, farm_id: u64, previous: &[(u64, u128)], current: &[(u64, u128)]) {
+        if previous == current {
+            return;
+        }
+        let initial_storage = env::storage_usage();
+        let mut expiries = self
+            .boost_expiries
+            .get(&farm_id)
+            .unwrap_or_else(|| TreeMap::new(boost_expiries_prefix(farm_id)));
+        let weight_at = |boosts: &[(u64, u128)], unlock_at: u64| {
+            boosts.iter().find(|(t, _)| *t == unlock_at).map_or(0, |(_, weight)| *weight)
+        };
+        let mut unlock_times: Vec<u64> = previous.iter().chain(current).map(|(t, _)| *t).collect();
+        unlock_times.sort_unstable();
+        unlock_times.dedup();
+        for unlock_at in unlock_times {
+            let (before, after) = (weight_at(previous, unlock_at), weight_at(current, unlock_at));
+            if after > before {
+                // Boosts only grow on positions still locked, so they have not expired.
+                let weight = expiries.get(&unlock_at).unwrap_or(0);
+                expiries.insert(&unlock_at, &(weight + (after - before)));
+            } else if let Some(weight) = expiries.get(&unlock_at) {
+                let weight = weight.saturating_sub(before - after);
+                if weight == 0 {
+                    expiries.remove(&unlock_at);
+                } else {
+                    expiries.insert(&unlock_at, &weight);
+                }
+            } else if let Some(mut expired) = self.expired_boosts.get(&(farm_id, unlock_at)) {
+                expired.weight = expired.weight.saturating_sub(before - after);
+                if expired.weight == 0 {
+                    self.expired_boosts.remove(&(farm_id, unlock_at));
+                } else {
+                    self.expired_boosts.insert(&(farm_id, unlock_at), &expired);
+                }
+            }
+        }
+        if expiries.is_empty() {
+            self.boost_expiries.remove(&farm_id);
+        } else {
+            self.boost_expiries.insert(&farm_id, &expiries);
+        }
+        self.internal_track_history_storage(initial_storage);
+    }
+}
+
+#[near_bindgen]
+impl ChildFarmingContract {
+    /// Settles `account_id`'s stake in `farm_id`, dropping the boosts of its positions
+    /// that have unlocked. Boosts stop earning at their unlock time regardless; this
+    /// only clears them from the stake's own bookkeeping.
+    pub fn refresh_boost(&mut self, account_id: AccountId, farm_id: u64) {
+        self.update_farm(farm_id);
+        let farm = self.internal_get_farm(farm_id).expect("Farm not found");
+        let stake_key = (account_id, farm_id);
+        let mut stake_info = self.internal_get_stake(&stake_key).expect("No stake found");
+        self.internal_settle(farm_id, &farm, &mut stake_info);
+        self.internal_save_stake(&stake_key, &stake_info);
+    }
+}
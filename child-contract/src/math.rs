@@ -0,0 +1,54 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::env;
+use std::convert::TryFrom;
+
+mod u256 {
+    // The code generated by `construct_uint!` does not follow our lints.
+    #![allow(clippy::all)]
+
+    uint::construct_uint! {
+        /// 256-bit unsigned integer used for `reward_per_share` and reward debts.
+        pub struct U256(4);
+    }
+}
+
+pub use u256::U256;
+
+/// Fixed-point scale of `reward_per_share`. It is large enough that rewards
+/// with few decimals still register against stakes with many.
+pub const ACC_REWARD_MULTIPLIER: u128 = 1_000_000_000_000_000_000_000_000_000_000_000_000;
+
+impl BorshSerialize for U256 {
+    fn serialize<W: borsh::io::Write>(&self, writer: &mut W) -> borsh::io::Result<()> {
+        self.0.serialize(writer)
+    }
+}
+
+impl BorshDeserialize for U256 {
+    fn deserialize_reader<R: borsh::io::Read>(reader: &mut R) -> borsh::io::Result<Self> {
+        Ok(U256(<[u64; 4]>::deserialize_reader(reader)?))
+    }
+}
+
+/// Growth of `reward_per_share` when `amount` is shared by `total_staked` tokens.
+/// Cannot overflow: `u128::MAX * ACC_REWARD_MULTIPLIER` fits into 256 bits.
+pub fn reward_per_share_increment(amount: u128, total_staked: u128) -> U256 {
+    U256::from(amount) * U256::from(ACC_REWARD_MULTIPLIER) / U256::from(total_staked)
+}
+
+/// Reward earned by `amount` staked tokens while `reward_per_share` grew by `per_share`.
+/// Panics if the result does not fit into a `u128`.
+pub fn reward_for(amount: u128, per_share: U256) -> u128 {
+    let reward = U256::from(amount)
+        .checked_mul(per_share)
+        .unwrap_or_else(|| env::panic_str("Reward calculation overflow"))
+        / U256::from(ACC_REWARD_MULTIPLIER);
+    u128::try_from(reward).unwrap_or_else(|_| env::panic_str("Reward calculation overflow"))
+}
+
+/// `reward_per_share` after growing by `increment`. Panics on overflow.
+pub fn add_reward_per_share(reward_per_share: U256, increment: U256) -> U256 {
+    reward_per_share
+        .checked_add(increment)
+        .unwrap_or_else(|| env::panic_str("Reward per share overflow"))
+}
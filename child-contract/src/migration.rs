@@ -0,0 +1,250 @@
+use crate::*;
+use near_sdk::json_types::Base64VecU8;
+use near_sdk::GasWeight;
+
+/// `reward_per_share` was scaled by 10^12 before it became a 256-bit number.
+const V1_REWARD_MULTIPLIER: u128 = 1_000_000_000_000;
+
+/// Rescales a V1 `reward_per_share` or reward debt to `math::ACC_REWARD_MULTIPLIER`.
+fn rescale_v1_reward_per_share(values: Vec<u128>) -> Vec<U256> {
+    values
+        .into_iter()
+        .map(|value| U256::from(value) * U256::from(math::ACC_REWARD_MULTIPLIER / V1_REWARD_MULTIPLIER))
+        .collect()
+}
+
+/// `FarmParams` as written before state versioning, without `farm_creator`.
+#[derive(BorshDeserialize, BorshSerialize, Clone)]
+pub struct FarmParamsV1 {
+    pub staking_token: AccountId,
+    pub reward_tokens: Vec<AccountId>,
+    pub reward_per_session: Vec<u128>,
+    pub session_interval: u64,
+    pub start_time: u64,
+    pub last_distribution: u64,
+    pub total_staked: u128,
+    pub reward_per_share: Vec<u128>,
+    pub lockup_period: u64,
+    pub remaining_reward: Vec<u128>,
+    pub status: FarmStatus,
+}
+
+impl FarmParamsV1 {
+    /// V1 farms did not record who created them, so they are attributed to `admin`.
+    /// The admin may thus manage them as their creator. V1 kept no record of reward
+    /// deposits either, so rewards reclaimed from a V1 farm by
+    /// `reclaim_remaining_rewards` are sent to the admin.
+    pub fn into_current(self, admin: AccountId) -> FarmParams {
+        let num_rewards = self.reward_tokens.len();
+        FarmParams {
+            staking_token: self.staking_token,
+            reward_tokens: self.reward_tokens,
+            reward_per_session: self.reward_per_session,
+            session_interval: self.session_interval,
+            start_time: self.start_time,
+            last_distribution: self.last_distribution,
+            total_staked: self.total_staked,
+            reward_per_share: rescale_v1_reward_per_share(self.reward_per_share),
+            lockup_period: self.lockup_period,
+            remaining_reward: self.remaining_reward,
+            status: self.status,
+            farm_creator: admin,
+            early_exit_penalty_bps: 0,
+            penalty_destination: PenaltyDestination::Burn,
+            burned_penalty: 0,
+            reward_funders: vec![vec![]; num_rewards],
+            end_time: None,
+            emission_schedule: vec![],
+            distribution_mode: DistributionMode::Session,
+            emission_carry: vec![0; num_rewards],
+            idle_reward_policy: IdleRewardPolicy::ReturnToFunder,
+            undistributed_reward: vec![0; num_rewards],
+            unfunded_emission: vec![0; num_rewards],
+            total_distributed: vec![0; num_rewards],
+            total_claimed: vec![0; num_rewards],
+            lock_tiers: vec![],
+            total_effective_staked: self.total_staked,
+        }
+    }
+}
+
+/// `StakeInfo` as written before `reward_debt` became a 256-bit number and stakes
+/// were split into lock positions.
+#[derive(BorshDeserialize, BorshSerialize, Clone)]
+pub struct StakeInfoV1 {
+    pub amount: u128,
+    pub lockup_end: u64,
+    pub reward_debt: Vec<u128>,
+    pub accrued_rewards: Vec<u128>,
+}
+
+impl StakeInfoV1 {
+    /// The whole V1 stake becomes a single lock position. When it was staked is
+    /// not known, so the position starts at 0.
+    pub fn into_current(self) -> StakeInfo {
+        let positions = if self.amount > 0 {
+            vec![LockPosition {
+                amount: self.amount,
+                start: 0,
+                unlock_at: self.lockup_end,
+                multiplier_bps: BASE_MULTIPLIER_BPS,
+            }]
+        } else {
+            vec![]
+        };
+        StakeInfo {
+            amount: self.amount,
+            effective_amount: self.amount,
+            positions,
+            reward_debt: rescale_v1_reward_per_share(self.reward_debt),
+            accrued_rewards: self.accrued_rewards,
+            auto_compound: false,
+        }
+    }
+}
+
+/// Contract state as written before state versioning (state version 1).
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct ChildFarmingContractV1 {
+    pub farms: UnorderedMap<u64, FarmParamsV1>,
+    pub stakes: UnorderedMap<(AccountId, u64), StakeInfoV1>,
+    pub farm_count: u64,
+    pub storage_deposits: UnorderedMap<AccountId, Balance>,
+    pub admin: AccountId,
+}
+
+impl ChildFarmingContract {
+    /// Bills the bytes written since `initial_storage` to `account_id` if it is still
+    /// registered. The bytes of accounts that are not stay with the contract.
+    fn internal_track_migrated_storage(&mut self, account_id: &AccountId, initial_storage: u64) {
+        if self.storage_deposits.get(account_id).is_some() {
+            self.internal_track_storage(account_id, initial_storage);
+        }
+    }
+}
+
+/// Rewrites every entry of a V1 map under the same storage prefix with a new value type.
+fn rewrite_map<K, V, W>(
+    mut old: UnorderedMap<K, V>,
+    prefix: &[u8],
+    convert: impl Fn(V) -> W,
+) -> UnorderedMap<K, W>
+where
+    K: BorshSerialize + BorshDeserialize,
+    V: BorshSerialize + BorshDeserialize,
+    W: BorshSerialize + BorshDeserialize,
+{
+    let entries = old.to_vec();
+    old.clear();
+    let mut new = UnorderedMap::new(prefix.to_vec());
+    for (key, value) in entries {
+        new.insert(&key, &convert(value));
+    }
+    new
+}
+
+#[near_bindgen]
+impl ChildFarmingContract {
+    /// Upgrades the stored state to `CURRENT_STATE_VERSION`. Call it right after
+    /// deploying new code; it is a no-op if the state is already current.
+    ///
+    /// Version 1 entries are rewritten with a layout tag in a single call, so the
+    /// number of farms and stakes must fit into the gas attached to it. Tagged
+    /// farms are only converted to the current layout when they are next read.
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let raw_state = env::storage_read(b"STATE").expect("Contract is not initialized");
+        if let Ok(contract) = Self::try_from_slice(&raw_state) {
+            assert_eq!(
+                contract.state_version, CURRENT_STATE_VERSION,
+                "Unknown state version"
+            );
+            return contract;
+        }
+        let old = ChildFarmingContractV1::try_from_slice(&raw_state)
+            .unwrap_or_else(|_| env::panic_str("Unknown state layout"));
+        Self::migrate_from_v1(old)
+    }
+
+    /// **(Master or admin)** Deploys `code` to this account and then calls `migrate`
+    /// with `migrate_args` (defaults to `{}`), handing it all the remaining gas.
+    /// The master is the parent account this child was deployed under.
+    pub fn upgrade(&mut self, code: Base64VecU8, migrate_args: Option<Base64VecU8>) -> Promise {
+        self.assert_upgrade_authority();
+        let migrate_args = migrate_args.map(|args| args.0).unwrap_or_else(|| b"{}".to_vec());
+        Promise::new(env::current_account_id())
+            .deploy_contract(code.0)
+            .function_call_weight(
+                "migrate".to_string(),
+                migrate_args,
+                NearToken::from_yoctonear(0),
+                Gas::from_gas(0),
+                GasWeight(1),
+            )
+    }
+
+    fn migrate_from_v1(mut old: ChildFarmingContractV1) -> Self {
+        let farms = old.farms.to_vec();
+        old.farms.clear();
+        let stakes = old.stakes.to_vec();
+        old.stakes.clear();
+        let storage_deposits = rewrite_map(old.storage_deposits, b"storage_deposits", |deposit| {
+            AccountStorage {
+                deposit,
+                used_bytes: 0,
+            }
+        });
+
+        let admin = old.admin;
+        let mut contract = Self {
+            farms: UnorderedMap::new(b"farms".to_vec()),
+            stakes: UnorderedMap::new(b"stakes".to_vec()),
+            farm_count: old.farm_count,
+            storage_deposits,
+            admin: admin.clone(),
+            account_storage_usage: 0,
+            pending_admin: None,
+            farm_creators: UnorderedSet::new(b"farm_creators".to_vec()),
+            paused_actions: 0,
+            paused_farms: UnorderedMap::new(b"paused_farms".to_vec()),
+            state_version: CURRENT_STATE_VERSION,
+            user_farms: LookupMap::new(b"user_farms".to_vec()),
+            farm_stakers: LookupMap::new(b"farm_stakers".to_vec()),
+            farm_rankings: LookupMap::new(b"farm_rankings".to_vec()),
+            stake_checkpoints: LookupMap::new(b"stake_checkpoints".to_vec()),
+            total_staked_checkpoints: LookupMap::new(b"total_staked_checkpoints".to_vec()),
+            boost_expiries: LookupMap::new(b"boost_expiries".to_vec()),
+            expired_boosts: LookupMap::new(b"expired_boosts".to_vec()),
+            history_storage_usage: 0,
+            unpaid_history_bytes: 0,
+        };
+        // V1 let anyone create farms without recording who did, so their bytes stay
+        // with the contract.
+        for (farm_id, farm) in farms {
+            contract.farms.insert(&farm_id, &VersionedFarmParams::V1(farm));
+        }
+        // V1 did not measure the bytes each account uses, so stakes are measured while
+        // they are written back, and billed with their index entries to their owners.
+        for ((account_id, farm_id), stake) in &stakes {
+            let initial_storage = contract.billable_storage_usage();
+            contract.stakes.insert(&(account_id.clone(), *farm_id), &VersionedStakeInfo::V1(stake.clone()));
+            contract.internal_index_stake(account_id, *farm_id);
+            contract.internal_rank_stake(account_id, *farm_id, 0, stake.amount);
+            contract.internal_track_migrated_storage(account_id, initial_storage);
+        }
+        // V1 kept no history, so the balance history starts with the migration. The
+        // contract pays for it.
+        for ((account_id, farm_id), stake) in &stakes {
+            contract.internal_checkpoint_stake(account_id, *farm_id, stake.amount);
+        }
+        for farm_id in 0..contract.farm_count {
+            if let Some(farm) = contract.internal_get_farm(farm_id) {
+                contract.internal_checkpoint_total_staked(farm_id, farm.total_staked);
+            }
+        }
+        contract.unpaid_history_bytes = 0;
+        contract.measure_account_storage_usage();
+        contract
+    }
+}
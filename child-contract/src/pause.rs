@@ -0,0 +1,114 @@
+use crate::*;
+
+/// User actions that can be paused, globally or per farm.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum PausableAction {
+    Stake,
+    Claim,
+    Withdraw,
+    AddReward,
+}
+
+impl PausableAction {
+    pub const ALL: [PausableAction; 4] = [
+        PausableAction::Stake,
+        PausableAction::Claim,
+        PausableAction::Withdraw,
+        PausableAction::AddReward,
+    ];
+
+    fn bit(self) -> u8 {
+        match self {
+            PausableAction::Stake => 1,
+            PausableAction::Claim => 1 << 1,
+            PausableAction::Withdraw => 1 << 2,
+            PausableAction::AddReward => 1 << 3,
+        }
+    }
+
+    /// Packs `actions` into a bit mask; `None` means every action.
+    pub(crate) fn to_mask(actions: Option<&[PausableAction]>) -> u8 {
+        actions
+            .unwrap_or(&Self::ALL)
+            .iter()
+            .fold(0, |mask, action| mask | action.bit())
+    }
+
+    pub(crate) fn from_mask(mask: u8) -> Vec<PausableAction> {
+        Self::ALL
+            .iter()
+            .copied()
+            .filter(|action| mask & action.bit() != 0)
+            .collect()
+    }
+}
+
+impl ChildFarmingContract {
+    /// Actions paused for `farm_id`, including the globally paused ones.
+    pub(crate) fn paused_mask(&self, farm_id: u64) -> u8 {
+        self.paused_actions | self.paused_farms.get(&farm_id).unwrap_or(0)
+    }
+
+    pub(crate) fn is_paused(&self, farm_id: u64, action: PausableAction) -> bool {
+        self.paused_mask(farm_id) & action.bit() != 0
+    }
+
+    pub(crate) fn assert_not_paused(&self, farm_id: u64, action: PausableAction) {
+        if self.is_paused(farm_id, action) {
+            env::panic_str(format!("{:?} is paused for farm {}", action, farm_id).as_str());
+        }
+    }
+}
+
+#[near_bindgen]
+impl ChildFarmingContract {
+    /// **(Admin or master)** Pauses `actions` (all of them if omitted) for one farm,
+    /// or for every farm when `farm_id` is omitted. Pausing is cumulative.
+    #[payable]
+    pub fn pause(&mut self, farm_id: Option<u64>, actions: Option<Vec<PausableAction>>) {
+        near_sdk::assert_one_yocto();
+        self.assert_pause_authority();
+        let mask = PausableAction::to_mask(actions.as_deref());
+        match farm_id {
+            Some(farm_id) => {
+                assert!(self.farms.get(&farm_id).is_some(), "Farm not found");
+                let current = self.paused_farms.get(&farm_id).unwrap_or(0);
+                self.paused_farms.insert(&farm_id, &(current | mask));
+            }
+            None => self.paused_actions |= mask,
+        }
+        FarmEvent::Paused {
+            farm_id,
+            actions: PausableAction::from_mask(mask),
+        }
+        .emit();
+    }
+
+    /// **(Admin or master)** Lifts the pause on `actions` (all of them if omitted)
+    /// for one farm, or globally when `farm_id` is omitted. A farm stays paused for
+    /// actions that are still paused globally.
+    #[payable]
+    pub fn unpause(&mut self, farm_id: Option<u64>, actions: Option<Vec<PausableAction>>) {
+        near_sdk::assert_one_yocto();
+        self.assert_pause_authority();
+        let mask = PausableAction::to_mask(actions.as_deref());
+        match farm_id {
+            Some(farm_id) => {
+                let remaining = self.paused_farms.get(&farm_id).unwrap_or(0) & !mask;
+                if remaining == 0 {
+                    self.paused_farms.remove(&farm_id);
+                } else {
+                    self.paused_farms.insert(&farm_id, &remaining);
+                }
+            }
+            None => self.paused_actions &= !mask,
+        }
+        FarmEvent::Unpaused {
+            farm_id,
+            actions: PausableAction::from_mask(mask),
+        }
+        .emit();
+    }
+}
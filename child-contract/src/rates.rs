@@ -0,0 +1,179 @@
+use crate::*;
+use std::convert::TryFrom;
+
+/// Current emission of one reward token of a farm.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct EmissionRateView {
+    pub reward_token: AccountId,
+    /// Rewards emitted per second, rounded down. 0 while the farm is not
+    /// distributing or the reward pool is empty.
+    pub reward_per_second: U128,
+    /// Rewards per second earned by one staked token without a boost, as a decimal
+    /// string scaled by `math::ACC_REWARD_MULTIPLIER`, like `reward_per_share`.
+    /// `None` while nobody is staked.
+    pub reward_per_second_per_token: Option<String>,
+}
+
+/// How long the reward pool of one reward token of a farm lasts.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RewardDurationView {
+    pub reward_token: AccountId,
+    pub remaining_reward: U128,
+    /// When the pool runs dry at the scheduled emission, if it does before the
+    /// farm's end time.
+    pub depletes_at_sec: Option<u64>,
+    /// Seconds from now until the pool runs dry or the farm ends, whichever is
+    /// first. `None` if neither ever happens.
+    pub remaining_sec: Option<u64>,
+}
+
+/// Time it takes to emit `amount` at `rate` per `interval` (nanoseconds), rounded
+/// up. `None` if it never happens.
+fn time_to_emit(amount: u128, rate: u128, interval: u64) -> Option<u64> {
+    if rate == 0 {
+        return None;
+    }
+    let time = (U256::from(amount) * U256::from(interval) + U256::from(rate - 1)) / U256::from(rate);
+    Some(u64::try_from(time).unwrap_or(u64::MAX))
+}
+
+impl FarmParams {
+    /// Reward per session of token `i` emitted at `now`: 0 while the farm is not
+    /// distributing or the pool is empty.
+    fn emission_per_session_at(&self, i: usize, now: u64) -> u128 {
+        let distributing = self.status_at(now) == FarmStatus::Active && now >= self.start_time;
+        if !distributing || self.remaining_reward[i] == 0 {
+            return 0;
+        }
+        self.reward_per_session_at(now)[i]
+    }
+
+    /// When emission from `last_distribution` on uses up `remaining_reward[i]`
+    /// following the schedule, ignoring the end time. `None` if it never does.
+    pub(crate) fn depletion_time(&self, i: usize) -> Option<u64> {
+        let mut left = self.remaining_reward[i];
+        let start = self.last_distribution;
+        let mut cursor = start;
+        let mut rate = self.reward_per_session_at(start)[i];
+        for (from, step_rate) in self.emission_schedule.iter().filter(|(from, _)| *from > start) {
+            if let Some(time) = time_to_emit(left, rate, self.session_interval) {
+                if time <= from - cursor {
+                    return Some(cursor + time);
+                }
+            }
+            let span = (from - cursor) as u128;
+            left = left.saturating_sub(rate.saturating_mul(span) / self.session_interval as u128);
+            cursor = *from;
+            rate = step_rate[i];
+        }
+        time_to_emit(left, rate, self.session_interval).map(|time| cursor.saturating_add(time))
+    }
+
+    /// When the farm is expected to end: at its end time, or else once every reward
+    /// pool has run dry at the scheduled emission. `None` if it is not running or
+    /// some pool never runs dry.
+    pub(crate) fn end_estimate(&self) -> Option<u64> {
+        if !self.status.is_running() {
+            return None;
+        }
+        if self.end_time.is_some() {
+            return self.end_time;
+        }
+        (0..self.reward_tokens.len()).try_fold(0, |end, i| self.depletion_time(i).map(|time| std::cmp::max(end, time)))
+    }
+}
+
+#[near_bindgen]
+impl ChildFarmingContract {
+    /// The current emission of each reward token of `farm_id`.
+    pub fn get_farm_emission_rate(&self, farm_id: u64) -> Vec<EmissionRateView> {
+        let now = env::block_timestamp();
+        let farm = self.internal_get_farm(farm_id).expect("Farm not found");
+        let (farm, _) = self.internal_farm_updated_to(farm_id, &farm, now);
+        let interval = farm.session_interval as u128;
+        farm.reward_tokens
+            .iter()
+            .enumerate()
+            .map(|(i, reward_token)| {
+                let reward_per_session = farm.emission_per_session_at(i, now);
+                let per_token = (farm.total_effective_staked > 0).then(|| {
+                    let per_session = math::reward_per_share_increment(reward_per_session, farm.total_effective_staked);
+                    // Split so that the scaling to seconds cannot overflow.
+                    let (interval, ns) = (U256::from(interval), U256::from(1_000_000_000_u64));
+                    (per_session / interval * ns + per_session % interval * ns / interval).to_string()
+                });
+                EmissionRateView {
+                    reward_token: reward_token.clone(),
+                    reward_per_second: U128(reward_per_session.saturating_mul(1_000_000_000) / interval),
+                    reward_per_second_per_token: per_token,
+                }
+            })
+            .collect()
+    }
+
+    /// Rewards of each reward token a new stake of `amount` in `farm_id` would earn
+    /// over the next `duration_sec`, optionally locked in `lock_tier`. Assumes the
+    /// other stakes stay as they are; the farm's schedule, end time and reward pools
+    /// are taken into account.
+    pub fn estimate_rewards(
+        &self,
+        farm_id: u64,
+        amount: U128,
+        duration_sec: u64,
+        lock_tier: Option<u8>,
+    ) -> Vec<U128> {
+        let now = env::block_timestamp();
+        let farm = self.internal_get_farm(farm_id).expect("Farm not found");
+        let (mut farm, _) = self.internal_farm_updated_to(farm_id, &farm, now);
+        if !farm.status.is_running() || amount.0 == 0 {
+            return vec![U128(0); farm.reward_tokens.len()];
+        }
+        let (lock_duration, multiplier_bps) = match lock_tier {
+            Some(tier) => *farm.lock_tiers.get(tier as usize).expect("Unknown lock tier"),
+            None => (0, BASE_MULTIPLIER_BPS),
+        };
+        let unlock_at = now + std::cmp::max(farm.lockup_period, lock_duration);
+        let mut stake_info = StakeInfo::new(&farm);
+        stake_info.add_position(&mut farm, amount.0, now, unlock_at, multiplier_bps);
+
+        // Boosts expire at the unlock time, as if the stake were refreshed then.
+        let until = now.saturating_add(duration_sec.saturating_mul(1_000_000_000));
+        if unlock_at < until && multiplier_bps != BASE_MULTIPLIER_BPS {
+            farm.update_to(unlock_at);
+            stake_info.settle(&farm);
+            stake_info.expire_boosts(&mut farm, unlock_at);
+        }
+        farm.update_to(until);
+        stake_info.unclaimed_rewards(&farm).into_iter().map(U128).collect()
+    }
+
+    /// How long each reward pool of `farm_id` lasts at the scheduled emission.
+    pub fn get_remaining_duration(&self, farm_id: u64) -> Vec<RewardDurationView> {
+        let now = env::block_timestamp();
+        let farm = self.internal_get_farm(farm_id).expect("Farm not found");
+        let (farm, _) = self.internal_farm_updated_to(farm_id, &farm, now);
+        farm.reward_tokens
+            .iter()
+            .enumerate()
+            .map(|(i, reward_token)| {
+                let (depletes_at, remaining) = if farm.status.is_running() {
+                    let depletes_at = farm
+                        .depletion_time(i)
+                        .filter(|depletes_at| farm.end_time.map_or(true, |end| *depletes_at < end));
+                    let stops_at = depletes_at.or(farm.end_time);
+                    (depletes_at, stops_at.map(|stops_at| stops_at.saturating_sub(now)))
+                } else {
+                    (None, Some(0))
+                };
+                RewardDurationView {
+                    reward_token: reward_token.clone(),
+                    remaining_reward: U128(farm.remaining_reward[i]),
+                    depletes_at_sec: depletes_at.map(|time| time / 1_000_000_000),
+                    remaining_sec: remaining.map(|ns| ns / 1_000_000_000),
+                }
+            })
+            .collect()
+    }
+}
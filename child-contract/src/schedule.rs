@@ -0,0 +1,125 @@
+use crate::*;
+
+/// Emission steps a farm may be created with.
+pub const MAX_EMISSION_STEPS: usize = 16;
+
+/// One step of a farm's emission schedule: from `from_sec` (unix time, in seconds)
+/// on, every session emits `reward_per_session` of each reward token.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct EmissionStep {
+    pub from_sec: u64,
+    pub reward_per_session: Vec<U128>,
+}
+
+impl From<&(u64, Vec<u128>)> for EmissionStep {
+    fn from((from, reward_per_session): &(u64, Vec<u128>)) -> Self {
+        EmissionStep {
+            from_sec: from / 1_000_000_000,
+            reward_per_session: reward_per_session.iter().map(|v| U128(*v)).collect(),
+        }
+    }
+}
+
+/// Checks `steps` against a farm starting at `start` and ending at `end` (nanoseconds)
+/// and converts them to `(from, reward_per_session)` in nanoseconds.
+pub(crate) fn parse_emission_schedule(
+    steps: &[EmissionStep],
+    num_rewards: usize,
+    start: u64,
+    end: Option<u64>,
+) -> Vec<(u64, Vec<u128>)> {
+    assert!(
+        steps.len() <= MAX_EMISSION_STEPS,
+        "Emission schedule cannot have more than {} steps",
+        MAX_EMISSION_STEPS
+    );
+    let mut previous = start;
+    steps
+        .iter()
+        .map(|step| {
+            let from = step.from_sec * 1_000_000_000;
+            assert!(
+                from > previous,
+                "Emission schedule steps must be in increasing order after the start time"
+            );
+            assert!(
+                end.map_or(true, |end| from < end),
+                "Emission schedule steps must start before the end time"
+            );
+            assert_eq!(
+                step.reward_per_session.len(),
+                num_rewards,
+                "Must provide reward_per_session for each reward token"
+            );
+            previous = from;
+            (from, step.reward_per_session.iter().map(|v| v.0).collect())
+        })
+        .collect()
+}
+
+impl FarmParams {
+    /// Reward per session of each reward token in effect at `time`.
+    pub fn reward_per_session_at(&self, time: u64) -> &[u128] {
+        self.emission_schedule
+            .iter()
+            .rev()
+            .find(|(from, _)| *from <= time)
+            .map_or(&self.reward_per_session, |(_, rate)| rate)
+    }
+
+    /// Rewards of each token emitted from `last_distribution` up to `to` following the
+    /// schedule, where a session's worth of time emits one `reward_per_session`. Time
+    /// spans that are not whole sessions are emitted pro rata, and the fractions left
+    /// over are carried in `emission_carry`. Moves `last_distribution` to `to`.
+    pub(crate) fn take_emission(&mut self, to: u64) -> Vec<u128> {
+        let mut emitted = vec![0_u128; self.reward_tokens.len()];
+        let interval = self.session_interval as u128;
+        let mut cursor = self.last_distribution;
+        while cursor < to {
+            let next = self
+                .emission_schedule
+                .iter()
+                .map(|(step_from, _)| *step_from)
+                .find(|step_from| *step_from > cursor)
+                .map_or(to, |step_from| std::cmp::min(step_from, to));
+            let span = next - cursor;
+            let sessions = (span / self.session_interval) as u128;
+            let rest = (span % self.session_interval) as u128;
+            let rate = self.reward_per_session_at(cursor).to_vec();
+            for ((total, carry), rate) in emitted.iter_mut().zip(self.emission_carry.iter_mut()).zip(rate) {
+                let partial = rate.saturating_mul(rest).saturating_add(*carry);
+                *carry = partial % interval;
+                let amount = rate.saturating_mul(sessions).saturating_add(partial / interval);
+                *total = total.saturating_add(amount);
+            }
+            cursor = next;
+        }
+        self.last_distribution = to;
+        emitted
+    }
+
+    /// The time rewards can be distributed up to at `now`: the last whole session
+    /// boundary, or the end time itself once the farm has reached it. Continuous
+    /// farms distribute up to `now`.
+    pub fn distributable_until(&self, now: u64) -> u64 {
+        let horizon = self.end_time.map_or(now, |end| std::cmp::min(end, now));
+        if self.distribution_mode == DistributionMode::Continuous || self.end_time == Some(horizon) {
+            return std::cmp::max(horizon, self.last_distribution);
+        }
+        let elapsed = horizon.saturating_sub(self.last_distribution);
+        self.last_distribution + elapsed / self.session_interval * self.session_interval
+    }
+
+    /// Folds the schedule steps that distribution has reached into `reward_per_session`.
+    pub(crate) fn advance_schedule(&mut self) {
+        let reached = self
+            .emission_schedule
+            .iter()
+            .take_while(|(from, _)| *from <= self.last_distribution)
+            .count();
+        if let Some((_, rate)) = self.emission_schedule.drain(..reached).next_back() {
+            self.reward_per_session = rate;
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use crate::*;
+
+const GAS_FOR_FT_BALANCE_OF: Gas = Gas::from_tgas(10);
+const GAS_FOR_SOLVENCY_CALLBACK: Gas = Gas::from_tgas(20);
+
+/// What the contract owes in one token, summed over all farms.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TokenLiabilities {
+    pub token_id: AccountId,
+    /// Principal staked in farms with this staking token.
+    pub staked: U128,
+    /// Rewards not distributed yet, including those set aside while nobody was staked.
+    pub remaining_reward: U128,
+    /// Rewards credited to stakers but not claimed yet.
+    pub accrued_rewards: U128,
+}
+
+impl TokenLiabilities {
+    pub fn total(&self) -> u128 {
+        self.staked
+            .0
+            .saturating_add(self.remaining_reward.0)
+            .saturating_add(self.accrued_rewards.0)
+    }
+}
+
+/// Result of `check_solvency`: the token balance held by this contract against
+/// what it owes in that token.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SolvencyReport {
+    pub balance: U128,
+    pub liabilities: TokenLiabilities,
+    /// Whether `balance >= staked + remaining_reward + accrued_rewards`.
+    pub solvent: bool,
+}
+
+#[near_bindgen]
+impl ChildFarmingContract {
+    /// Staked principal, remaining rewards and unclaimed rewards of `token_id`
+    /// over all farms. Reads every farm, so it is meant for off-chain monitoring.
+    pub fn get_token_liabilities(&self, token_id: AccountId) -> TokenLiabilities {
+        let mut staked = 0_u128;
+        let mut remaining_reward = 0_u128;
+        let mut accrued_rewards = 0_u128;
+        for farm_id in 0..self.farm_count {
+            let Some(farm) = self.internal_get_farm(farm_id) else {
+                continue;
+            };
+            if farm.staking_token == token_id {
+                staked = staked.saturating_add(farm.total_staked);
+            }
+            for (i, reward_token) in farm.reward_tokens.iter().enumerate() {
+                if reward_token == &token_id {
+                    remaining_reward = remaining_reward
+                        .saturating_add(farm.remaining_reward[i])
+                        .saturating_add(farm.undistributed_reward[i]);
+                    accrued_rewards = accrued_rewards
+                        .saturating_add(farm.total_distributed[i].saturating_sub(farm.total_claimed[i]));
+                }
+            }
+        }
+        TokenLiabilities {
+            token_id,
+            staked: U128(staked),
+            remaining_reward: U128(remaining_reward),
+            accrued_rewards: U128(accrued_rewards),
+        }
+    }
+
+    /// Asks `token_id` for this contract's balance and compares it with
+    /// `get_token_liabilities`. The report is returned by `on_solvency_checked`.
+    pub fn check_solvency(&self, token_id: AccountId) -> Promise {
+        Promise::new(token_id.clone())
+            .function_call(
+                "ft_balance_of".to_string(),
+                near_sdk::serde_json::to_vec(&serde_json::json!({
+                    "account_id": env::current_account_id(),
+                }))
+                .unwrap(),
+                NearToken::from_yoctonear(0),
+                GAS_FOR_FT_BALANCE_OF,
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_SOLVENCY_CALLBACK)
+                    .on_solvency_checked(token_id),
+            )
+    }
+
+    /// Private callback of `check_solvency` with the result of `ft_balance_of`.
+    #[private]
+    pub fn on_solvency_checked(&self, token_id: AccountId) -> SolvencyReport {
+        let balance: U128 = match env::promise_result(0) {
+            PromiseResult::Successful(value) => near_sdk::serde_json::from_slice(&value)
+                .unwrap_or_else(|_| env::panic_str("Invalid ft_balance_of result")),
+            PromiseResult::Failed => env::panic_str("ft_balance_of failed"),
+        };
+        let liabilities = self.get_token_liabilities(token_id);
+        let solvent = balance.0 >= liabilities.total();
+        if !solvent {
+            env::log_str(
+                format!(
+                    "Insolvent in {}: balance {} is below liabilities {}",
+                    liabilities.token_id,
+                    balance.0,
+                    liabilities.total()
+                )
+                .as_str(),
+            );
+        }
+        SolvencyReport {
+            balance,
+            liabilities,
+            solvent,
+        }
+    }
+}
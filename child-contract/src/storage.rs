@@ -0,0 +1,257 @@
+use crate::*;
+use near_contract_standards::storage_management::{
+    StorageBalance, StorageBalanceBounds, StorageManagement,
+};
+
+impl ChildFarmingContract {
+    /// Measures the bytes added by registering an account with the longest possible id.
+    pub(crate) fn measure_account_storage_usage(&mut self) {
+        let initial_storage_usage = env::storage_usage();
+        let tmp_account_id: AccountId = "a".repeat(64).parse().unwrap();
+        self.storage_deposits.insert(&tmp_account_id, &AccountStorage::default());
+        self.account_storage_usage = env::storage_usage() - initial_storage_usage;
+        self.storage_deposits.remove(&tmp_account_id);
+    }
+
+    /// Contract storage usage without the checkpoints, which are paid for once
+    /// instead. Storage is attributed to accounts as the change in this number.
+    pub(crate) fn billable_storage_usage(&self) -> u64 {
+        env::storage_usage() - self.history_storage_usage
+    }
+
+    /// Takes the cost of the checkpoints written so far in this call.
+    fn take_unpaid_history_cost(&mut self) -> Balance {
+        let bytes = std::mem::take(&mut self.unpaid_history_bytes);
+        bytes as Balance * env::storage_byte_cost().as_yoctonear()
+    }
+
+    /// Attributes the change in billable storage since `initial_storage` to `account_id`,
+    /// and takes the cost of the checkpoints written in this call out of its deposit
+    /// for good. Freed bytes are credited back to the account. Returns the part of the
+    /// checkpoint cost that its available balance could not cover.
+    pub(crate) fn internal_track_storage(&mut self, account_id: &AccountId, initial_storage: u64) -> Balance {
+        let current_storage = self.billable_storage_usage();
+        let history_cost = self.take_unpaid_history_cost();
+        if current_storage == initial_storage && history_cost == 0 {
+            return 0;
+        }
+        let mut account = self.storage_deposits.get(account_id).unwrap_or_default();
+        if current_storage > initial_storage {
+            account.used_bytes += current_storage - initial_storage;
+        } else {
+            account.used_bytes = account
+                .used_bytes
+                .saturating_sub(initial_storage - current_storage);
+        }
+        let available = account.deposit.saturating_sub(self.required_storage_balance(&account));
+        let paid = std::cmp::min(history_cost, available);
+        account.deposit -= paid;
+        self.storage_deposits.insert(account_id, &account);
+        history_cost - paid
+    }
+
+    /// Same as `internal_track_storage`, but panics if the account's deposit
+    /// no longer covers everything it uses and the checkpoints it wrote.
+    pub(crate) fn internal_charge_storage(&mut self, account_id: &AccountId, initial_storage: u64) {
+        let unpaid_history_cost = self.internal_track_storage(account_id, initial_storage);
+        let account = self.storage_deposits.get(account_id).unwrap_or_default();
+        let required = self.required_storage_balance(&account) + unpaid_history_cost;
+        assert!(
+            account.deposit >= required,
+            "Insufficient storage. Need {} more yoctoNEAR.",
+            required - account.deposit
+        );
+    }
+
+    /// Same as `internal_charge_storage`, but the bytes are paid for out of `payer`'s
+    /// deposit. Their cost moves to the deposit of `account_id`, which owns the bytes,
+    /// so it becomes available again to `account_id` once the bytes are freed. The
+    /// checkpoints written in this call are paid for by `payer` as well.
+    pub(crate) fn internal_charge_storage_to(
+        &mut self,
+        account_id: &AccountId,
+        payer: &AccountId,
+        initial_storage: u64,
+    ) {
+        if account_id == payer {
+            return self.internal_charge_storage(account_id, initial_storage);
+        }
+        let history_cost = self.take_unpaid_history_cost();
+        let mut account = self
+            .storage_deposits
+            .get(account_id)
+            .unwrap_or_else(|| env::panic_str(&format!("The account {} is not registered", account_id)));
+        let added_bytes = self.billable_storage_usage().saturating_sub(initial_storage);
+        let cost = added_bytes as Balance * env::storage_byte_cost().as_yoctonear();
+        account.deposit += cost;
+        self.storage_deposits.insert(account_id, &account);
+        self.internal_track_storage(account_id, initial_storage);
+
+        let mut payer_account = self.storage_deposits.get(payer).unwrap_or_default();
+        let required = self.required_storage_balance(&payer_account) + cost + history_cost;
+        assert!(
+            payer_account.deposit >= required,
+            "Insufficient storage. Need {} more yoctoNEAR.",
+            required - payer_account.deposit
+        );
+        payer_account.deposit -= cost + history_cost;
+        self.storage_deposits.insert(payer, &payer_account);
+    }
+
+    /// Whether `account_id` is registered and its available balance covers `bytes` more.
+    pub(crate) fn can_pay_storage(&self, account_id: &AccountId, bytes: u64) -> bool {
+        self.storage_deposits.get(account_id).map_or(false, |account| {
+            let cost = bytes as Balance * env::storage_byte_cost().as_yoctonear();
+            account.deposit >= self.required_storage_balance(&account) + cost
+        })
+    }
+
+    /// The part of an account's deposit that is locked by its registration and data.
+    fn required_storage_balance(&self, account: &AccountStorage) -> Balance {
+        (self.account_storage_usage + account.used_bytes) as Balance
+            * env::storage_byte_cost().as_yoctonear()
+    }
+
+    fn internal_storage_balance(&self, account: &AccountStorage) -> StorageBalance {
+        StorageBalance {
+            total: NearToken::from_yoctonear(account.deposit),
+            available: NearToken::from_yoctonear(
+                account
+                    .deposit
+                    .saturating_sub(self.required_storage_balance(account)),
+            ),
+        }
+    }
+}
+
+#[near_bindgen]
+impl StorageManagement for ChildFarmingContract {
+    /// Registers `account_id` (defaults to the caller) or tops up its deposit.
+    /// With `registration_only`, anything above the minimum balance is refunded.
+    #[payable]
+    fn storage_deposit(
+        &mut self,
+        account_id: Option<AccountId>,
+        registration_only: Option<bool>,
+    ) -> StorageBalance {
+        let amount = env::attached_deposit().as_yoctonear();
+        let account_id = account_id.unwrap_or_else(env::predecessor_account_id);
+        let registration_only = registration_only.unwrap_or(false);
+
+        let mut account = match self.storage_deposits.get(&account_id) {
+            Some(account) if registration_only => {
+                if amount > 0 {
+                    Promise::new(env::predecessor_account_id())
+                        .transfer(NearToken::from_yoctonear(amount));
+                }
+                return self.internal_storage_balance(&account);
+            }
+            Some(account) => account,
+            None => {
+                let min_balance = self.storage_balance_bounds().min.as_yoctonear();
+                assert!(
+                    amount >= min_balance,
+                    "The attached deposit is less than the minimum storage balance"
+                );
+                AccountStorage::default()
+            }
+        };
+
+        let deposit = if registration_only {
+            let min_balance = self.storage_balance_bounds().min.as_yoctonear();
+            let refund = amount - min_balance;
+            if refund > 0 {
+                Promise::new(env::predecessor_account_id())
+                    .transfer(NearToken::from_yoctonear(refund));
+            }
+            min_balance
+        } else {
+            amount
+        };
+        account.deposit += deposit;
+        self.storage_deposits.insert(&account_id, &account);
+
+        FarmEvent::StorageDeposit {
+            account_id,
+            amount: U128(deposit),
+        }
+        .emit();
+        self.internal_storage_balance(&account)
+    }
+
+    /// Withdraws up to the available balance; bytes backing live farms and stakes stay locked.
+    #[payable]
+    fn storage_withdraw(&mut self, amount: Option<NearToken>) -> StorageBalance {
+        near_sdk::assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let mut account = self
+            .storage_deposits
+            .get(&account_id)
+            .unwrap_or_else(|| {
+                env::panic_str(format!("The account {} is not registered", account_id).as_str())
+            });
+
+        let available = self.internal_storage_balance(&account).available.as_yoctonear();
+        let to_withdraw = amount.map(|v| v.as_yoctonear()).unwrap_or(available);
+        assert!(
+            to_withdraw <= available,
+            "The amount is greater than the available storage balance"
+        );
+
+        account.deposit -= to_withdraw;
+        self.storage_deposits.insert(&account_id, &account);
+        if to_withdraw > 0 {
+            Promise::new(account_id.clone()).transfer(NearToken::from_yoctonear(to_withdraw));
+        }
+        FarmEvent::StorageWithdraw {
+            account_id,
+            amount: U128(to_withdraw),
+        }
+        .emit();
+        self.internal_storage_balance(&account)
+    }
+
+    /// Removes the caller's registration and refunds the whole deposit.
+    /// Accounts that still pay for farms or stakes cannot unregister, even with `force`.
+    #[payable]
+    fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        near_sdk::assert_one_yocto();
+        // Farms and stakes hold other users' funds, so `force` cannot discard them.
+        let _ = force;
+        let account_id = env::predecessor_account_id();
+        match self.storage_deposits.get(&account_id) {
+            Some(account) => {
+                assert_eq!(
+                    account.used_bytes, 0,
+                    "Can't unregister an account with active farms or stakes"
+                );
+                self.storage_deposits.remove(&account_id);
+                if account.deposit > 0 {
+                    Promise::new(account_id.clone())
+                        .transfer(NearToken::from_yoctonear(account.deposit));
+                }
+                FarmEvent::StorageUnregister { account_id }.emit();
+                true
+            }
+            None => {
+                env::log_str(format!("The account {} is not registered", account_id).as_str());
+                false
+            }
+        }
+    }
+
+    fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        StorageBalanceBounds {
+            min: NearToken::from_yoctonear(
+                self.account_storage_usage as Balance * env::storage_byte_cost().as_yoctonear(),
+            ),
+            max: None,
+        }
+    }
+
+    fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
+        self.storage_deposits
+            .get(&account_id)
+            .map(|account| self.internal_storage_balance(&account))
+    }
+}
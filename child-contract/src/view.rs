@@ -1,4 +1,5 @@
 use crate::*;
+use std::convert::TryFrom;
 use near_sdk::{
     near_bindgen,
     serde::{Deserialize, Serialize},
@@ -16,13 +17,42 @@ pub struct FarmView {
     pub start_at_sec: u64,
     pub last_distribution_sec: u64,
     pub total_staked: U128,
-    pub reward_per_share: Vec<U128>,
+    /// Staked tokens weighted by their lock tier boosts.
+    pub total_effective_staked: U128,
+    /// Decimal strings, scaled by `math::ACC_REWARD_MULTIPLIER`.
+    pub reward_per_share: Vec<String>,
     pub lockup_period_sec: u64,
     pub status: FarmStatus,
+    pub farm_creator: AccountId,
+    pub early_exit_penalty_bps: u16,
+    pub penalty_destination: PenaltyDestination,
+    pub burned_penalty: U128,
+    pub end_at_sec: Option<u64>,
+    /// Upcoming changes of `reward_per_session`.
+    pub emission_schedule: Vec<EmissionStep>,
+    pub distribution_mode: DistributionMode,
+    pub idle_reward_policy: IdleRewardPolicy,
+    /// Rewards emitted while nobody was staked, per reward token.
+    pub undistributed_reward: Vec<U128>,
+    /// Emission the reward pools could not cover, per reward token.
+    pub unfunded_emission: Vec<U128>,
+    /// Lock durations stakers may choose for a reward boost.
+    pub lock_tiers: Vec<LockTier>,
+    /// Rewards left to distribute, per reward token.
+    pub remaining_reward: Vec<U128>,
+    /// Rewards credited to stakers, per reward token.
+    pub total_distributed: Vec<U128>,
+    /// Rewards paid out to stakers, per reward token.
+    pub total_claimed: Vec<U128>,
+    pub staker_count: u64,
+    /// When the farm is expected to end: its end time, or else when the last
+    /// reward pool runs dry at the scheduled emission. `None` if the farm is not
+    /// running or no end is in sight.
+    pub end_estimate_sec: Option<u64>,
 }
 
-impl From<(&FarmParams, u64)> for FarmView {
-    fn from((farm, farm_id): (&FarmParams, u64)) -> Self {
+impl From<(&FarmParams, u64, u64)> for FarmView {
+    fn from((farm, farm_id, staker_count): (&FarmParams, u64, u64)) -> Self {
         FarmView {
             farm_id,
 
@@ -40,15 +70,48 @@ impl From<(&FarmParams, u64)> for FarmView {
             last_distribution_sec: farm.last_distribution / 1_000_000_000,
 
             total_staked: U128(farm.total_staked),
+            total_effective_staked: U128(farm.total_effective_staked),
 
-            reward_per_share: farm
-                .reward_per_share
-                .iter()
-                .map(|v| U128(*v))
-                .collect(),
+            reward_per_share: farm.reward_per_share.iter().map(U256::to_string).collect(),
 
             lockup_period_sec: farm.lockup_period / 1_000_000_000,
-            status: farm.status.clone(),
+            status: farm.status_at(env::block_timestamp()),
+            farm_creator: farm.farm_creator.clone(),
+            early_exit_penalty_bps: farm.early_exit_penalty_bps,
+            penalty_destination: farm.penalty_destination.clone(),
+            burned_penalty: U128(farm.burned_penalty),
+            end_at_sec: farm.end_time.map(|end| end / 1_000_000_000),
+            emission_schedule: farm.emission_schedule.iter().map(EmissionStep::from).collect(),
+            distribution_mode: farm.distribution_mode,
+            idle_reward_policy: farm.idle_reward_policy,
+            undistributed_reward: farm.undistributed_reward.iter().map(|v| U128(*v)).collect(),
+            unfunded_emission: farm.unfunded_emission.iter().map(|v| U128(*v)).collect(),
+            lock_tiers: farm.lock_tiers.iter().map(LockTier::from).collect(),
+            remaining_reward: farm.remaining_reward.iter().map(|v| U128(*v)).collect(),
+            total_distributed: farm.total_distributed.iter().map(|v| U128(*v)).collect(),
+            total_claimed: farm.total_claimed.iter().map(|v| U128(*v)).collect(),
+            staker_count,
+            end_estimate_sec: farm.end_estimate().map(|end| end / 1_000_000_000),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LockPositionView {
+    pub amount: U128,
+    pub start_sec: u64,
+    pub unlock_at_sec: u64,
+    pub multiplier_bps: u32,
+}
+
+impl From<&LockPosition> for LockPositionView {
+    fn from(position: &LockPosition) -> Self {
+        LockPositionView {
+            amount: U128(position.amount),
+            start_sec: position.start / 1_000_000_000,
+            unlock_at_sec: position.unlock_at / 1_000_000_000,
+            multiplier_bps: position.multiplier_bps,
         }
     }
 }
@@ -58,29 +121,112 @@ impl From<(&FarmParams, u64)> for FarmView {
 pub struct StakeInfoView {
     pub farm_id: u64,
     pub amount: U128,
+    /// `amount` weighted by the lock tier boosts of the positions.
+    pub effective_amount: U128,
+    /// When the last position unlocks.
     pub lockup_end_sec: u64,
-    pub reward_debt: Vec<U128>,
+    /// Staked tokens by lockup, in the order they unlock.
+    pub positions: Vec<LockPositionView>,
+    /// Decimal strings, scaled by `math::ACC_REWARD_MULTIPLIER`.
+    pub reward_debt: Vec<String>,
     pub accrued_rewards: Vec<U128>,
     pub reward_tokens: Vec<AccountId>,
+    pub auto_compound: bool,
+}
+
+/// A stake in a farm together with its owner.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StakerView {
+    pub account_id: AccountId,
+    #[serde(flatten)]
+    pub stake: StakeInfoView,
+}
+
+/// `farm` must have been brought up to date, so that `accrued_rewards` includes
+/// the rewards pending per reward token.
+impl From<(&StakeInfo, &FarmParams, u64)> for StakeInfoView {
+    fn from((info, farm, farm_id): (&StakeInfo, &FarmParams, u64)) -> Self {
+        StakeInfoView {
+            farm_id,
+            amount: U128(info.amount),
+            effective_amount: U128(info.effective_amount),
+            lockup_end_sec: info.lockup_end() / 1_000_000_000,
+            positions: info.positions.iter().map(LockPositionView::from).collect(),
+            reward_debt: info.reward_debt.iter().map(U256::to_string).collect(),
+            accrued_rewards: info.unclaimed_rewards(farm).into_iter().map(U128).collect(),
+            reward_tokens: farm.reward_tokens.clone(),
+            auto_compound: info.auto_compound,
+        }
+    }
 }
 
 #[near_bindgen]
 impl ChildFarmingContract {
+    pub fn get_admin(&self) -> AccountId {
+        self.admin.clone()
+    }
+
+    pub fn get_pending_admin(&self) -> Option<AccountId> {
+        self.pending_admin.clone()
+    }
+
+    pub fn list_farm_creators(&self) -> Vec<AccountId> {
+        self.farm_creators.to_vec()
+    }
+
+    /// Reward deposits of a farm per reward token as `(funder, amount)`, oldest first.
+    pub fn get_reward_funders(&self, farm_id: u64) -> Vec<Vec<(AccountId, U128)>> {
+        let farm = self.internal_get_farm(farm_id).expect("Farm not found");
+        farm.reward_funders
+            .iter()
+            .map(|fundings| fundings.iter().map(|(funder, amount)| (funder.clone(), U128(*amount))).collect())
+            .collect()
+    }
+
+    /// Paused actions of `farm_id`, including globally paused ones, or only the
+    /// globally paused actions when `farm_id` is omitted.
+    pub fn get_paused_actions(&self, farm_id: Option<u64>) -> Vec<PausableAction> {
+        let mask = match farm_id {
+            Some(farm_id) => self.paused_mask(farm_id),
+            None => self.paused_actions,
+        };
+        PausableAction::from_mask(mask)
+    }
+
+    /// Farms with their own pause bits, excluding the globally paused actions.
+    pub fn list_paused_farms(&self) -> Vec<(u64, Vec<PausableAction>)> {
+        self.paused_farms
+            .iter()
+            .map(|(farm_id, mask)| (farm_id, PausableAction::from_mask(mask)))
+            .collect()
+    }
+
     pub fn list_farms(&self, from_index: u64, limit: u64) -> Vec<FarmView> {
         let mut results = Vec::new();
         let end = std::cmp::min(self.farm_count, from_index + limit);
         for farm_id in from_index..end {
-            if let Some(farm) = self.farms.get(&farm_id) {
-                results.push(FarmView::from((&farm, farm_id)));
+            if let Some(farm) = self.internal_get_farm(farm_id) {
+                results.push(self.internal_farm_view(&farm, farm_id));
             }
         }
         results
     }
 
+    /// The farm as of its last distribution.
     pub fn get_farm(&self, farm_id: u64) -> Option<FarmView> {
-        self.farms
-            .get(&farm_id)
-            .map(|farm| FarmView::from((&farm, farm_id)))
+        self.internal_get_farm(farm_id)
+            .map(|farm| self.internal_farm_view(&farm, farm_id))
+    }
+
+    /// Like `get_farm`, with the emission up to now distributed, as the next call
+    /// touching the farm would.
+    pub fn get_farm_live(&self, farm_id: u64) -> Option<FarmView> {
+        self.internal_get_farm(farm_id)
+            .map(|farm| {
+                let (farm, _) = self.internal_farm_updated_to(farm_id, &farm, env::block_timestamp());
+                self.internal_farm_view(&farm, farm_id)
+            })
     }
 
     pub fn get_stake_info(
@@ -89,78 +235,89 @@ impl ChildFarmingContract {
         farm_id: u64
     ) -> Option<StakeInfoView> {
         let key = (account_id, farm_id);
-        if let Some(info) = self.stakes.get(&key) {
-            if let Some(farm) = self.farms.get(&farm_id) {
-                let sim_farm = self.simulate_update_farm(&farm);
-                // Compute pending rewards per reward token:
-                let updated_accrued: Vec<U128> = info.accrued_rewards
-                    .iter()
-                    .enumerate()
-                    .map(|(i, &val)| {
-                        let pending = info.amount
-                            .saturating_mul(sim_farm.reward_per_share[i].saturating_sub(info.reward_debt[i]))
-                            / ACC_REWARD_MULTIPLIER;
-                        U128(val.saturating_add(pending))
-                    })
-                    .collect();
-                return Some(StakeInfoView {
-                    farm_id,
-                    amount: U128(info.amount),
-                    lockup_end_sec: info.lockup_end / 1_000_000_000,
-                    reward_debt: info.reward_debt.iter().map(|v| U128(*v)).collect(),
-                    accrued_rewards: updated_accrued,
-                    reward_tokens: farm.reward_tokens.clone(),
-                });
+        if let Some(mut info) = self.internal_get_stake(&key) {
+            if let Some(farm) = self.internal_get_farm(farm_id) {
+                let (sim_farm, expired) = self.internal_farm_updated_to(farm_id, &farm, env::block_timestamp());
+                self.internal_drop_expired_boosts(farm_id, &mut info, &expired);
+                return Some(StakeInfoView::from((&info, &sim_farm, farm_id)));
             }
         }
         None
     }
 
-
+    /// Stakes of `account_id`, paginated over the farms it has a stake in.
     pub fn list_stakes_by_user(
-        &self, 
-        account_id: String, 
-        from_index: u64, 
+        &self,
+        account_id: AccountId,
+        from_index: u64,
         limit: u64
     ) -> Vec<StakeInfoView> {
-        let mut results = Vec::new();
-        let mut count = 0;
-        let mut skipped = 0;
-        for ((user, farm_id), stake_info) in self.stakes.iter() {
-            if user == account_id {
-                if skipped < from_index {
-                    skipped += 1;
-                    continue;
-                }
-                if count < limit {
-                    if let Some(farm) = self.farms.get(&farm_id) {
-                        let sim_farm = self.simulate_update_farm(&farm);
-                        let updated_accrued: Vec<U128> = stake_info
-                            .accrued_rewards
-                            .iter()
-                            .enumerate()
-                            .map(|(i, &val)| {
-                                let pending = stake_info.amount
-                                    .saturating_mul(sim_farm.reward_per_share[i].saturating_sub(stake_info.reward_debt[i]))
-                                    / ACC_REWARD_MULTIPLIER;
-                                U128(val.saturating_add(pending))
-                            })
-                            .collect();
-                        results.push(StakeInfoView {
-                            farm_id,
-                            amount: U128(stake_info.amount),
-                            lockup_end_sec: stake_info.lockup_end / 1_000_000_000,
-                            reward_debt: stake_info.reward_debt.iter().map(|v| U128(*v)).collect(),
-                            accrued_rewards: updated_accrued,
-                            reward_tokens: farm.reward_tokens.clone(),
-                        });
-                        count += 1;
-                    }
-                } else {
-                    break;
-                }
-            }
-        }
-        results
+        let farms = match self.user_farms.get(&account_id) {
+            Some(farms) => farms,
+            None => return vec![],
+        };
+        let farm_ids = farms.as_vector();
+        let end = std::cmp::min(farm_ids.len(), from_index.saturating_add(limit));
+        (from_index..end)
+            .filter_map(|i| farm_ids.get(i))
+            .filter_map(|farm_id| self.get_stake_info(account_id.clone(), farm_id))
+            .collect()
     }
-}
\ No newline at end of file
+
+    /// Number of accounts with a stake in `farm_id`.
+    pub fn get_farm_staker_count(&self, farm_id: u64) -> u64 {
+        self.farm_stakers.get(&farm_id).map_or(0, |stakers| stakers.len())
+    }
+
+    /// Stakes in `farm_id`, paginated in no particular order.
+    pub fn list_farm_stakers(&self, farm_id: u64, from_index: u64, limit: u64) -> Vec<StakerView> {
+        let stakers = match self.farm_stakers.get(&farm_id) {
+            Some(stakers) => stakers,
+            None => return vec![],
+        };
+        let account_ids = stakers.as_vector();
+        let end = std::cmp::min(account_ids.len(), from_index.saturating_add(limit));
+        self.internal_staker_views(farm_id, (from_index..end).filter_map(|i| account_ids.get(i)))
+    }
+
+    /// The `limit` largest stakes in `farm_id`, largest first.
+    pub fn get_top_stakers(&self, farm_id: u64, limit: u64) -> Vec<StakerView> {
+        let ranking = match self.farm_rankings.get(&farm_id) {
+            Some(ranking) => ranking,
+            None => return vec![],
+        };
+        let account_ids = ranking
+            .iter_rev()
+            .take(usize::try_from(limit).unwrap_or(usize::MAX))
+            .map(|((_, account_id), _)| account_id);
+        self.internal_staker_views(farm_id, account_ids)
+    }
+}
+
+impl ChildFarmingContract {
+    fn internal_farm_view(&self, farm: &FarmParams, farm_id: u64) -> FarmView {
+        FarmView::from((farm, farm_id, self.get_farm_staker_count(farm_id)))
+    }
+
+    /// Views of the stakes of `account_ids` in `farm_id`, with rewards as of now.
+    fn internal_staker_views(
+        &self,
+        farm_id: u64,
+        account_ids: impl Iterator<Item = AccountId>,
+    ) -> Vec<StakerView> {
+        let (farm, expired) = match self.internal_get_farm(farm_id) {
+            Some(farm) => self.internal_farm_updated_to(farm_id, &farm, env::block_timestamp()),
+            None => return vec![],
+        };
+        account_ids
+            .filter_map(|account_id| {
+                let mut info = self.internal_get_stake(&(account_id.clone(), farm_id))?;
+                self.internal_drop_expired_boosts(farm_id, &mut info, &expired);
+                Some(StakerView {
+                    stake: StakeInfoView::from((&info, &farm, farm_id)),
+                    account_id,
+                })
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,164 @@
+//! Sandbox tests of the transfer rollback callbacks.
+//!
+//! They deploy the release build, so build it and run them with:
+//!
+//! ```bash
+//! cargo build --target wasm32-unknown-unknown --release
+//! cargo test -- --ignored
+//! ```
+//!
+//! The staking and reward "tokens" are plain accounts without code: they call
+//! `ft_on_transfer` themselves, and every `ft_transfer` sent back to them fails.
+
+use near_workspaces::network::Sandbox;
+use near_workspaces::types::NearToken;
+use near_workspaces::{Account, Contract, Worker};
+use serde_json::{json, Value};
+
+const WASM_PATH: &str = "target/wasm32-unknown-unknown/release/single_token_farm.wasm";
+
+struct Setup {
+    worker: Worker<Sandbox>,
+    contract: Contract,
+    user: Account,
+    staking_token: Account,
+    reward_token: Account,
+}
+
+async fn create_account(root: &Account, name: &str) -> anyhow::Result<Account> {
+    let account = root
+        .create_subaccount(name)
+        .initial_balance(NearToken::from_near(10))
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(account)
+}
+
+/// Deploys the farm with its own account as admin, and creates farm 0 that emits
+/// 10 reward tokens per second without a lockup.
+async fn setup() -> anyhow::Result<Setup> {
+    let worker = near_workspaces::sandbox().await?;
+    let wasm = std::fs::read(WASM_PATH)?;
+    let contract = worker.dev_deploy(&wasm).await?;
+    contract
+        .call("new")
+        .args_json(json!({ "admin": contract.id() }))
+        .transact()
+        .await?
+        .into_result()?;
+
+    let root = worker.root_account()?;
+    let user = create_account(&root, "user").await?;
+    let staking_token = create_account(&root, "staking").await?;
+    let reward_token = create_account(&root, "reward").await?;
+
+    for account in [contract.as_account(), &user] {
+        account
+            .call(contract.id(), "storage_deposit")
+            .args_json(json!({}))
+            .deposit(NearToken::from_near(1))
+            .transact()
+            .await?
+            .into_result()?;
+    }
+    contract
+        .call("create_farm")
+        .args_json(json!({ "input": {
+            "staking_token": staking_token.id(),
+            "reward_tokens": [reward_token.id()],
+            "lockup_period_sec": 0,
+            "reward_per_session": ["10"],
+            "session_interval_sec": 1,
+            "start_at_sec": 0,
+        }}))
+        .deposit(NearToken::from_yoctonear(1))
+        .transact()
+        .await?
+        .into_result()?;
+
+    Ok(Setup {
+        worker,
+        contract,
+        user,
+        staking_token,
+        reward_token,
+    })
+}
+
+async fn ft_on_transfer(token: &Account, contract: &Contract, sender: &Account, amount: u128, msg: &str) -> anyhow::Result<()> {
+    token
+        .call(contract.id(), "ft_on_transfer")
+        .args_json(json!({ "sender_id": sender.id(), "amount": amount.to_string(), "msg": msg }))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(())
+}
+
+async fn stake_info(setup: &Setup) -> anyhow::Result<Value> {
+    let info = setup
+        .contract
+        .view("get_stake_info")
+        .args_json(json!({ "account_id": setup.user.id(), "farm_id": 0 }))
+        .await?
+        .json()?;
+    Ok(info)
+}
+
+async fn farm(setup: &Setup) -> anyhow::Result<Value> {
+    let farm = setup.contract.view("get_farm").args_json(json!({ "farm_id": 0 })).await?.json()?;
+    Ok(farm)
+}
+
+#[tokio::test]
+#[ignore = "needs the release wasm and a sandbox"]
+async fn test_failed_withdraw_transfer_restores_stake() -> anyhow::Result<()> {
+    let setup = setup().await?;
+    ft_on_transfer(&setup.staking_token, &setup.contract, &setup.user, 100, "STAKE:0").await?;
+
+    let outcome = setup
+        .user
+        .call(setup.contract.id(), "withdraw")
+        .args_json(json!({ "farm_id": 0, "amount": "100" }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(outcome.is_success());
+    // The transfer to the code-less token account fails, and the callback still succeeds.
+    assert_eq!(outcome.receipt_failures().len(), 1);
+
+    let info = stake_info(&setup).await?;
+    assert_eq!(info["amount"], "100");
+    assert_eq!(farm(&setup).await?["total_staked"], "100");
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "needs the release wasm and a sandbox"]
+async fn test_failed_claim_transfer_restores_rewards() -> anyhow::Result<()> {
+    let setup = setup().await?;
+    ft_on_transfer(&setup.reward_token, &setup.contract, &setup.user, 1_000, "ADD_REWARD:0").await?;
+    ft_on_transfer(&setup.staking_token, &setup.contract, &setup.user, 100, "STAKE:0").await?;
+    setup.worker.fast_forward(10).await?;
+
+    let outcome = setup
+        .user
+        .call(setup.contract.id(), "claim_rewards")
+        .args_json(json!({ "farm_id": 0 }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(outcome.is_success());
+    assert_eq!(outcome.receipt_failures().len(), 1);
+
+    let info = stake_info(&setup).await?;
+    assert_eq!(info["amount"], "100");
+    let accrued: u128 = info["accrued_rewards"][0].as_str().unwrap().parse()?;
+    assert!(accrued > 0);
+    assert_eq!(farm(&setup).await?["total_claimed"][0], "0");
+    Ok(())
+}
@@ -0,0 +1,65 @@
+use crate::*;
+
+pub const EVENT_STANDARD: &str = "farm_master";
+pub const EVENT_STANDARD_VERSION: &str = "1.0.0";
+
+/// NEP-297 events emitted by the master contract. Each one is logged as
+/// `EVENT_JSON:{"standard":"farm_master","version":"1.0.0","event":"<name>","data":{..}}`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+pub enum MasterEvent {
+    ChildRegistered {
+        child_account_id: AccountId,
+        contract_type: ChildContractType,
+    },
+    ChildUnregistered {
+        child_account_id: AccountId,
+    },
+    ChildDeployed {
+        child_account_id: AccountId,
+        contract_type: ChildContractType,
+    },
+    ChildUpgraded {
+        child_account_id: AccountId,
+        code_hash: Base58CryptoHash,
+        version: String,
+    },
+    ChildCodeStored {
+        contract_type: ChildContractType,
+        version: String,
+        code_hash: Base58CryptoHash,
+        size: u64,
+    },
+    ChildCodeRemoved {
+        contract_type: ChildContractType,
+        version: String,
+    },
+}
+
+/// The full NEP-297 envelope around a `MasterEvent`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct EventLog {
+    pub standard: String,
+    pub version: String,
+    #[serde(flatten)]
+    pub event: MasterEvent,
+}
+
+impl MasterEvent {
+    pub fn emit(self) {
+        let log = EventLog {
+            standard: EVENT_STANDARD.to_string(),
+            version: EVENT_STANDARD_VERSION.to_string(),
+            event: self,
+        };
+        env::log_str(
+            format!(
+                "EVENT_JSON:{}",
+                near_sdk::serde_json::to_string(&log).unwrap()
+            )
+            .as_str(),
+        );
+    }
+}
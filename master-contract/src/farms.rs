@@ -0,0 +1,57 @@
+// `call_create_farm` mirrors the child's `FarmInput` field by field. It lives in its
+// own module because near-sdk does not copy lint attributes onto the
+// `MasterContractExt::call_create_farm` wrapper it generates next to it.
+#![allow(clippy::too_many_arguments)]
+
+use crate::*;
+
+#[near_bindgen]
+impl MasterContract {
+    /// **(Owner-only)** Calls `create_farm` on a farming child contract.
+    ///
+    /// `attached_deposit` is added to the master's storage deposit on the child in the
+    /// same batch and pays for the farm. Children accept farms from the master as their
+    /// parent account, so the master is recorded as the farm's creator.
+    pub fn call_create_farm(
+        &self,
+        child_account_id: AccountId,
+        staking_token: AccountId,
+        reward_tokens: Vec<AccountId>,
+        lockup_period_sec: u64,
+        reward_per_session: Vec<U128>,
+        session_interval_sec: u64,
+        start_at_sec: u64,
+        attached_deposit: NearToken,
+    ) -> Promise {
+        self.assert_owner();
+        // Build the JSON arguments expected by the child contract's create_farm.
+        let args = near_sdk::serde_json::json!({
+            "input": {
+                "staking_token": staking_token,
+                "reward_tokens": reward_tokens,
+                "lockup_period_sec": lockup_period_sec,
+                "reward_per_session": reward_per_session,
+                "session_interval_sec": session_interval_sec,
+                "start_at_sec": start_at_sec,
+            }
+        });
+        let args_vec = near_sdk::serde_json::to_vec(&args).unwrap();
+
+        // Specify the gas to be attached; you can adjust this value as needed.
+        let gas_amount = Gas::from_tgas(50);
+
+        Promise::new(child_account_id)
+            .function_call(
+                "storage_deposit".to_string(),
+                b"{}".to_vec(),
+                attached_deposit,
+                GAS_FOR_CHILD_STORAGE_DEPOSIT,
+            )
+            .function_call(
+                "create_farm".to_string(),
+                args_vec,
+                NearToken::from_yoctonear(0),
+                gas_amount,
+            )
+    }
+}
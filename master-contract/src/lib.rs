@@ -1,9 +1,23 @@
+pub mod events;
+pub mod farms;
+pub mod migration;
+pub mod registry;
+
 use near_sdk::{
-    borsh::{self, BorshDeserialize, BorshSerialize}, collections::UnorderedMap, env, ext_contract, near_bindgen, AccountId, Gas, NearToken, PanicOnDefault, Promise, PromiseResult
+    borsh::{self, BorshDeserialize, BorshSerialize}, collections::{LookupMap, UnorderedMap}, env, ext_contract, near_bindgen, AccountId, Gas, GasWeight, NearToken, PanicOnDefault, Promise, PromiseResult
 };
-use near_sdk::json_types::U128;
+use near_sdk::json_types::{Base58CryptoHash, Base64VecU8, U128};
 use near_sdk::serde::{Deserialize, Serialize};
 
+use crate::events::MasterEvent;
+use crate::registry::ChildCodeInfo;
+
+/// Layout version of the contract state, see `migrate`.
+pub const CURRENT_STATE_VERSION: u32 = 2;
+
+const GAS_FOR_CHILD_INIT: Gas = Gas::from_tgas(30);
+const GAS_FOR_CHILD_PAUSE: Gas = Gas::from_tgas(10);
+const GAS_FOR_CHILD_STORAGE_DEPOSIT: Gas = Gas::from_tgas(10);
 
 #[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 #[serde(crate = "near_sdk::serde")]
@@ -13,10 +27,15 @@ pub enum ChildContractType {
     LP,
 }
 
-#[derive(BorshDeserialize, BorshSerialize)]
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Debug, Clone)]
+#[serde(crate = "near_sdk::serde")]
 pub struct ChildContractMetadata {
     pub contract_type: ChildContractType,
     pub deployed_at: u64,
+    /// sha256 of the code last installed by the master, if any.
+    pub code_hash: Option<Base58CryptoHash>,
+    /// Version label of that code, if it was given one.
+    pub version: Option<String>,
 }
 
 #[near_bindgen]
@@ -24,6 +43,12 @@ pub struct ChildContractMetadata {
 pub struct MasterContract {
     child_contracts: UnorderedMap<AccountId, ChildContractMetadata>,
     owner: AccountId,
+    code_infos: UnorderedMap<(ChildContractType, String), ChildCodeInfo>,
+    code_blobs: LookupMap<(ChildContractType, String), Vec<u8>>,
+    /// Layout version of this state, see `CURRENT_STATE_VERSION`.
+    state_version: u32,
+    /// Type and version of the build the next `store_child_code` call stores.
+    pending_code: Option<(ChildContractType, String)>,
 }
 
 #[near_bindgen]
@@ -33,6 +58,10 @@ impl MasterContract {
         Self {
             child_contracts: UnorderedMap::new(b"childs".to_vec()),
             owner,
+            code_infos: UnorderedMap::new(b"code_infos".to_vec()),
+            code_blobs: LookupMap::new(b"code_blobs".to_vec()),
+            state_version: CURRENT_STATE_VERSION,
+            pending_code: None,
         }
     }
 
@@ -40,25 +69,14 @@ impl MasterContract {
     /// This is useful if the child contract was deployed externally.
     pub fn register_child_contract(&mut self, child_account_id: AccountId, contract_type: ChildContractType) {
         self.assert_owner();
-        let metadata = ChildContractMetadata {
-            contract_type: contract_type.clone(),
-            deployed_at: env::block_timestamp(),
-        };
-        self.child_contracts.insert(&child_account_id, &metadata);
-        env::log_str(
-            format!(
-                "Registered child contract: {} with type {:?}",
-                child_account_id, contract_type
-            )
-            .as_str(),
-        );
+        self.internal_register_child(child_account_id, contract_type, None, None);
     }
 
     /// **(Owner-only)** Unregister a child contract.
     pub fn unregister_child_contract(&mut self, child_account_id: AccountId) {
         self.assert_owner();
         self.child_contracts.remove(&child_account_id);
-        env::log_str(format!("Unregistered child contract: {}", child_account_id).as_str());
+        MasterEvent::ChildUnregistered { child_account_id }.emit();
     }
 
     /// Returns a list of all registered child contracts (account and metadata).
@@ -66,7 +84,8 @@ impl MasterContract {
         self.child_contracts.iter().collect()
     }
 
-    /// A generic method to call a function on a child contract.
+    /// **(Owner-only)** A generic method to call a function on a child contract.
+    /// Children trust calls from the master, so this must not be open to everyone.
     /// You must supply:
     /// - the child contract account id,
     /// - the method name,
@@ -81,6 +100,7 @@ impl MasterContract {
         deposit: NearToken,
         gas: Gas,
     ) -> Promise {
+        self.assert_owner();
         Promise::new(child_account_id).function_call(
             method_name,
             args,
@@ -89,39 +109,6 @@ impl MasterContract {
         )
     }
 
-    pub fn call_create_farm(
-        &self,
-        child_account_id: AccountId,
-        staking_token: AccountId,
-        reward_tokens: Vec<AccountId>,
-        lockup_period_sec: u64,
-        reward_per_session: Vec<U128>,
-        session_interval_sec: u64,
-        start_at_sec: u64,
-        attached_deposit: NearToken,
-    ) -> Promise {
-        // Build the JSON arguments expected by the child contract's create_farm.
-        let args = near_sdk::serde_json::json!({
-            "staking_token": staking_token,
-            "reward_tokens": reward_tokens,
-            "lockup_period_sec": lockup_period_sec,
-            "reward_per_session": reward_per_session,
-            "session_interval_sec": session_interval_sec,
-            "start_at_sec": start_at_sec,
-        });
-        let args_vec = near_sdk::serde_json::to_vec(&args).unwrap();
-
-        // Specify the gas to be attached; you can adjust this value as needed.
-        let gas_amount = Gas::from_tgas(50);
-
-        Promise::new(child_account_id).function_call(
-            "create_farm".to_string(),
-            args_vec,
-            attached_deposit,
-            gas_amount,
-        )
-    }
-
     /// **(Owner-only) [Advanced Option]**
     /// Deploy a new child contract to a sub-account and register it.
     ///
@@ -131,6 +118,9 @@ impl MasterContract {
     /// - the WASM code for the child contract,
     /// - and an initial balance to fund the new account.
     ///
+    /// The child's `new` is called in the same batch with `init_args`, or with the
+    /// defaults for its type (see `default_init_args`), so nobody can initialize it first.
+    ///
     /// After the deployment, a callback (`on_child_deploy`) is executed to
     /// register the new child contract.
     #[payable]
@@ -140,24 +130,54 @@ impl MasterContract {
         contract_type: ChildContractType,
         child_contract_wasm: Vec<u8>,
         initial_balance: NearToken,
+        init_args: Option<Base64VecU8>,
     ) -> Promise {
         self.assert_owner();
-        // Create a new account for the child contract, fund it, and deploy the given WASM code.
-        Promise::new(child_account_id.clone())
-            .create_account()
-            .transfer(initial_balance)
-            .deploy_contract(child_contract_wasm)
-            .then(
-                // After deployment, call our private callback to register the child contract.
-                Self::ext(env::current_account_id())
-                    .with_static_gas(Gas::from_tgas(10))
-                    .on_child_deploy(child_account_id, contract_type)
-            )
+        self.internal_deploy_child(child_account_id, contract_type, child_contract_wasm, None, initial_balance, init_args)
+    }
+
+    /// **(Owner-only)** Pauses every action on all registered farming children, in
+    /// registration order. Pass `from_index` and `limit` to split a large fleet
+    /// over several calls when it does not fit into one call's gas.
+    pub fn pause_all_children(&mut self, from_index: Option<u64>, limit: Option<u64>) {
+        self.assert_owner();
+        self.internal_call_farming_children("pause", from_index, limit);
+    }
+
+    /// **(Owner-only)** Lifts the global pause on all registered farming children.
+    /// Pauses set on individual farms are left alone.
+    pub fn unpause_all_children(&mut self, from_index: Option<u64>, limit: Option<u64>) {
+        self.assert_owner();
+        self.internal_call_farming_children("unpause", from_index, limit);
+    }
+
+    /// **(Owner-only)** Push new code to a registered child contract.
+    ///
+    /// The child's `upgrade` method deploys `code` to itself and calls its `migrate`
+    /// with `migrate_args`. Once that succeeds, `on_child_upgraded` records the code
+    /// hash and `version` in the child's metadata. The child only accepts upgrades
+    /// from the master if it is a sub-account of the master.
+    pub fn upgrade_child(
+        &mut self,
+        child_account_id: AccountId,
+        code: Base64VecU8,
+        version: String,
+        migrate_args: Option<Base64VecU8>,
+    ) -> Promise {
+        self.assert_owner();
+        self.internal_upgrade_child(child_account_id, code.0, version, migrate_args)
     }
 
-    /// Private callback after deploying a child contract.
+    /// Private callback after deploying a child contract. The deploy batch is atomic,
+    /// so a successful result means the child was also initialized.
     #[private]
-    pub fn on_child_deploy(&mut self, child_account_id: AccountId, contract_type: ChildContractType) {
+    pub fn on_child_deploy(
+        &mut self,
+        child_account_id: AccountId,
+        contract_type: ChildContractType,
+        code_hash: Base58CryptoHash,
+        version: Option<String>,
+    ) {
         assert_eq!(
             env::promise_results_count(),
             1,
@@ -165,15 +185,171 @@ impl MasterContract {
         );
         match env::promise_result(0) {
             PromiseResult::Successful(_) => {
-                self.register_child_contract(child_account_id, contract_type);
-                env::log_str("Child contract deployed and registered.");
+                MasterEvent::ChildDeployed {
+                    child_account_id: child_account_id.clone(),
+                    contract_type: contract_type.clone(),
+                }
+                .emit();
+                // The predecessor here is the master itself, so skip the owner check.
+                self.internal_register_child(child_account_id, contract_type, Some(code_hash), version);
             }
             _ => {
-                env::panic_str("Child contract deployment failed");
+                env::panic_str("Child contract deployment or initialization failed");
             }
         }
     }
 
+    /// Private callback after upgrading a child contract.
+    #[private]
+    pub fn on_child_upgraded(&mut self, child_account_id: AccountId, code_hash: Base58CryptoHash, version: String) {
+        assert_eq!(
+            env::promise_results_count(),
+            1,
+            "Expected one promise result."
+        );
+        match env::promise_result(0) {
+            PromiseResult::Successful(_) => {
+                let mut metadata = self
+                    .child_contracts
+                    .get(&child_account_id)
+                    .expect("Child contract is not registered");
+                metadata.code_hash = Some(code_hash);
+                metadata.version = Some(version.clone());
+                self.child_contracts.insert(&child_account_id, &metadata);
+                MasterEvent::ChildUpgraded {
+                    child_account_id,
+                    code_hash,
+                    version,
+                }
+                .emit();
+            }
+            _ => {
+                env::panic_str("Child contract upgrade failed");
+            }
+        }
+    }
+
+    /// Creates, funds, deploys and initializes a new sub-account in one batch, then
+    /// registers it in `on_child_deploy`.
+    fn internal_deploy_child(
+        &mut self,
+        child_account_id: AccountId,
+        contract_type: ChildContractType,
+        code: Vec<u8>,
+        version: Option<String>,
+        initial_balance: NearToken,
+        init_args: Option<Base64VecU8>,
+    ) -> Promise {
+        let code_hash = Base58CryptoHash::from(env::sha256_array(&code));
+        let init_args = init_args
+            .map(|args| args.0)
+            .unwrap_or_else(|| self.default_init_args(&contract_type));
+        // Create a new account for the child contract, fund it, deploy the given WASM code
+        // and initialize it. If `new` fails, the whole batch is reverted.
+        Promise::new(child_account_id.clone())
+            .create_account()
+            .transfer(initial_balance)
+            .deploy_contract(code)
+            .function_call(
+                "new".to_string(),
+                init_args,
+                NearToken::from_yoctonear(0),
+                GAS_FOR_CHILD_INIT,
+            )
+            .then(
+            // After deployment, call our private callback to register the child contract.
+            Self::ext(env::current_account_id())
+                .with_static_gas(Gas::from_tgas(10))
+                .on_child_deploy(child_account_id, contract_type, code_hash, version)
+        )
+    }
+
+    /// Calls the one-yocto `method_name` with empty arguments on a page of farming children.
+    fn internal_call_farming_children(&self, method_name: &str, from_index: Option<u64>, limit: Option<u64>) {
+        let from_index = from_index.unwrap_or(0) as usize;
+        let limit = limit.unwrap_or(self.child_contracts.len()) as usize;
+        for (child_account_id, metadata) in self.child_contracts.iter().skip(from_index).take(limit) {
+            if metadata.contract_type != ChildContractType::Farming {
+                continue;
+            }
+            Promise::new(child_account_id).function_call(
+                method_name.to_string(),
+                b"{}".to_vec(),
+                NearToken::from_yoctonear(1),
+                GAS_FOR_CHILD_PAUSE,
+            );
+        }
+    }
+
+    /// Arguments for a child's `new` when the caller gives none. Farming children are
+    /// administered by the owner directly; the master keeps upgrade rights as their
+    /// parent account.
+    fn default_init_args(&self, contract_type: &ChildContractType) -> Vec<u8> {
+        match contract_type {
+            ChildContractType::Farming => {
+                near_sdk::serde_json::to_vec(&near_sdk::serde_json::json!({ "admin": self.owner })).unwrap()
+            }
+            _ => env::panic_str("init_args are required for this contract type"),
+        }
+    }
+
+    /// Sends `code` to a registered child's `upgrade` method; `on_child_upgraded`
+    /// records it once the child has migrated.
+    fn internal_upgrade_child(
+        &mut self,
+        child_account_id: AccountId,
+        code: Vec<u8>,
+        version: String,
+        migrate_args: Option<Base64VecU8>,
+    ) -> Promise {
+        assert!(
+            self.child_contracts.get(&child_account_id).is_some(),
+            "Child contract is not registered"
+        );
+        let code_hash = Base58CryptoHash::from(env::sha256_array(&code));
+        let args = near_sdk::serde_json::to_vec(&near_sdk::serde_json::json!({
+            "code": Base64VecU8(code),
+            "migrate_args": migrate_args,
+        }))
+        .unwrap();
+
+        // The child needs whatever gas is left to deploy and migrate.
+        Promise::new(child_account_id.clone())
+            .function_call_weight(
+                "upgrade".to_string(),
+                args,
+                NearToken::from_yoctonear(0),
+                Gas::from_gas(0),
+                GasWeight(1),
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(Gas::from_tgas(10))
+                    .on_child_upgraded(child_account_id, code_hash, version)
+            )
+    }
+
+    fn internal_register_child(
+        &mut self,
+        child_account_id: AccountId,
+        contract_type: ChildContractType,
+        code_hash: Option<Base58CryptoHash>,
+        version: Option<String>,
+    ) {
+        let metadata = ChildContractMetadata {
+            contract_type: contract_type.clone(),
+            deployed_at: env::block_timestamp(),
+            code_hash,
+            version,
+        };
+        self.child_contracts.insert(&child_account_id, &metadata);
+        MasterEvent::ChildRegistered {
+            child_account_id,
+            contract_type,
+        }
+        .emit();
+    }
+
     /// Simple helper: only the owner may call certain methods.
     fn assert_owner(&self) {
         assert_eq!(
@@ -186,7 +362,14 @@ impl MasterContract {
 
 #[ext_contract(ext_self)]
 pub trait ExtSelf {
-    fn on_child_deploy(&mut self, child_account_id: AccountId, contract_type: ChildContractType);
+    fn on_child_deploy(
+        &mut self,
+        child_account_id: AccountId,
+        contract_type: ChildContractType,
+        code_hash: Base58CryptoHash,
+        version: Option<String>,
+    );
+    fn on_child_upgraded(&mut self, child_account_id: AccountId, code_hash: Base58CryptoHash, version: String);
 }
 
 ///////////////////////////////////////////
@@ -197,7 +380,10 @@ pub trait ExtSelf {
 mod tests {
     use super::*;
     use near_sdk::test_utils::{VMContextBuilder, accounts};
-    use near_sdk::{testing_env};
+    use near_sdk::{testing_env, RuntimeFeesConfig};
+    use crate::events::EventLog;
+    use near_sdk::mock::MockAction;
+    use crate::migration::{ChildContractMetadataV1, MasterContractV1};
 
     /// Helper to build the testing context.
     fn get_context(predecessor: AccountId, deposit: NearToken, block_timestamp: u64) -> VMContextBuilder {
@@ -209,6 +395,54 @@ mod tests {
         builder
     }
 
+    /// Parses the NEP-297 events logged since the context was last set.
+    fn emitted_events() -> Vec<MasterEvent> {
+        near_sdk::test_utils::get_logs()
+            .iter()
+            .filter_map(|log| log.strip_prefix("EVENT_JSON:"))
+            .map(|json| {
+                let log: EventLog = near_sdk::serde_json::from_str(json).unwrap();
+                assert_eq!(log.standard, events::EVENT_STANDARD);
+                assert_eq!(log.version, events::EVENT_STANDARD_VERSION);
+                log.event
+            })
+            .collect()
+    }
+
+    /// Stores `code` in the registry the way a client does: `prepare_child_code`, and
+    /// then `store_child_code` with the code as the raw input.
+    fn store_code(
+        contract: &mut MasterContract,
+        contract_type: ChildContractType,
+        version: &str,
+        code: Vec<u8>,
+    ) -> ChildCodeInfo {
+        contract.prepare_child_code(contract_type, version.to_string());
+        let mut context = get_context(env::predecessor_account_id(), NearToken::from_yoctonear(0), 0).build();
+        context.input = code;
+        testing_env!(context);
+        contract.store_child_code()
+    }
+
+    /// Returns the arguments of the first scheduled call to `method`.
+    fn scheduled_call_args(method: &str) -> Vec<u8> {
+        near_sdk::test_utils::get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| receipt.actions)
+            .find_map(|action| match action {
+                MockAction::FunctionCallWeight { method_name, args, .. } if method_name == method.as_bytes() => {
+                    Some(args)
+                }
+                _ => None,
+            })
+            .unwrap_or_else(|| panic!("No {} call scheduled", method))
+    }
+
+    /// Returns the arguments of the `new` call scheduled in the deploy batch.
+    fn scheduled_init_args() -> Vec<u8> {
+        scheduled_call_args("new")
+    }
+
     #[test]
     fn test_new_and_list_child_contracts_empty() {
         let context = get_context(accounts(0), NearToken::from_yoctonear(0), 0);
@@ -230,7 +464,7 @@ mod tests {
 
         let list = contract.list_child_contracts();
         assert_eq!(list.len(), 1, "Expected one child contract registered");
-        let (registered_account, metadata) = list.get(0).unwrap();
+        let (registered_account, metadata) = list.first().unwrap();
         assert_eq!(registered_account, &child_account);
         assert_eq!(metadata.contract_type, ChildContractType::Farming);
     }
@@ -297,6 +531,24 @@ mod tests {
         let _promise = contract.call_child_contract(child_account, "dummy_method".to_string(), args, deposit, gas);
     }
 
+    #[test]
+    #[should_panic(expected = "Only owner can call this method")]
+    fn test_call_child_contract_non_owner() {
+        let context = get_context(accounts(1), NearToken::from_yoctonear(0), 0);
+        testing_env!(context.build());
+        let contract = MasterContract::new(accounts(0));
+
+        // Children accept upgrades from the master, so the passthrough must be gated.
+        let child_account: AccountId = "farm.master.testnet".parse().unwrap();
+        contract.call_child_contract(
+            child_account,
+            "upgrade".to_string(),
+            b"{}".to_vec(),
+            NearToken::from_yoctonear(0),
+            Gas::from_tgas(10),
+        );
+    }
+
     #[test]
     fn test_call_create_farm() {
         let owner = accounts(0);
@@ -313,7 +565,7 @@ mod tests {
         let start_at_sec = 0;
         let attached_deposit= NearToken::from_yoctonear(1); // deposit in yoctoNEAR
 
-        let _promise = contract.call_create_farm(
+        contract.call_create_farm(
             child_account,
             staking_token,
             reward_tokens,
@@ -323,6 +575,24 @@ mod tests {
             start_at_sec,
             attached_deposit,
         );
+
+        // The child's `create_farm` takes a single `input` argument.
+        let args: near_sdk::serde_json::Value =
+            near_sdk::serde_json::from_slice(&scheduled_call_args("create_farm")).unwrap();
+        assert_eq!(
+            args,
+            near_sdk::serde_json::json!({
+                "input": {
+                    "staking_token": "staking.token",
+                    "reward_tokens": ["reward.token"],
+                    "lockup_period_sec": 60,
+                    "reward_per_session": ["100"],
+                    "session_interval_sec": 10,
+                    "start_at_sec": 0,
+                }
+            })
+        );
+        assert_eq!(scheduled_call_args("storage_deposit"), b"{}".to_vec());
     }
 
     #[test]
@@ -337,6 +607,440 @@ mod tests {
         let child_account: AccountId = "child1.testnet".parse().unwrap();
         let wasm_code = vec![0u8; 10]; // dummy WASM bytes
         let initial_balance = NearToken::from_yoctonear(10);
-        contract.deploy_child_contract(child_account, ChildContractType::Farming, wasm_code, initial_balance);
+        contract.deploy_child_contract(child_account, ChildContractType::Farming, wasm_code, initial_balance, None);
+    }
+
+    #[test]
+    fn test_register_and_unregister_events() {
+        let owner = accounts(0);
+        let context = get_context(owner.clone(), NearToken::from_yoctonear(0), 0);
+        testing_env!(context.build());
+        let mut contract = MasterContract::new(owner);
+
+        let child_account: AccountId = "child1.testnet".parse().unwrap();
+        contract.register_child_contract(child_account.clone(), ChildContractType::Farming);
+        assert_eq!(
+            near_sdk::test_utils::get_logs(),
+            vec![r#"EVENT_JSON:{"standard":"farm_master","version":"1.0.0","event":"child_registered","data":{"child_account_id":"child1.testnet","contract_type":"Farming"}}"#]
+        );
+
+        testing_env!(context.build());
+        contract.unregister_child_contract(child_account.clone());
+        assert_eq!(
+            emitted_events(),
+            vec![MasterEvent::ChildUnregistered { child_account_id: child_account }]
+        );
+    }
+
+    #[test]
+    fn test_on_child_deploy_registers_child() {
+        let owner = accounts(0);
+        let context = get_context(owner.clone(), NearToken::from_yoctonear(0), 0);
+        testing_env!(context.build());
+        let mut contract = MasterContract::new(owner);
+
+        // The callback runs with the master itself as predecessor.
+        let context = get_context(env::current_account_id(), NearToken::from_yoctonear(0), 0);
+        testing_env!(
+            context.build(),
+            near_sdk::test_vm_config(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])]
+        );
+        let child_account: AccountId = "farm.master.testnet".parse().unwrap();
+        contract.on_child_deploy(child_account.clone(), ChildContractType::Farming, Base58CryptoHash::default(), None);
+
+        assert_eq!(contract.list_child_contracts().len(), 1);
+        assert_eq!(
+            emitted_events(),
+            vec![
+                MasterEvent::ChildDeployed {
+                    child_account_id: child_account.clone(),
+                    contract_type: ChildContractType::Farming,
+                },
+                MasterEvent::ChildRegistered {
+                    child_account_id: child_account,
+                    contract_type: ChildContractType::Farming,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Child contract is not registered")]
+    fn test_upgrade_child_not_registered() {
+        let owner = accounts(0);
+        let context = get_context(owner.clone(), NearToken::from_yoctonear(0), 0);
+        testing_env!(context.build());
+        let mut contract = MasterContract::new(owner);
+        let child_account: AccountId = "farm.master.testnet".parse().unwrap();
+        contract.upgrade_child(child_account, Base64VecU8(vec![0u8; 10]), "1.1.0".to_string(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only owner can call this method")]
+    fn test_upgrade_child_non_owner() {
+        let owner = accounts(0);
+        let context = get_context(owner.clone(), NearToken::from_yoctonear(0), 0);
+        testing_env!(context.build());
+        let mut contract = MasterContract::new(owner);
+        let child_account: AccountId = "farm.master.testnet".parse().unwrap();
+        contract.register_child_contract(child_account.clone(), ChildContractType::Farming);
+
+        let context = get_context(accounts(1), NearToken::from_yoctonear(0), 0);
+        testing_env!(context.build());
+        contract.upgrade_child(child_account, Base64VecU8(vec![0u8; 10]), "1.1.0".to_string(), None);
+    }
+
+    #[test]
+    fn test_on_child_upgraded_records_code_hash() {
+        let owner = accounts(0);
+        let context = get_context(owner.clone(), NearToken::from_yoctonear(0), 0);
+        testing_env!(context.build());
+        let mut contract = MasterContract::new(owner);
+        let child_account: AccountId = "farm.master.testnet".parse().unwrap();
+        contract.register_child_contract(child_account.clone(), ChildContractType::Farming);
+
+        let code = vec![1u8; 10];
+        let code_hash = Base58CryptoHash::from(env::sha256_array(&code));
+        let _ = contract.upgrade_child(child_account.clone(), Base64VecU8(code), "1.1.0".to_string(), None);
+
+        let context = get_context(env::current_account_id(), NearToken::from_yoctonear(0), 0);
+        testing_env!(
+            context.build(),
+            near_sdk::test_vm_config(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])]
+        );
+        contract.on_child_upgraded(child_account.clone(), code_hash, "1.1.0".to_string());
+
+        let (_, metadata) = contract.list_child_contracts().pop().unwrap();
+        assert_eq!(metadata.code_hash, Some(code_hash));
+        assert_eq!(metadata.version.as_deref(), Some("1.1.0"));
+        assert_eq!(
+            emitted_events(),
+            vec![MasterEvent::ChildUpgraded {
+                child_account_id: child_account,
+                code_hash,
+                version: "1.1.0".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Child contract upgrade failed")]
+    fn test_on_child_upgraded_failure() {
+        let owner = accounts(0);
+        let context = get_context(owner.clone(), NearToken::from_yoctonear(0), 0);
+        testing_env!(context.build());
+        let mut contract = MasterContract::new(owner);
+        let child_account: AccountId = "farm.master.testnet".parse().unwrap();
+        contract.register_child_contract(child_account.clone(), ChildContractType::Farming);
+
+        let context = get_context(env::current_account_id(), NearToken::from_yoctonear(0), 0);
+        testing_env!(
+            context.build(),
+            near_sdk::test_vm_config(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed]
+        );
+        contract.on_child_upgraded(child_account, Base58CryptoHash::default(), "1.1.0".to_string());
+    }
+
+    #[test]
+    fn test_store_and_list_child_code() {
+        let owner = accounts(0);
+        let context = get_context(owner.clone(), NearToken::from_yoctonear(0), 0);
+        testing_env!(context.build());
+        let mut contract = MasterContract::new(owner);
+
+        let code = vec![7u8; 32];
+        let info = store_code(&mut contract, ChildContractType::Farming, "1.10.0", code.clone());
+        assert_eq!(info.code_hash, Base58CryptoHash::from(env::sha256_array(&code)));
+        assert_eq!(info.size, 32);
+        assert_eq!(
+            emitted_events(),
+            vec![MasterEvent::ChildCodeStored {
+                contract_type: ChildContractType::Farming,
+                version: "1.10.0".to_string(),
+                code_hash: info.code_hash,
+                size: 32,
+            }]
+        );
+        store_code(&mut contract, ChildContractType::Farming, "1.2.0", vec![1u8; 16]);
+        store_code(&mut contract, ChildContractType::NFT, "1.0.0", vec![2u8; 8]);
+
+        // Versions sort numerically, not lexicographically.
+        let versions: Vec<String> = contract
+            .list_child_code(Some(ChildContractType::Farming))
+            .into_iter()
+            .map(|info| info.version)
+            .collect();
+        assert_eq!(versions, vec!["1.2.0", "1.10.0"]);
+        assert_eq!(contract.list_child_code(None).len(), 3);
+        assert_eq!(
+            contract.get_child_code(ChildContractType::Farming, "1.10.0".to_string()),
+            Some(info)
+        );
+
+        contract.remove_child_code(ChildContractType::Farming, "1.10.0".to_string());
+        assert!(contract.get_child_code(ChildContractType::Farming, "1.10.0".to_string()).is_none());
+        assert_eq!(contract.list_child_code(None).len(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "Code version is already stored")]
+    fn test_store_child_code_twice() {
+        let owner = accounts(0);
+        let context = get_context(owner.clone(), NearToken::from_yoctonear(0), 0);
+        testing_env!(context.build());
+        let mut contract = MasterContract::new(owner);
+        store_code(&mut contract, ChildContractType::Farming, "1.0.0", vec![1u8; 8]);
+        store_code(&mut contract, ChildContractType::Farming, "1.0.0", vec![2u8; 8]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Call prepare_child_code first")]
+    fn test_store_child_code_without_prepare() {
+        let owner = accounts(0);
+        let mut context = get_context(owner.clone(), NearToken::from_yoctonear(0), 0).build();
+        context.input = vec![1u8; 8];
+        testing_env!(context);
+        let mut contract = MasterContract::new(owner);
+        contract.store_child_code();
+    }
+
+    #[test]
+    #[should_panic(expected = "Version must be MAJOR.MINOR.PATCH")]
+    fn test_store_child_code_invalid_version() {
+        let owner = accounts(0);
+        let context = get_context(owner.clone(), NearToken::from_yoctonear(0), 0);
+        testing_env!(context.build());
+        let mut contract = MasterContract::new(owner);
+        store_code(&mut contract, ChildContractType::Farming, "v1.0", vec![1u8; 8]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only owner can call this method")]
+    fn test_store_child_code_non_owner() {
+        let context = get_context(accounts(1), NearToken::from_yoctonear(0), 0);
+        testing_env!(context.build());
+        let mut contract = MasterContract::new(accounts(0));
+        store_code(&mut contract, ChildContractType::Farming, "1.0.0", vec![1u8; 8]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Code version is not stored")]
+    fn test_deploy_child_from_registry_unknown_version() {
+        let owner = accounts(0);
+        let context = get_context(owner.clone(), NearToken::from_yoctonear(0), 0);
+        testing_env!(context.build());
+        let mut contract = MasterContract::new(owner);
+        store_code(&mut contract, ChildContractType::Farming, "1.0.0", vec![1u8; 8]);
+        contract.deploy_child_from_registry(
+            "farm.master.testnet".parse().unwrap(),
+            ChildContractType::Farming,
+            "2.0.0".to_string(),
+            NearToken::from_yoctonear(10),
+            None,
+        );
+    }
+
+    #[test]
+    fn test_deploy_child_from_registry_records_version() {
+        let owner = accounts(0);
+        let context = get_context(owner.clone(), NearToken::from_yoctonear(0), 0);
+        testing_env!(context.build());
+        let mut contract = MasterContract::new(owner);
+        let info = store_code(&mut contract, ChildContractType::Farming, "1.0.0", vec![1u8; 8]);
+
+        let child_account: AccountId = "farm.master.testnet".parse().unwrap();
+        let _ = contract.deploy_child_from_registry(
+            child_account.clone(),
+            ChildContractType::Farming,
+            "1.0.0".to_string(),
+            NearToken::from_yoctonear(10),
+            Some(Base64VecU8(br#"{"admin":"owner.testnet"}"#.to_vec())),
+        );
+
+        let context = get_context(env::current_account_id(), NearToken::from_yoctonear(0), 0);
+        testing_env!(
+            context.build(),
+            near_sdk::test_vm_config(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])]
+        );
+        contract.on_child_deploy(child_account, ChildContractType::Farming, info.code_hash, Some(info.version));
+
+        let (_, metadata) = contract.list_child_contracts().pop().unwrap();
+        assert_eq!(metadata.code_hash, Some(info.code_hash));
+        assert_eq!(metadata.version.as_deref(), Some("1.0.0"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Code version is not stored")]
+    fn test_upgrade_child_from_registry_wrong_type() {
+        let owner = accounts(0);
+        let context = get_context(owner.clone(), NearToken::from_yoctonear(0), 0);
+        testing_env!(context.build());
+        let mut contract = MasterContract::new(owner);
+        let child_account: AccountId = "farm.master.testnet".parse().unwrap();
+        contract.register_child_contract(child_account.clone(), ChildContractType::Farming);
+        store_code(&mut contract, ChildContractType::NFT, "1.0.0", vec![1u8; 8]);
+
+        // Only builds of the child's own type can be installed.
+        contract.upgrade_child_from_registry(child_account, "1.0.0".to_string(), None);
+    }
+
+    #[test]
+    fn test_deploy_child_contract_initializes_with_owner_as_admin() {
+        let owner = accounts(0);
+        let context = get_context(owner.clone(), NearToken::from_yoctonear(0), 0);
+        testing_env!(context.build());
+        let mut contract = MasterContract::new(owner);
+
+        let child_account: AccountId = "farm.master.testnet".parse().unwrap();
+        drop(contract.deploy_child_contract(
+            child_account,
+            ChildContractType::Farming,
+            vec![0u8; 10],
+            NearToken::from_yoctonear(10),
+            None,
+        ));
+        assert_eq!(scheduled_init_args(), br#"{"admin":"alice"}"#.to_vec());
+    }
+
+    #[test]
+    fn test_deploy_child_contract_custom_init_args() {
+        let owner = accounts(0);
+        let context = get_context(owner.clone(), NearToken::from_yoctonear(0), 0);
+        testing_env!(context.build());
+        let mut contract = MasterContract::new(owner);
+
+        let init_args = br#"{"admin":"farm-admin.testnet"}"#.to_vec();
+        drop(contract.deploy_child_contract(
+            "farm.master.testnet".parse().unwrap(),
+            ChildContractType::Farming,
+            vec![0u8; 10],
+            NearToken::from_yoctonear(10),
+            Some(Base64VecU8(init_args.clone())),
+        ));
+        assert_eq!(scheduled_init_args(), init_args);
+    }
+
+    #[test]
+    #[should_panic(expected = "init_args are required for this contract type")]
+    fn test_deploy_child_contract_no_default_init_args() {
+        let owner = accounts(0);
+        let context = get_context(owner.clone(), NearToken::from_yoctonear(0), 0);
+        testing_env!(context.build());
+        let mut contract = MasterContract::new(owner);
+        contract.deploy_child_contract(
+            "nft.master.testnet".parse().unwrap(),
+            ChildContractType::NFT,
+            vec![0u8; 10],
+            NearToken::from_yoctonear(10),
+            None,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Child contract deployment or initialization failed")]
+    fn test_on_child_deploy_failure_does_not_register() {
+        let owner = accounts(0);
+        let context = get_context(owner.clone(), NearToken::from_yoctonear(0), 0);
+        testing_env!(context.build());
+        let mut contract = MasterContract::new(owner);
+
+        let context = get_context(env::current_account_id(), NearToken::from_yoctonear(0), 0);
+        testing_env!(
+            context.build(),
+            near_sdk::test_vm_config(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed]
+        );
+        contract.on_child_deploy(
+            "farm.master.testnet".parse().unwrap(),
+            ChildContractType::Farming,
+            Base58CryptoHash::default(),
+            None,
+        );
+    }
+
+    #[test]
+    fn test_pause_all_children() {
+        let owner = accounts(0);
+        let context = get_context(owner.clone(), NearToken::from_yoctonear(0), 0);
+        testing_env!(context.build());
+        let mut contract = MasterContract::new(owner);
+        contract.register_child_contract("farm1.master.testnet".parse().unwrap(), ChildContractType::Farming);
+        contract.register_child_contract("nft.master.testnet".parse().unwrap(), ChildContractType::NFT);
+        contract.register_child_contract("farm2.master.testnet".parse().unwrap(), ChildContractType::Farming);
+
+        contract.pause_all_children(None, None);
+        let receivers: Vec<String> = near_sdk::test_utils::get_created_receipts()
+            .into_iter()
+            .filter(|receipt| {
+                receipt.actions.iter().any(|action| {
+                    matches!(action, MockAction::FunctionCallWeight { method_name, .. } if method_name == b"pause")
+                })
+            })
+            .map(|receipt| receipt.receiver_id.to_string())
+            .collect();
+        assert_eq!(receivers, vec!["farm1.master.testnet", "farm2.master.testnet"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only owner can call this method")]
+    fn test_pause_all_children_non_owner() {
+        let context = get_context(accounts(1), NearToken::from_yoctonear(0), 0);
+        testing_env!(context.build());
+        let mut contract = MasterContract::new(accounts(0));
+        contract.pause_all_children(None, None);
+    }
+
+    #[test]
+    fn test_migrate_from_v1_state() {
+        let context = get_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        testing_env!(context.build());
+        let mut old = MasterContractV1 {
+            child_contracts: UnorderedMap::new(b"childs".to_vec()),
+            owner: accounts(0),
+        };
+        let child: AccountId = "child1.testnet".parse().unwrap();
+        old.child_contracts.insert(
+            &child,
+            &ChildContractMetadataV1 {
+                contract_type: ChildContractType::Farming,
+                deployed_at: 42,
+            },
+        );
+        env::state_write(&old);
+
+        let mut contract = MasterContract::migrate();
+        assert_eq!(contract.state_version, CURRENT_STATE_VERSION);
+        assert_eq!(contract.owner, accounts(0));
+        let list = contract.list_child_contracts();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].0, child);
+        assert_eq!(list[0].1.contract_type, ChildContractType::Farming);
+        assert_eq!(list[0].1.deployed_at, 42);
+        assert!(list[0].1.code_hash.is_none());
+        assert!(list[0].1.version.is_none());
+
+        // The migrated contract keeps working, including the code registry.
+        contract.register_child_contract("child2.testnet".parse().unwrap(), ChildContractType::LP);
+        assert_eq!(contract.list_child_contracts().len(), 2);
+        assert!(contract.list_child_code(None).is_empty());
+
+        // Migrating an already current state is a no-op.
+        env::state_write(&contract);
+        let contract = MasterContract::migrate();
+        assert_eq!(contract.list_child_contracts().len(), 2);
     }
 }
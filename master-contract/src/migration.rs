@@ -0,0 +1,66 @@
+use crate::*;
+
+/// `ChildContractMetadata` as written before state versioning, without the code
+/// hash and version of the installed code.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct ChildContractMetadataV1 {
+    pub contract_type: ChildContractType,
+    pub deployed_at: u64,
+}
+
+/// Contract state as written before state versioning (state version 1).
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct MasterContractV1 {
+    pub child_contracts: UnorderedMap<AccountId, ChildContractMetadataV1>,
+    pub owner: AccountId,
+}
+
+#[near_bindgen]
+impl MasterContract {
+    /// Upgrades the stored state to `CURRENT_STATE_VERSION`. Call it right after
+    /// deploying new code; it is a no-op if the state is already current.
+    ///
+    /// Version 1 children are rewritten in a single call, so they must fit into the
+    /// gas attached to it. Their code hash and version are unknown until the master
+    /// next upgrades them.
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let raw_state = env::storage_read(b"STATE").expect("Contract is not initialized");
+        if let Ok(contract) = Self::try_from_slice(&raw_state) {
+            assert_eq!(
+                contract.state_version, CURRENT_STATE_VERSION,
+                "Unknown state version"
+            );
+            return contract;
+        }
+        let old = MasterContractV1::try_from_slice(&raw_state)
+            .unwrap_or_else(|_| env::panic_str("Unknown state layout"));
+        Self::migrate_from_v1(old)
+    }
+
+    fn migrate_from_v1(mut old: MasterContractV1) -> Self {
+        let children = old.child_contracts.to_vec();
+        old.child_contracts.clear();
+        let mut child_contracts = UnorderedMap::new(b"childs".to_vec());
+        for (child_account_id, metadata) in children {
+            child_contracts.insert(
+                &child_account_id,
+                &ChildContractMetadata {
+                    contract_type: metadata.contract_type,
+                    deployed_at: metadata.deployed_at,
+                    code_hash: None,
+                    version: None,
+                },
+            );
+        }
+        Self {
+            child_contracts,
+            owner: old.owner,
+            code_infos: UnorderedMap::new(b"code_infos".to_vec()),
+            code_blobs: LookupMap::new(b"code_blobs".to_vec()),
+            state_version: CURRENT_STATE_VERSION,
+            pending_code: None,
+        }
+    }
+}
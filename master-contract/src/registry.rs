@@ -0,0 +1,158 @@
+use crate::*;
+
+/// A child contract build stored in the master's code registry.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ChildCodeInfo {
+    pub contract_type: ChildContractType,
+    pub version: String,
+    /// sha256 of the WASM code.
+    pub code_hash: Base58CryptoHash,
+    /// Size of the WASM code in bytes.
+    pub size: u64,
+    pub stored_at: u64,
+}
+
+/// Parses a `MAJOR.MINOR.PATCH` version so stored versions sort numerically.
+fn parse_version(version: &str) -> (u64, u64, u64) {
+    let parts: Vec<u64> = version
+        .split('.')
+        .map(|part| part.parse().unwrap_or_else(|_| env::panic_str("Version must be MAJOR.MINOR.PATCH")))
+        .collect();
+    match parts[..] {
+        [major, minor, patch] => (major, minor, patch),
+        _ => env::panic_str("Version must be MAJOR.MINOR.PATCH"),
+    }
+}
+
+#[near_bindgen]
+impl MasterContract {
+    /// **(Owner-only)** First step of storing a child contract build: names the
+    /// `contract_type` and `version` that the next `store_child_code` call stores.
+    /// Preparing again replaces the pending upload.
+    pub fn prepare_child_code(&mut self, contract_type: ChildContractType, version: String) {
+        self.assert_owner();
+        parse_version(&version);
+        assert!(
+            self.code_infos.get(&(contract_type.clone(), version.clone())).is_none(),
+            "Code version is already stored"
+        );
+        self.pending_code = Some((contract_type, version));
+    }
+
+    /// **(Owner-only)** Stores the WASM code sent as the raw arguments of this call
+    /// under the type and version given to `prepare_child_code`.
+    ///
+    /// The code is read with `env::input()` rather than as JSON, so it travels without
+    /// base64 overhead. Versions are immutable once stored. The master account pays
+    /// for the storage, so it must hold enough NEAR for the code size.
+    pub fn store_child_code(&mut self) -> ChildCodeInfo {
+        self.assert_owner();
+        let (contract_type, version) = self
+            .pending_code
+            .take()
+            .unwrap_or_else(|| env::panic_str("Call prepare_child_code first"));
+        let code = env::input().unwrap_or_default();
+        assert!(!code.is_empty(), "Code is empty");
+        let key = (contract_type.clone(), version.clone());
+        assert!(
+            self.code_infos.get(&key).is_none(),
+            "Code version is already stored"
+        );
+
+        let info = ChildCodeInfo {
+            contract_type,
+            version,
+            code_hash: Base58CryptoHash::from(env::sha256_array(&code)),
+            size: code.len() as u64,
+            stored_at: env::block_timestamp(),
+        };
+        self.code_blobs.insert(&key, &code);
+        self.code_infos.insert(&key, &info);
+        MasterEvent::ChildCodeStored {
+            contract_type: info.contract_type.clone(),
+            version: info.version.clone(),
+            code_hash: info.code_hash,
+            size: info.size,
+        }
+        .emit();
+        info
+    }
+
+    /// **(Owner-only)** Deletes a stored build and frees its storage.
+    pub fn remove_child_code(&mut self, contract_type: ChildContractType, version: String) {
+        self.assert_owner();
+        let key = (contract_type.clone(), version.clone());
+        assert!(
+            self.code_infos.remove(&key).is_some(),
+            "Code version is not stored"
+        );
+        self.code_blobs.remove(&key);
+        MasterEvent::ChildCodeRemoved {
+            contract_type,
+            version,
+        }
+        .emit();
+    }
+
+    /// **(Owner-only)** Deploys a stored build to a new sub-account and registers it.
+    /// The child is initialized as in `deploy_child_contract`.
+    #[payable]
+    pub fn deploy_child_from_registry(
+        &mut self,
+        child_account_id: AccountId,
+        contract_type: ChildContractType,
+        version: String,
+        initial_balance: NearToken,
+        init_args: Option<Base64VecU8>,
+    ) -> Promise {
+        self.assert_owner();
+        let code = self.internal_get_code(&contract_type, &version);
+        self.internal_deploy_child(
+            child_account_id,
+            contract_type,
+            code,
+            Some(version),
+            initial_balance,
+            init_args,
+        )
+    }
+
+    /// **(Owner-only)** Upgrades a registered child to a stored build of its own type.
+    pub fn upgrade_child_from_registry(
+        &mut self,
+        child_account_id: AccountId,
+        version: String,
+        migrate_args: Option<Base64VecU8>,
+    ) -> Promise {
+        self.assert_owner();
+        let metadata = self
+            .child_contracts
+            .get(&child_account_id)
+            .expect("Child contract is not registered");
+        let code = self.internal_get_code(&metadata.contract_type, &version);
+        self.internal_upgrade_child(child_account_id, code, version, migrate_args)
+    }
+
+    /// Lists stored builds, oldest version first, optionally only those of `contract_type`.
+    pub fn list_child_code(&self, contract_type: Option<ChildContractType>) -> Vec<ChildCodeInfo> {
+        let mut infos: Vec<ChildCodeInfo> = self
+            .code_infos
+            .values()
+            .filter(|info| contract_type.as_ref().map_or(true, |t| &info.contract_type == t))
+            .collect();
+        infos.sort_by_key(|info| parse_version(&info.version));
+        infos
+    }
+
+    /// Returns the stored build of `contract_type` at `version`, if any.
+    pub fn get_child_code(&self, contract_type: ChildContractType, version: String) -> Option<ChildCodeInfo> {
+        self.code_infos.get(&(contract_type, version))
+    }
+
+    fn internal_get_code(&self, contract_type: &ChildContractType, version: &str) -> Vec<u8> {
+        self.code_blobs
+            .get(&(contract_type.clone(), version.to_string()))
+            .expect("Code version is not stored")
+    }
+}